@@ -4,8 +4,48 @@ use crate::lexer::TokenType::*;
 use crate::lexer::*;
 use crate::utils;
 
+use serde::Serialize;
 use wasm_bindgen::prelude::*;
 
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct AsmError {
+    pub line: u32,
+    pub message: String,
+}
+
+impl AsmError {
+    pub fn new(line: u32, message: String) -> AsmError {
+        AsmError { line, message }
+    }
+}
+
+//what kind of instruction holds an absolute address that must move with the program
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelocationKind {
+    Jp,
+    Call,
+    LdI,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+pub struct Relocation {
+    //address of the instruction, whose low 12 bits are the target
+    pub addr: u16,
+    pub kind: RelocationKind,
+}
+
+//an opcode's Display with byte immediates padded to two digits, matching the word beside it
+fn listing_operation(op: &Opcode) -> String {
+    match op {
+        LDRegByte(reg, byte) => format!("LD V{:X}, {:02X}", reg.value(), byte),
+        SERegByte(reg, byte) => format!("SE V{:X}, {:02X}", reg.value(), byte),
+        SNERegByte(reg, byte) => format!("SNE V{:X}, {:02X}", reg.value(), byte),
+        RNDRegByte(reg, byte) => format!("RND V{:X}, {:02X}", reg.value(), byte),
+        _ => op.to_string(),
+    }
+}
+
 #[wasm_bindgen]
 pub struct Assembler {
     asm: Vec<Opcode>,
@@ -23,27 +63,60 @@ impl Assembler {
         }
     }
 
+    //new_from_compiler() and assemble() in one step, with binary() ready to read
+    pub fn assemble_compiler(compiler: &Compiler) -> Assembler {
+        let mut assembler = Assembler::new_from_compiler(compiler);
+        assembler.assemble();
+        assembler
+    }
+
+    //throws the JSON serialised AsmErrors if the source doesn't parse
+    pub fn new_from_source(src: &str) -> Result<Assembler, JsValue> {
+        Assembler::from_source(src).map_err(|errors| JsValue::from_serde(&errors).unwrap())
+    }
+
     fn opcode_to_u16(op: &Opcode) -> u16 {
         match op {
-            LDRegByte(reg, byte) => (0x6 << 12) | (reg << 8) | (byte),
-            LDRegReg(reg1, reg2) => (0x8 << 12) | (reg1 << 8) | (reg2 << 4) | (0x0),
-            AddRegReg(reg1, reg2) => (0x8 << 12) | (reg1 << 8) | (reg2 << 4) | (0x4),
-            SubRegReg(reg1, reg2) => (0x8 << 12) | (reg1 << 8) | (reg2 << 4) | (0x5),
-            SERegReg(reg1, reg2) => (0x5 << 12) | (reg1 << 8) | (reg2 << 4) | (0x0),
-            SNERegReg(reg1, reg2) => (0x9 << 12) | (reg1 << 8) | (reg2 << 4) | (0x0),
-            LDFReg(reg) => (0xF << 12) | (reg << 8) | (0x29),
-            LDIReg(reg) => (0xF << 12) | (reg << 8) | (0x55),
-            LDRegI(reg) => (0xF << 12) | (reg << 8) | (0x65),
-            LDDTReg(reg) => (0xF << 12) | (reg << 8) | (0x15),
-            LDRegDT(reg) => (0xF << 12) | (reg << 8) | (0x07),
-            LDSTReg(reg) => (0xF << 12) | (reg << 8) | (0x18),
-            LDRegKey(reg) => (0xF << 12) | (reg << 8) | (0x0A),
-            LDIAddr(addr) => (0xA << 12) | (addr),
-            RNDRegByte(reg, byte) => (0xC << 12) | (reg << 8) | (byte),
-            DRWRegRegNibble(reg1, reg2, nib) => (0xD << 12) | (reg1 << 8) | (reg2 << 4) | (nib),
-            JP(addr) => (0x1 << 12) | (addr),
-            CALL(addr) => (0x2 << 12) | (addr),
+            LDRegByte(reg, byte) => (0x6 << 12) | (reg.value() << 8) | (*byte as u16),
+            LDRegReg(reg1, reg2) => (0x8 << 12) | (reg1.value() << 8) | (reg2.value() << 4) | (0x0),
+            AddRegReg(reg1, reg2) => {
+                (0x8 << 12) | (reg1.value() << 8) | (reg2.value() << 4) | (0x4)
+            }
+            SubRegReg(reg1, reg2) => {
+                (0x8 << 12) | (reg1.value() << 8) | (reg2.value() << 4) | (0x5)
+            }
+            AndRegReg(reg1, reg2) => {
+                (0x8 << 12) | (reg1.value() << 8) | (reg2.value() << 4) | (0x2)
+            }
+            XorRegReg(reg1, reg2) => {
+                (0x8 << 12) | (reg1.value() << 8) | (reg2.value() << 4) | (0x3)
+            }
+            SERegReg(reg1, reg2) => (0x5 << 12) | (reg1.value() << 8) | (reg2.value() << 4) | (0x0),
+            SNERegReg(reg1, reg2) => {
+                (0x9 << 12) | (reg1.value() << 8) | (reg2.value() << 4) | (0x0)
+            }
+            SERegByte(reg, byte) => (0x3 << 12) | (reg.value() << 8) | (*byte as u16),
+            SNERegByte(reg, byte) => (0x4 << 12) | (reg.value() << 8) | (*byte as u16),
+            LDFReg(reg) => (0xF << 12) | (reg.value() << 8) | (0x29),
+            LDIReg(reg) => (0xF << 12) | (reg.value() << 8) | (0x55),
+            LDRegI(reg) => (0xF << 12) | (reg.value() << 8) | (0x65),
+            LDDTReg(reg) => (0xF << 12) | (reg.value() << 8) | (0x15),
+            LDRegDT(reg) => (0xF << 12) | (reg.value() << 8) | (0x07),
+            LDSTReg(reg) => (0xF << 12) | (reg.value() << 8) | (0x18),
+            LDRegKey(reg) => (0xF << 12) | (reg.value() << 8) | (0x0A),
+            SKPReg(reg) => (0xE << 12) | (reg.value() << 8) | (0x9E),
+            SKNPReg(reg) => (0xE << 12) | (reg.value() << 8) | (0xA1),
+            LDIAddr(addr) => (0xA << 12) | (addr.value()),
+            AddIReg(reg) => (0xF << 12) | (reg.value() << 8) | (0x1E),
+            RNDRegByte(reg, byte) => (0xC << 12) | (reg.value() << 8) | (*byte as u16),
+            DRWRegRegNibble(reg1, reg2, nib) => {
+                (0xD << 12) | (reg1.value() << 8) | (reg2.value() << 4) | (nib.value())
+            }
+            JP(addr) => (0x1 << 12) | (addr.value()),
+            CALL(addr) => (0x2 << 12) | (addr.value()),
             RET => 0x00EE,
+            Opcode::Nop => 0x0000,
+            DW(word) => *word,
         }
     }
 
@@ -57,6 +130,55 @@ impl Assembler {
         }
     }
 
+    //throws the message from assemble_to_size()
+    pub fn assemble_padded(&mut self, size: usize, fill: u8) -> Result<(), JsValue> {
+        self.assemble_to_size(size, fill)
+            .map_err(|message| JsValue::from_str(&message))
+    }
+
+    pub fn patch_byte(&mut self, offset: usize, value: u8) {
+        match self.binary.get_mut(offset) {
+            Some(byte) => *byte = value,
+            None => panic!("Patching binary out of range"),
+        }
+
+        let word = offset / 2;
+        self.binary_u16[word] =
+            u16::from_be_bytes([self.binary[word * 2], self.binary[word * 2 + 1]]);
+    }
+
+    pub fn patch_word(&mut self, offset: usize, word: u16) {
+        if offset + 1 >= self.binary.len() {
+            panic!("Patching binary out of range");
+        }
+
+        let split = word.to_be_bytes();
+        self.patch_byte(offset, split[0]);
+        self.patch_byte(offset + 1, split[1]);
+    }
+
+    pub fn listing(&self) -> String {
+        self.asm
+            .iter()
+            .zip(self.binary_u16.iter())
+            .enumerate()
+            .map(|(i, (op, word))| {
+                format!(
+                    "{:04X}: {:04X}   {}",
+                    asm_bytes_len(i),
+                    word,
+                    listing_operation(op)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    //[{ addr, kind }] for every instruction with an absolute target
+    pub fn relocations(&self) -> JsValue {
+        JsValue::from_serde(&self.relocation_table()).unwrap()
+    }
+
     pub fn stringify_binary(&self) -> String {
         self.binary_u16
             .iter()
@@ -71,6 +193,246 @@ impl Assembler {
     pub fn binary(&self) -> &Vec<u8> {
         &self.binary
     }
+
+    //assemble() followed by filling the binary out to size bytes, for loaders and cartridges
+    //that expect a fixed ROM size. Errors, leaving the binary unpadded, if it's already larger
+    //or size isn't a whole number of instructions.
+    pub fn assemble_to_size(&mut self, size: usize, fill: u8) -> Result<(), String> {
+        self.assemble();
+        if !size.is_multiple_of(2) {
+            return Err(format!(
+                "ROM size {} is not a whole number of 2 byte words",
+                size
+            ));
+        }
+        if self.binary.len() > size {
+            return Err(format!(
+                "program is {} bytes, larger than the {} byte ROM",
+                self.binary.len(),
+                size
+            ));
+        }
+        self.binary.resize(size, fill);
+        self.binary_u16 = self
+            .binary
+            .chunks_exact(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect();
+        Ok(())
+    }
+
+    //writes to the registers compiled code relies on, for hand-written asm mixed with compiler
+    //output. VF is flagged when an instruction targets it directly; the carry/borrow the ALU ops
+    //leave there is expected. VD (frame pointer) and VE (scratch) only matter once functions
+    //are called, so they're flagged only when the program contains a CALL.
+    pub fn check_reserved_registers(&self) -> Vec<String> {
+        let frames = self.asm.iter().any(|op| matches!(op, CALL(_)));
+        let mut reserved = vec![(0xF, "flags")];
+        if frames {
+            reserved.push((0xD, "the frame pointer"));
+            reserved.push((0xE, "scratch"));
+        }
+
+        let mut warnings = Vec::new();
+        for (i, op) in self.asm.iter().enumerate() {
+            let written = match op {
+                LDRegByte(x, _)
+                | LDRegReg(x, _)
+                | AddRegReg(x, _)
+                | SubRegReg(x, _)
+                | AndRegReg(x, _)
+                | XorRegReg(x, _)
+                | LDRegDT(x)
+                | LDRegKey(x)
+                | RNDRegByte(x, _) => x.value()..=x.value(),
+                //Fx65 fills V0 through Vx
+                LDRegI(x) => 0..=x.value(),
+                _ => continue,
+            };
+            for (reg, role) in reserved.iter() {
+                if written.contains(reg) {
+                    warnings.push(format!(
+                        "opcode {} ({}) writes V{:X}, reserved for {}",
+                        i, op, reg, role
+                    ));
+                }
+            }
+        }
+        warnings
+    }
+
+    //instructions to rebase when the program is loaded somewhere other than 0x200. LD I only
+    //counts when it points into the program itself, `I = 5` style loads of fixed addresses such
+    //as the font stay put.
+    pub fn relocation_table(&self) -> Vec<Relocation> {
+        let program = 0x200..asm_bytes_len(self.asm.len());
+        self.asm
+            .iter()
+            .enumerate()
+            .filter_map(|(i, op)| {
+                let kind = match op {
+                    JP(_) => RelocationKind::Jp,
+                    CALL(_) => RelocationKind::Call,
+                    LDIAddr(addr) if program.contains(&addr.value()) => RelocationKind::LdI,
+                    _ => return None,
+                };
+                Some(Relocation {
+                    addr: asm_bytes_len(i),
+                    kind,
+                })
+            })
+            .collect()
+    }
+
+    //parses hand-written assembly in the same syntax as Compiler::stringify_asm, one instruction
+    //per line with hex operands. `;` starts a comment. Lines are numbered from 0 like the lexer.
+    pub fn from_source(src: &str) -> Result<Assembler, Vec<AsmError>> {
+        let mut asm = Vec::new();
+        let mut errors = Vec::new();
+
+        for (line, text) in src.lines().enumerate() {
+            let text = text.split(';').next().unwrap().trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            match Assembler::parse_line(text) {
+                Ok(op) => asm.push(op),
+                Err(message) => errors.push(AsmError::new(line as u32, message)),
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(Assembler {
+                asm,
+                binary_u16: Vec::new(),
+                binary: Vec::new(),
+            }),
+            false => Err(errors),
+        }
+    }
+
+    fn parse_line(text: &str) -> Result<Opcode, String> {
+        let (mnemonic, rest) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, rest)) => (mnemonic, rest.trim()),
+            None => (text, ""),
+        };
+        let operands: Vec<&str> = match rest.is_empty() {
+            true => Vec::new(),
+            false => rest.split(',').map(|operand| operand.trim()).collect(),
+        };
+
+        let expected = match mnemonic.to_uppercase().as_str() {
+            "RET" | "NOP" => 0,
+            "JP" | "CALL" | "DW" | "SKP" | "SKNP" => 1,
+            "DRW" => 3,
+            "LD" | "ADD" | "SUB" | "AND" | "XOR" | "SE" | "SNE" | "RND" => 2,
+            _ => return Err(format!("unknown mnemonic {}", mnemonic)),
+        };
+        if operands.len() < expected {
+            return Err(format!("missing operand for {}", mnemonic));
+        }
+        if operands.len() > expected {
+            return Err(format!("too many operands for {}", mnemonic));
+        }
+
+        let op = match (mnemonic.to_uppercase().as_str(), operands.as_slice()) {
+            ("RET", []) => RET,
+            ("NOP", []) => Opcode::Nop,
+            ("JP", [addr]) => JP(Assembler::parse_addr(addr)?),
+            ("CALL", [addr]) => CALL(Assembler::parse_addr(addr)?),
+            ("DW", [word]) => DW(Assembler::parse_word(word)?),
+            ("SKP", [x]) => SKPReg(Assembler::parse_reg(x)?),
+            ("SKNP", [x]) => SKNPReg(Assembler::parse_reg(x)?),
+            ("ADD", [i, x]) if i.eq_ignore_ascii_case("I") => AddIReg(Assembler::parse_reg(x)?),
+            ("ADD", [x, y]) => AddRegReg(Assembler::parse_reg(x)?, Assembler::parse_reg(y)?),
+            ("SUB", [x, y]) => SubRegReg(Assembler::parse_reg(x)?, Assembler::parse_reg(y)?),
+            ("AND", [x, y]) => AndRegReg(Assembler::parse_reg(x)?, Assembler::parse_reg(y)?),
+            ("XOR", [x, y]) => XorRegReg(Assembler::parse_reg(x)?, Assembler::parse_reg(y)?),
+            ("SE", [x, y]) => match Assembler::parse_reg(y) {
+                Ok(y) => SERegReg(Assembler::parse_reg(x)?, y),
+                Err(_) => SERegByte(Assembler::parse_reg(x)?, Assembler::parse_byte(y)?),
+            },
+            ("SNE", [x, y]) => match Assembler::parse_reg(y) {
+                Ok(y) => SNERegReg(Assembler::parse_reg(x)?, y),
+                Err(_) => SNERegByte(Assembler::parse_reg(x)?, Assembler::parse_byte(y)?),
+            },
+            ("RND", [x, byte]) => {
+                RNDRegByte(Assembler::parse_reg(x)?, Assembler::parse_byte(byte)?)
+            }
+            ("DRW", [x, y, n]) => DRWRegRegNibble(
+                Assembler::parse_reg(x)?,
+                Assembler::parse_reg(y)?,
+                Assembler::parse_nibble(n)?,
+            ),
+            ("LD", [dst, src]) => {
+                match (dst.to_uppercase().as_str(), src.to_uppercase().as_str()) {
+                    ("F", _) => LDFReg(Assembler::parse_reg(src)?),
+                    ("[I]", _) => LDIReg(Assembler::parse_reg(src)?),
+                    ("DT", _) => LDDTReg(Assembler::parse_reg(src)?),
+                    ("ST", _) => LDSTReg(Assembler::parse_reg(src)?),
+                    ("I", _) => LDIAddr(Assembler::parse_addr(src)?),
+                    (_, "[I]") => LDRegI(Assembler::parse_reg(dst)?),
+                    (_, "DT") => LDRegDT(Assembler::parse_reg(dst)?),
+                    (_, "K") => LDRegKey(Assembler::parse_reg(dst)?),
+                    _ => match Assembler::parse_reg(src) {
+                        Ok(y) => LDRegReg(Assembler::parse_reg(dst)?, y),
+                        Err(_) => {
+                            LDRegByte(Assembler::parse_reg(dst)?, Assembler::parse_byte(src)?)
+                        }
+                    },
+                }
+            }
+            _ => unreachable!(),
+        };
+        Ok(op)
+    }
+
+    fn parse_reg(operand: &str) -> Result<Reg, String> {
+        let mut chars = operand.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('V'), Some(index), None) | (Some('v'), Some(index), None) => {
+                match index.to_digit(16) {
+                    Some(index) => Ok(Reg::new(index as u16)),
+                    None => Err(format!("expected register, found {}", operand)),
+                }
+            }
+            _ => Err(format!("expected register, found {}", operand)),
+        }
+    }
+
+    fn parse_hex(operand: &str) -> Result<u32, String> {
+        let digits = operand.trim_start_matches("0x").trim_start_matches("0X");
+        u32::from_str_radix(digits, 16).map_err(|_| format!("expected number, found {}", operand))
+    }
+
+    fn parse_byte(operand: &str) -> Result<u8, String> {
+        match Assembler::parse_hex(operand)? {
+            byte @ 0x00..=0xFF => Ok(byte as u8),
+            _ => Err(format!("byte {} out of range", operand)),
+        }
+    }
+
+    fn parse_nibble(operand: &str) -> Result<Nibble, String> {
+        match Assembler::parse_hex(operand)? {
+            nibble @ 0x0..=0xF => Ok(Nibble::new(nibble as u16)),
+            _ => Err(format!("nibble {} out of range", operand)),
+        }
+    }
+
+    fn parse_addr(operand: &str) -> Result<Addr, String> {
+        match Assembler::parse_hex(operand)? {
+            addr @ 0x000..=0xFFF => Ok(Addr::new(addr as u16)),
+            _ => Err(format!("address {} out of range", operand)),
+        }
+    }
+
+    fn parse_word(operand: &str) -> Result<u16, String> {
+        match Assembler::parse_hex(operand)? {
+            word @ 0x0000..=0xFFFF => Ok(word as u16),
+            _ => Err(format!("word {} out of range", operand)),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -80,9 +442,26 @@ mod tests {
 
     #[test]
     pub fn test_opcode_to_u16() {
-        //println!("{}", Assembler::opcode_to_u16(&LDRegByte(0, 0xD)));
-        assert_eq!(Assembler::opcode_to_u16(&LDRegByte(0, 0xD)), 0x600D);
-        assert_eq!(Assembler::opcode_to_u16(&AddRegReg(4, 15)), 0x84F4);
+        //println!("{}", Assembler::opcode_to_u16(&LDRegByte(Reg::new(0), 0xD)));
+        assert_eq!(
+            Assembler::opcode_to_u16(&LDRegByte(Reg::new(0), 0xD)),
+            0x600D
+        );
+        assert_eq!(
+            Assembler::opcode_to_u16(&AddRegReg(Reg::new(4), Reg::new(15))),
+            0x84F4
+        );
+        assert_eq!(Assembler::opcode_to_u16(&Opcode::Nop), 0x0000);
+        assert_eq!(Assembler::opcode_to_u16(&DW(0x8000)), 0x8000);
+        assert_eq!(
+            Assembler::opcode_to_u16(&SERegByte(Reg::new(3), 0x2A)),
+            0x332A
+        );
+        assert_eq!(
+            Assembler::opcode_to_u16(&SNERegByte(Reg::new(3), 0x2A)),
+            0x432A
+        );
+        assert_eq!(Assembler::opcode_to_u16(&AddIReg(Reg::new(7))), 0xF71E);
     }
 
     #[test]
@@ -104,6 +483,190 @@ mod tests {
         ));
     }
 
+    #[test]
+    pub fn test_listing() {
+        let mut l = Lexer::new("14 + 14;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        let mut a = Assembler::new_from_compiler(&c);
+        a.assemble();
+
+        assert_eq!(
+            a.listing(),
+            "0200: 600E   LD V0, 0E\n0202: 610E   LD V1, 0E\n0204: 8014   ADD V0, V1"
+        );
+    }
+
+    #[test]
+    pub fn test_patch() {
+        let mut l = Lexer::new("14 + 14;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        let mut a = Assembler::new_from_compiler(&c);
+        a.assemble();
+
+        a.patch_byte(3, 0x2A);
+        assert!(utils::vectors_equivalent(
+            a.binary.clone(),
+            vec![0x60, 0x0E, 0x61, 0x2A, 0x80, 0x14]
+        ));
+        assert_eq!(a.stringify_binary(), "600E 612A 8014");
+
+        a.patch_word(4, 0x8015);
+        assert_eq!(a.stringify_binary(), "600E 612A 8015");
+    }
+
+    #[test]
+    #[should_panic(expected = "Patching binary out of range")]
+    pub fn test_patch_out_of_range() {
+        let mut l = Lexer::new("14 + 14;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        let mut a = Assembler::new_from_compiler(&c);
+        a.assemble();
+
+        a.patch_word(5, 0x1234);
+    }
+
+    #[test]
+    pub fn test_from_source() {
+        let mut l =
+            Lexer::new("var a = 3; DT = a; while (a != 0) { a = a - 1; } I = 20; DRAW(a, a, 5);");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        let a = Assembler::from_source(&c.stringify_asm()).ok().unwrap();
+        assert_eq!(&a.asm, c.asm());
+    }
+
+    #[test]
+    pub fn test_assemble_compiler() {
+        let mut l = Lexer::new("9 - 7;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        let mut two_step = Assembler::new_from_compiler(&c);
+        two_step.assemble();
+        let one_step = Assembler::assemble_compiler(&c);
+        assert!(!one_step.binary().is_empty());
+        assert_eq!(one_step.binary(), two_step.binary());
+    }
+
+    #[test]
+    pub fn test_from_source_add_i() {
+        let a = Assembler::from_source("ADD I, V7\nadd i, va\nADD V1, V2")
+            .ok()
+            .unwrap();
+        assert_eq!(
+            a.asm,
+            vec![
+                AddIReg(Reg::new(7)),
+                AddIReg(Reg::new(0xA)),
+                AddRegReg(Reg::new(1), Reg::new(2))
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_assemble_to_size() {
+        let mut a = Assembler::from_source("LD V0, 5\nJP 202").unwrap();
+        assert_eq!(a.assemble_to_size(16, 0xFF), Ok(()));
+        assert_eq!(a.binary.len(), 16);
+        assert_eq!(&a.binary[..4], &[0x60, 0x05, 0x12, 0x02]);
+        assert!(a.binary[4..].iter().all(|byte| *byte == 0xFF));
+        assert_eq!(a.binary_u16.len(), 8);
+
+        let mut a = Assembler::from_source("LD V0, 5\nJP 202").unwrap();
+        assert_eq!(
+            a.assemble_to_size(2, 0),
+            Err(String::from(
+                "program is 4 bytes, larger than the 2 byte ROM"
+            ))
+        );
+        assert_eq!(a.binary.len(), 4);
+
+        let mut a = Assembler::from_source("LD V0, 5\nJP 202").unwrap();
+        assert_eq!(
+            a.assemble_to_size(7, 0),
+            Err(String::from(
+                "ROM size 7 is not a whole number of 2 byte words"
+            ))
+        );
+        assert_eq!(a.binary.len(), 4);
+        assert_eq!(a.binary_u16.len(), 2);
+    }
+
+    #[test]
+    pub fn test_check_reserved_registers() {
+        let mut a = Assembler::from_source("LD VD, 5\nADD V0, V1\nLD VF, 1\nLD VF, [I]").unwrap();
+        assert_eq!(a.asm[0], LDRegByte(Reg::new(0xD), 5));
+        assert_eq!(
+            a.check_reserved_registers(),
+            vec![
+                String::from("opcode 2 (LD VF, 1) writes VF, reserved for flags"),
+                String::from("opcode 3 (LD VF, [I]) writes VF, reserved for flags"),
+            ]
+        );
+
+        //once functions are called VD and VE belong to the calling convention too
+        a.asm.push(CALL(Addr::new(0x200)));
+        let warnings = a.check_reserved_registers();
+        assert_eq!(
+            warnings[0],
+            "opcode 0 (LD VD, 5) writes VD, reserved for the frame pointer"
+        );
+        assert_eq!(warnings.len(), 5);
+    }
+
+    #[test]
+    pub fn test_relocations() {
+        let mut l =
+            Lexer::new("fn f(a) { a = a + 1; } f(1); I = 5; hline(0, 0, 1); while (1 != 0) {}");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        let a = Assembler::new_from_compiler(&c);
+
+        let reloc = |addr, kind| Relocation { addr, kind };
+        assert_eq!(
+            a.relocation_table(),
+            vec![
+                //over the function body, then the call into it
+                reloc(0x200, RelocationKind::Jp),
+                reloc(0x220, RelocationKind::Call),
+                //hline's sprite in the data region, but not `LD I, 5`
+                reloc(0x22A, RelocationKind::LdI),
+                reloc(0x22E, RelocationKind::Jp),
+                reloc(0x238, RelocationKind::Jp),
+                reloc(0x240, RelocationKind::Jp),
+                reloc(0x242, RelocationKind::Jp),
+                //the halt before the data
+                reloc(0x244, RelocationKind::Jp),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_from_source_errors() {
+        let errors = Assembler::from_source("LD V0, 5\nMOV V0, V1\n\nADD V0 ; missing y\nJP 1000")
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            errors,
+            vec![
+                AsmError::new(1, String::from("unknown mnemonic MOV")),
+                AsmError::new(3, String::from("missing operand for ADD")),
+                AsmError::new(4, String::from("address 1000 out of range")),
+            ]
+        );
+    }
+
     #[test]
     pub fn test_sub() {
         let mut l = Lexer::new("9 - 7;");
@@ -119,3 +682,131 @@ mod tests {
         ));
     }
 }
+
+//encodes every Opcode with a spread of operand values and decodes the word again through the
+//emulator's tables. Display is written to match the disassembler, so any miswired nibble shows
+//up as a text mismatch
+#[cfg(test)]
+mod encoding_tests {
+    use super::Assembler;
+    use crate::chip8::Chip8;
+    use crate::compiler::Opcode::*;
+    use crate::compiler::*;
+
+    const REGS: [u16; 3] = [0x0, 0x5, 0xF];
+    const BYTES: [u8; 3] = [0x00, 0x3C, 0xFF];
+    const ADDRS: [u16; 3] = [0x000, 0x2A4, 0xFFF];
+    const NIBBLES: [u16; 3] = [0x0, 0x7, 0xF];
+
+    fn reg_pairs() -> Vec<(Reg, Reg)> {
+        REGS.iter()
+            .flat_map(|x| REGS.iter().map(move |y| (Reg::new(*x), Reg::new(*y))))
+            .collect()
+    }
+
+    fn representatives() -> Vec<Opcode> {
+        let mut ops = Vec::new();
+        for x in REGS.iter().map(|x| Reg::new(*x)) {
+            ops.extend([
+                LDFReg(x),
+                LDIReg(x),
+                LDRegI(x),
+                LDDTReg(x),
+                LDRegDT(x),
+                LDSTReg(x),
+                LDRegKey(x),
+                SKPReg(x),
+                SKNPReg(x),
+                AddIReg(x),
+            ]);
+            for byte in BYTES {
+                ops.extend([
+                    LDRegByte(x, byte),
+                    SERegByte(x, byte),
+                    SNERegByte(x, byte),
+                    RNDRegByte(x, byte),
+                ]);
+            }
+        }
+        for (x, y) in reg_pairs() {
+            ops.extend([
+                LDRegReg(x, y),
+                AddRegReg(x, y),
+                SubRegReg(x, y),
+                AndRegReg(x, y),
+                XorRegReg(x, y),
+                SERegReg(x, y),
+                SNERegReg(x, y),
+            ]);
+            for n in NIBBLES {
+                ops.push(DRWRegRegNibble(x, y, Nibble::new(n)));
+            }
+        }
+        for addr in ADDRS.iter().map(|addr| Addr::new(*addr)) {
+            ops.extend([LDIAddr(addr), JP(addr), CALL(addr)]);
+        }
+        ops.extend([RET, Opcode::Nop, DW(0x1234)]);
+        ops
+    }
+
+    //the text the disassembler should print for op, None for raw data which has no mnemonic of
+    //its own. Listing every variant here makes a new Opcode fail to compile until it's covered
+    fn expected_disasm(op: &Opcode) -> Option<String> {
+        match op {
+            LDRegByte(..)
+            | LDRegReg(..)
+            | AddRegReg(..)
+            | SubRegReg(..)
+            | AndRegReg(..)
+            | XorRegReg(..)
+            | SERegReg(..)
+            | SNERegReg(..)
+            | SERegByte(..)
+            | SNERegByte(..)
+            | LDFReg(..)
+            | LDIReg(..)
+            | LDRegI(..)
+            | LDDTReg(..)
+            | LDRegDT(..)
+            | LDSTReg(..)
+            | LDRegKey(..)
+            | SKPReg(..)
+            | SKNPReg(..)
+            | LDIAddr(..)
+            | AddIReg(..)
+            | RNDRegByte(..)
+            | DRWRegRegNibble(..)
+            | JP(..)
+            | CALL(..)
+            | RET
+            | Opcode::Nop => Some(op.to_string()),
+            DW(..) => None,
+        }
+    }
+
+    #[test]
+    pub fn test_encode_decode_round_trip() {
+        let ops = representatives();
+        let mut rom = Vec::new();
+        for op in ops.iter() {
+            rom.extend(Assembler::opcode_to_u16(op).to_be_bytes());
+        }
+
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_bytes(&rom);
+        c8.disassemble();
+        let lines = c8.disasm_lines();
+
+        for (op, line) in ops.iter().zip(lines.iter()) {
+            if let Some(expected) = expected_disasm(op) {
+                assert_eq!(
+                    line.text,
+                    expected,
+                    "{:?} encoded as {:04X}",
+                    op,
+                    Assembler::opcode_to_u16(op)
+                );
+            }
+        }
+    }
+}
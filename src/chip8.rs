@@ -3,7 +3,8 @@ use crate::utils;
 
 use array_init::array_init;
 use getrandom::*;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::io;
 use std::io::Read;
@@ -12,11 +13,227 @@ use wasm_bindgen::prelude::*;
 type Chip8OpcodeFn = fn(&mut Chip8);
 type GetNameFn = fn(&mut Chip8) -> String;
 
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct MemoryRegion {
+    pub label: String,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl MemoryRegion {
+    pub fn new(label: &str, start: u16, end: u16) -> MemoryRegion {
+        MemoryRegion {
+            label: String::from(label),
+            start,
+            end,
+        }
+    }
+}
+
 pub struct Instruction {
     get_disasm: GetNameFn,
     operation: Chip8OpcodeFn,
 }
 
+impl Instruction {
+    fn is_null(&self) -> bool {
+        self.operation as usize == Chip8::OP_null as Chip8OpcodeFn as usize
+    }
+}
+
+//xorshift64* generator, used for RND in place of getrandom once a seed has been set
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        //xorshift never leaves the all-zero state, so fold the seed with a non-zero constant
+        let state = seed ^ 0x9E3779B97F4A7C15;
+        SeededRng {
+            state: if state == 0 {
+                0x9E3779B97F4A7C15
+            } else {
+                state
+            },
+        }
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545F4914F6CDD1D) >> 56) as u8
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Classic,
+    SuperChip,
+    XoChip,
+}
+
+//behaviours that differ between CHIP-8 interpreters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    //Fx55/Fx65 leave I pointing past the last register transferred
+    pub load_store_increment: bool,
+    //Bnnn jumps to VX + nnn, X being the high nibble of nnn, rather than V0 + nnn
+    pub jump_with_vx: bool,
+    //8xy6/8xyE shift Vy into Vx (COSMAC VIP) rather than shifting Vx in place
+    pub shift_vy: bool,
+}
+
+impl Quirks {
+    pub fn classic() -> Quirks {
+        Quirks {
+            load_store_increment: true,
+            jump_with_vx: false,
+            shift_vy: false,
+        }
+    }
+
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            load_store_increment: true,
+            jump_with_vx: false,
+            shift_vy: true,
+        }
+    }
+
+    pub fn chip48() -> Quirks {
+        Quirks {
+            load_store_increment: false,
+            jump_with_vx: true,
+            shift_vy: false,
+        }
+    }
+
+    pub fn superchip() -> Quirks {
+        Quirks {
+            load_store_increment: false,
+            jump_with_vx: true,
+            shift_vy: false,
+        }
+    }
+
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            load_store_increment: true,
+            jump_with_vx: false,
+            //Octo follows the VIP here
+            shift_vy: true,
+        }
+    }
+}
+
+pub const PROFILES: [&str; 4] = ["cosmac-vip", "chip48", "superchip", "xo-chip"];
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct DisasmLine {
+    pub addr: u16,
+    pub text: String,
+    //a byte of the instruction was stored to while writes were tracked
+    pub self_modified: bool,
+}
+
+//straight-line run of instructions from start to end (the address of the last one) and the
+//blocks control can pass to afterwards
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub end: u16,
+    pub successors: Vec<u16>,
+}
+
+//how many reachable instructions of each quirk-sensitive kind a ROM contains, for suggesting
+//a compatibility profile
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize)]
+pub struct QuirkUsage {
+    //8xy6 and 8xyE, which shift Vy or Vx depending on shift_vy
+    pub shifts: u32,
+    //Fx55 and Fx65, which may or may not advance I
+    pub load_store: u32,
+    //Bnnn, which adds V0 or Vx depending on jump_with_vx
+    pub jump_with_offset: u32,
+    //Dxyn, whose wrapping, clipping and 16x16 Dxy0 differ between interpreters
+    pub draws: u32,
+}
+
+//everything a reversible instruction can change, as it was before the instruction ran
+#[derive(Debug, Clone, Copy)]
+struct UndoEntry {
+    pc: u16,
+    I: u16,
+    sp: u8,
+    //the stack slot a CALL would overwrite
+    stack_slot: u16,
+    x: u8,
+    vx: u8,
+    vf: u8,
+    delay_timer: u8,
+    sound_timer: u8,
+    cycles: u64,
+}
+
+//instructions undo() can step back over
+const UNDO_LOG_LEN: usize = 256;
+
+//one executed instruction and the registers it left behind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u16,
+    pub V: [u8; 16],
+    pub I: u16,
+}
+
+//first cycle at which two traces disagree. A pc is None when that trace ended first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TraceDivergence {
+    pub cycle: usize,
+    pub pc: Option<u16>,
+    pub other_pc: Option<u16>,
+}
+
+//why run() returned control to the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    MaxCycles,
+    //pc reached an address passed to add_breakpoint()
+    Breakpoint,
+    //a JP to its own address, which would spin forever
+    Halt,
+    KeyWait,
+    //the next opcode has no handler, or the machine faulted
+    Illegal,
+    //the cycle budget ran out without the program halting, it may be stuck in a loop
+    Timeout,
+    //a Dxyn or 00E0 ran, see run_until_draw()
+    Draw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RunResult {
+    pub cycles_run: u32,
+    pub stop_reason: StopReason,
+}
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    Running,
+    //Fx0A is waiting and the loop can stop clocking until set_key() is called
+    BlockedOnKey,
+    Faulted,
+    //see StopReason::Timeout
+    TimedOut,
+}
+
 #[derive(Debug, Clone)]
 pub struct Chip8State {
     //next opcode for fetch-execute-decode cycle
@@ -27,8 +244,13 @@ pub struct Chip8State {
     I: u16,
     //program counter
     pc: u16,
-    //64*32 framebuffer
-    framebuffer: [u32; 64 * 32],
+    //64*32 framebuffer, or 128*64 in hires mode. Pixels are 0 or 0xFFFFFFFF except in XO-CHIP
+    //mode, where bit n is set while the pixel is lit on bit plane n
+    framebuffer: [u32; 128 * 64],
+    //XO-CHIP planes that Dxyn and 00E0 act on, selected by Fn01
+    plane_mask: u8,
+    //SCHIP 128*64 display, toggled by 00FF/00FE
+    hires: bool,
     //timers
     delay_timer: u8,
     sound_timer: u8,
@@ -38,8 +260,25 @@ pub struct Chip8State {
     sp: u8,
     //key status
     keys: [u8; 16],
-    //4096 bytes of addressable memory
-    ram: [u8; 4096],
+    //4096 bytes of addressable memory, 65536 for XO-CHIP
+    ram: Vec<u8>,
+    //recoverable fault, clock() does nothing while set
+    error: Option<String>,
+    //deterministic RND source, getrandom is used when None
+    rng: Option<SeededRng>,
+    //machine cycles the executed instructions would have taken on a COSMAC VIP
+    cycles: u64,
+    //60Hz frames elapsed, one per tick_timers()
+    frame_count: u64,
+    //set while Fx0A has no key to consume
+    waiting_for_key: bool,
+    //VF as left by the most recent Dxyn
+    last_draw_collision: bool,
+    //whether the sound timer was running after the previous tick_timers(), and the edges the
+    //latest tick found against it
+    sound_on: bool,
+    sound_started: bool,
+    sound_stopped: bool,
 }
 
 impl Chip8State {
@@ -49,13 +288,24 @@ impl Chip8State {
             V: [0; 16],
             I: 0,
             pc: 0,
-            framebuffer: [0; 64 * 32],
+            framebuffer: [0; 128 * 64],
+            plane_mask: 1,
+            hires: false,
             delay_timer: 0,
             sound_timer: 0,
             stack: [0; 16],
             sp: 0,
             keys: [0; 16],
-            ram: [0; 4096],
+            ram: vec![0; 4096],
+            error: None,
+            rng: None,
+            cycles: 0,
+            frame_count: 0,
+            waiting_for_key: false,
+            last_draw_collision: false,
+            sound_on: false,
+            sound_started: false,
+            sound_stopped: false,
         }
     }
 }
@@ -69,8 +319,28 @@ pub struct Chip8 {
     //chip built-in fontset
     fontset: [u8; 80],
 
-    video_width: u32,
-    video_height: u32,
+    mode: Mode,
+    quirks: Quirks,
+
+    //RGBA colours used when expanding the framebuffer
+    fg_color: u32,
+    bg_color: u32,
+
+    //force an odd pc back onto an instruction boundary instead of faulting
+    realign_odd_pc: bool,
+
+    //where ROMs are loaded and reset() points pc, 0x200 unless e.g. an ETI-660 ROM wants 0x600
+    start_address: u16,
+
+    //length in bytes of the ROM loaded at start_address
+    rom_len: usize,
+
+    //seed that reset() reinstalls so every fresh session produces the same RND sequence
+    reset_seed: Option<u64>,
+
+    //SCHIP/XO-CHIP persistent "RPL user flags" written by Fx75 and read by Fx85. Like the HP48
+    //calculator they come from, they survive reset() and are only cleared by power_cycle().
+    rpl_flags: [u8; 16],
 
     disasm_opcode: u16,
 
@@ -78,9 +348,32 @@ pub struct Chip8 {
     opcodes_0: [Instruction; 0xE + 1],
     opcodes_8: [Instruction; 0xE + 1],
     opcodes_E: [Instruction; 0xE + 1],
-    opcodes_F: [Instruction; 0x65 + 1],
+    opcodes_F: [Instruction; 0x85 + 1],
 
     disasm_map: HashMap<u16, String>,
+
+    //addresses run() stops at before executing
+    breakpoints: HashSet<u16>,
+    //the breakpoint run() last stopped at, executed rather than stopped at when the next run
+    //starts there so resuming makes progress
+    resume_breakpoint: Option<u16>,
+
+    //instructions run()/run_for() may execute in total before timing out, counted from the
+    //last reset or set_cycle_budget()
+    cycle_budget: Option<u32>,
+    budget_used: u64,
+
+    //record every executed instruction into trace, cleared on reset
+    tracing: bool,
+    trace: Vec<TraceEntry>,
+
+    //record the addresses instructions store to, so disasm_lines() can flag modified code
+    track_writes: bool,
+    written: HashSet<u16>,
+
+    //deltas undo() reverts, newest last. Emptied by any instruction that can't be undone.
+    undo_enabled: bool,
+    undo_log: VecDeque<UndoEntry>,
 }
 
 #[wasm_bindgen]
@@ -134,7 +427,14 @@ impl Chip8 {
                 operation: Chip8::OP_Annn,
             },
             Instruction {
-                get_disasm: |c8| format!("JP V0, {}", Chip8::get_args_disasm_nnn(c8)),
+                get_disasm: |c8| {
+                    //under the jump_with_vx quirk the high nibble of nnn also picks the register
+                    let x = match c8.quirks.jump_with_vx {
+                        true => (c8.disasm_opcode & 0x0F00u16) >> 8u32,
+                        false => 0,
+                    };
+                    format!("JP V{:X}, {}", x, Chip8::get_args_disasm_nnn(c8))
+                },
                 operation: Chip8::OP_Bnnn,
             },
             Instruction {
@@ -159,109 +459,221 @@ impl Chip8 {
             get_disasm: |_| String::from("null"),
             operation: Chip8::OP_null,
         });
-        opcodes_0[0x0] = Instruction {
-            get_disasm: |_| String::from("CLS"),
-            operation: Chip8::OP_00E0,
-        };
-        opcodes_0[0xE] = Instruction {
-            get_disasm: |_| String::from("RET"),
-            operation: Chip8::OP_00EE,
-        };
+        Chip8::register_opcode(
+            &mut opcodes_0,
+            0x0,
+            Instruction {
+                get_disasm: |_| String::from("CLS"),
+                operation: Chip8::OP_00E0,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_0,
+            0xE,
+            Instruction {
+                get_disasm: |_| String::from("RET"),
+                operation: Chip8::OP_00EE,
+            },
+        );
 
         let mut opcodes_8: [Instruction; 0xE + 1] = array_init(|_i| Instruction {
             get_disasm: |_| String::from("null"),
             operation: Chip8::OP_null,
         });
-        opcodes_8[0x0] = Instruction {
-            get_disasm: |c8| format!("LD {}", Chip8::get_args_disasm_xy(c8)),
-            operation: Chip8::OP_8xy0,
-        };
-        opcodes_8[0x1] = Instruction {
-            get_disasm: |c8| format!("OR {}", Chip8::get_args_disasm_xy(c8)),
-            operation: Chip8::OP_8xy1,
-        };
-        opcodes_8[0x2] = Instruction {
-            get_disasm: |c8| format!("AND {}", Chip8::get_args_disasm_xy(c8)),
-            operation: Chip8::OP_8xy2,
-        };
-        opcodes_8[0x3] = Instruction {
-            get_disasm: |c8| format!("XOR {}", Chip8::get_args_disasm_xy(c8)),
-            operation: Chip8::OP_8xy3,
-        };
-        opcodes_8[0x4] = Instruction {
-            get_disasm: |c8| format!("ADD {}", Chip8::get_args_disasm_xy(c8)),
-            operation: Chip8::OP_8xy4,
-        };
-        opcodes_8[0x5] = Instruction {
-            get_disasm: |c8| format!("SUB {}", Chip8::get_args_disasm_xy(c8)),
-            operation: Chip8::OP_8xy5,
-        };
-        opcodes_8[0x6] = Instruction {
-            get_disasm: |c8| format!("SHR {}", Chip8::get_args_disasm_xy(c8)),
-            operation: Chip8::OP_8xy6,
-        };
-        opcodes_8[0x7] = Instruction {
-            get_disasm: |c8| format!("SUBN {}", Chip8::get_args_disasm_xy(c8)),
-            operation: Chip8::OP_8xy7,
-        };
-        opcodes_8[0xE] = Instruction {
-            get_disasm: |c8| format!("SHL {}", Chip8::get_args_disasm_xy(c8)),
-            operation: Chip8::OP_8xyE,
-        };
+        Chip8::register_opcode(
+            &mut opcodes_8,
+            0x0,
+            Instruction {
+                get_disasm: |c8| format!("LD {}", Chip8::get_args_disasm_xy(c8)),
+                operation: Chip8::OP_8xy0,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_8,
+            0x1,
+            Instruction {
+                get_disasm: |c8| format!("OR {}", Chip8::get_args_disasm_xy(c8)),
+                operation: Chip8::OP_8xy1,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_8,
+            0x2,
+            Instruction {
+                get_disasm: |c8| format!("AND {}", Chip8::get_args_disasm_xy(c8)),
+                operation: Chip8::OP_8xy2,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_8,
+            0x3,
+            Instruction {
+                get_disasm: |c8| format!("XOR {}", Chip8::get_args_disasm_xy(c8)),
+                operation: Chip8::OP_8xy3,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_8,
+            0x4,
+            Instruction {
+                get_disasm: |c8| format!("ADD {}", Chip8::get_args_disasm_xy(c8)),
+                operation: Chip8::OP_8xy4,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_8,
+            0x5,
+            Instruction {
+                get_disasm: |c8| format!("SUB {}", Chip8::get_args_disasm_xy(c8)),
+                operation: Chip8::OP_8xy5,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_8,
+            0x6,
+            Instruction {
+                get_disasm: |c8| format!("SHR {}", Chip8::get_args_disasm_xy(c8)),
+                operation: Chip8::OP_8xy6,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_8,
+            0x7,
+            Instruction {
+                get_disasm: |c8| format!("SUBN {}", Chip8::get_args_disasm_xy(c8)),
+                operation: Chip8::OP_8xy7,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_8,
+            0xE,
+            Instruction {
+                get_disasm: |c8| format!("SHL {}", Chip8::get_args_disasm_xy(c8)),
+                operation: Chip8::OP_8xyE,
+            },
+        );
 
         let mut opcodes_E: [Instruction; 0xE + 1] = array_init(|_i| Instruction {
             get_disasm: |_| String::from("null"),
             operation: Chip8::OP_null,
         });
-        opcodes_E[0xE] = Instruction {
-            get_disasm: |c8| format!("SKP {}", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Ex9E,
-        };
-        opcodes_E[0x1] = Instruction {
-            get_disasm: |c8| format!("SKNP {}", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_ExA1,
-        };
+        Chip8::register_opcode(
+            &mut opcodes_E,
+            0xE,
+            Instruction {
+                get_disasm: |c8| format!("SKP {}", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Ex9E,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_E,
+            0x1,
+            Instruction {
+                get_disasm: |c8| format!("SKNP {}", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_ExA1,
+            },
+        );
 
-        let mut opcodes_F: [Instruction; 0x65 + 1] = array_init(|_i| Instruction {
+        let mut opcodes_F: [Instruction; 0x85 + 1] = array_init(|_i| Instruction {
             get_disasm: |_| String::from("null"),
             operation: Chip8::OP_null,
         });
-        opcodes_F[0x07] = Instruction {
-            get_disasm: |c8| format!("LD {}, DT", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Fx07,
-        };
-        opcodes_F[0x0A] = Instruction {
-            get_disasm: |c8| format!("LD {}, K", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Fx0A,
-        };
-        opcodes_F[0x15] = Instruction {
-            get_disasm: |c8| format!("LD DT, {}", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Fx15,
-        };
-        opcodes_F[0x18] = Instruction {
-            get_disasm: |c8| format!("LD ST, {}", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Fx18,
-        };
-        opcodes_F[0x1E] = Instruction {
-            get_disasm: |c8| format!("ADD I, {}", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Fx1E,
-        };
-        opcodes_F[0x29] = Instruction {
-            get_disasm: |c8| format!("LD F, {}", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Fx29,
-        };
-        opcodes_F[0x33] = Instruction {
-            get_disasm: |c8| format!("LD B, {}", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Fx33,
-        };
-        opcodes_F[0x55] = Instruction {
-            get_disasm: |c8| format!("LD [I], {}", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Fx55,
-        };
-        opcodes_F[0x65] = Instruction {
-            get_disasm: |c8| format!("LD {}, [I]", Chip8::get_args_disasm_x(c8)),
-            operation: Chip8::OP_Fx65,
-        };
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x01,
+            Instruction {
+                get_disasm: |c8| format!("PLANE {}", (c8.disasm_opcode & 0x0F00) >> 8),
+                operation: Chip8::OP_Fn01,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x07,
+            Instruction {
+                get_disasm: |c8| format!("LD {}, DT", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx07,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x0A,
+            Instruction {
+                get_disasm: |c8| format!("LD {}, K", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx0A,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x15,
+            Instruction {
+                get_disasm: |c8| format!("LD DT, {}", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx15,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x18,
+            Instruction {
+                get_disasm: |c8| format!("LD ST, {}", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx18,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x1E,
+            Instruction {
+                get_disasm: |c8| format!("ADD I, {}", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx1E,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x29,
+            Instruction {
+                get_disasm: |c8| format!("LD F, {}", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx29,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x33,
+            Instruction {
+                get_disasm: |c8| format!("LD B, {}", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx33,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x55,
+            Instruction {
+                get_disasm: |c8| format!("LD [I], {}", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx55,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x65,
+            Instruction {
+                get_disasm: |c8| format!("LD {}, [I]", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx65,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x75,
+            Instruction {
+                get_disasm: |c8| format!("LD R, {}", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx75,
+            },
+        );
+        Chip8::register_opcode(
+            &mut opcodes_F,
+            0x85,
+            Instruction {
+                get_disasm: |c8| format!("LD {}, R", Chip8::get_args_disasm_x(c8)),
+                operation: Chip8::OP_Fx85,
+            },
+        );
 
         Chip8 {
             state: Chip8State::new(),
@@ -286,14 +698,31 @@ impl Chip8 {
                 0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
                 0xF0, 0x80, 0xF0, 0x80, 0x80, // F
             ],
-            video_width: 64,
-            video_height: 32,
+            mode: Mode::Classic,
+            quirks: Quirks::classic(),
+            fg_color: 0xFFFFFFFF,
+            bg_color: 0x000000FF,
+            realign_odd_pc: false,
+            start_address: 0x200,
+            rom_len: 0,
+            reset_seed: None,
+            rpl_flags: [0; 16],
             opcodes: opcodes,
             opcodes_0: opcodes_0,
             opcodes_8: opcodes_8,
             opcodes_E: opcodes_E,
             opcodes_F: opcodes_F,
             disasm_map: HashMap::new(),
+            breakpoints: HashSet::new(),
+            resume_breakpoint: None,
+            cycle_budget: None,
+            budget_used: 0,
+            tracing: false,
+            trace: Vec::new(),
+            track_writes: false,
+            written: HashSet::new(),
+            undo_enabled: false,
+            undo_log: VecDeque::new(),
             disasm_opcode: 0,
         }
     }
@@ -310,6 +739,11 @@ impl Chip8 {
         self.state.V.as_ptr()
     }
 
+    //single register read, None (undefined in JS) past VF
+    pub fn register(&self, index: u8) -> Option<u8> {
+        self.state.V.get(index as usize).copied()
+    }
+
     pub fn pc(&self) -> u16 {
         self.state.pc
     }
@@ -330,139 +764,725 @@ impl Chip8 {
         self.state.sound_timer
     }
 
-    pub fn video_height(&self) -> u32 {
-        self.video_height
+    pub fn estimated_cycles(&self) -> u64 {
+        self.state.cycles
     }
 
-    pub fn video_width(&self) -> u32 {
-        self.video_width
+    pub fn frame_count(&self) -> u64 {
+        self.state.frame_count
     }
 
-    pub fn save_state(&mut self) {
-        self.saved_state = self.state.clone();
+    //whether the last Dxyn erased a lit pixel, kept even after VF is overwritten
+    pub fn last_draw_collision(&self) -> bool {
+        self.state.last_draw_collision
     }
 
-    pub fn load_state(&mut self) {
-        self.state = self.saved_state.clone();
+    //one `NAME: value` line per register for the debugger inspector. radix is 2, 10 or 16;
+    //binary and hex values are zero-padded to the register's width.
+    pub fn dump_registers(&self, radix: u8) -> String {
+        let mut lines: Vec<String> = self
+            .state
+            .V
+            .iter()
+            .enumerate()
+            .map(|(i, v)| format!("V{:X}: {}", i, Chip8::format_radix(*v as u16, 8, radix)))
+            .collect();
+        lines.push(format!(
+            "I: {}",
+            Chip8::format_radix(self.state.I, 16, radix)
+        ));
+        lines.push(format!(
+            "PC: {}",
+            Chip8::format_radix(self.state.pc, 16, radix)
+        ));
+        lines.push(format!(
+            "SP: {}",
+            Chip8::format_radix(self.state.sp as u16, 8, radix)
+        ));
+        lines.push(format!(
+            "DT: {}",
+            Chip8::format_radix(self.state.delay_timer as u16, 8, radix)
+        ));
+        lines.push(format!(
+            "ST: {}",
+            Chip8::format_radix(self.state.sound_timer as u16, 8, radix)
+        ));
+        lines.join("\n")
     }
 
-    pub fn disasm_map_serialised(&self) -> JsValue {
-        return JsValue::from_serde(&self.disasm_map).unwrap();
+    //return addresses currently on the stack, oldest first
+    pub fn dump_stack(&self) -> String {
+        self.state.stack[..self.state.sp as usize]
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| format!("{:X}: 0x{:04X}", i, addr))
+            .collect::<Vec<String>>()
+            .join("\n")
     }
 
-    pub fn set_key(&mut self, key: u8, value: u8) {
-        match key {
-            0..=15 => self.state.keys[key as usize] = value,
-            _ => panic!("Writing key out of range"),
+    pub fn video_height(&self) -> u32 {
+        match self.state.hires {
+            true => 64,
+            false => 32,
         }
     }
 
-    fn read(&mut self, addr: u16) -> u8 {
-        match addr {
-            0x000..=0xFFF => return self.state.ram[addr as usize],
-            _ => panic!("Reading memory out of range"),
+    pub fn video_width(&self) -> u32 {
+        match self.state.hires {
+            true => 128,
+            false => 64,
         }
     }
 
-    fn write(&mut self, addr: u16, data: u8) {
-        match addr {
-            0x000..=0xFFF => self.state.ram[addr as usize] = data,
-            _ => panic!("Writing memory out of range"),
-        }
+    pub fn mode(&self) -> Mode {
+        self.mode
     }
 
-    pub fn reset(&mut self) {
-        self.state.pc = 0x200;
-        self.state.opcode = 0;
-        self.state.I = 0;
-        self.state.sp = 0;
-        self.state.delay_timer = 0;
-        self.state.sound_timer = 0;
-
-        self.state.ram.iter_mut().for_each(|x| *x = 0);
-        self.state.stack.iter_mut().for_each(|x| *x = 0);
-        self.state.V.iter_mut().for_each(|x| *x = 0);
-        self.state.framebuffer.iter_mut().for_each(|x| *x = 0);
-        self.state.keys.iter_mut().for_each(|x| *x = 0);
-
-        for i in 0..80 {
-            self.write(i, self.fontset[i as usize]);
-        }
+    //selects the quirk set for the target interpreter, hires stays under the program's control
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.quirks = match mode {
+            Mode::Classic => Quirks::classic(),
+            Mode::SuperChip => Quirks::superchip(),
+            Mode::XoChip => Quirks::xo_chip(),
+        };
     }
 
-    fn load_rom_from_file(&mut self, file_path: &str) {
-        self.reset();
-
-        let mut f = File::open(&file_path).expect("Failed to open file");
-        //let metadata = f.metadata().expect("Failed to read file metadata");
-        f.read(&mut self.state.ram[0x200..])
-            .expect("Failed to read file into RAM buffer");
+    pub fn profile_names() -> Vec<String> {
+        PROFILES.iter().map(|name| String::from(*name)).collect()
     }
 
-    pub fn load_rom_from_assembler(&mut self, assembler: &Assembler) {
-        self.reset();
+    //configures quirks, mode and memory size for a target machine, see PROFILES. Resizing memory
+    //clears it, so select the profile before loading a ROM
+    pub fn set_profile(&mut self, profile: &str) {
+        let (mode, quirks, ram_size) = match profile {
+            "cosmac-vip" => (Mode::Classic, Quirks::cosmac_vip(), 4096),
+            "chip48" => (Mode::Classic, Quirks::chip48(), 4096),
+            "superchip" => (Mode::SuperChip, Quirks::superchip(), 4096),
+            "xo-chip" => (Mode::XoChip, Quirks::xo_chip(), 65536),
+            _ => panic!("Unknown profile {}", profile),
+        };
 
-        self.state.ram[0x200..(0x200 + assembler.binary().len())]
-            .clone_from_slice(&assembler.binary());
+        self.mode = mode;
+        self.quirks = quirks;
+        if self.state.ram.len() != ram_size {
+            self.state.ram = vec![0; ram_size];
+            self.reset();
+        }
     }
 
-    pub fn load_rom_from_bytes(&mut self, buffer: &[u8]) {
-        self.reset();
-
-        self.state.ram[0x200..(0x200 + buffer.len())].clone_from_slice(&buffer);
+    //overrides the profile's shift quirk, e.g. for a ROM written for the other behaviour
+    pub fn set_shift_vy(&mut self, shift_vy: bool) {
+        self.quirks.shift_vy = shift_vy;
     }
 
-    pub fn disassemble(&mut self) {
-        let mut done = false;
-        let mut i = 0x200;
-
-        self.disasm_opcode = 0;
-        self.disasm_map.clear();
-
-        while !done {
-            self.disasm_opcode = ((self.read(i) as u16) << 8) | (self.read(i + 1) as u16);
-            let disasm: String =
-                (self.opcodes[((self.disasm_opcode & 0xF000u16) >> 12) as usize].get_disasm)(self);
-
-            self.disasm_map.insert(i, disasm);
-            i += 2;
+    //loads the ROM, switching to SuperChip mode with the hires display if it uses any
+    //SCHIP-only instruction (00FE, 00FF, Dxy0, Fx30, Fx75) and to Classic otherwise
+    pub fn load_rom_auto(&mut self, buffer: &[u8]) {
+        self.load_rom_from_bytes(buffer);
+
+        let superchip = buffer.chunks_exact(2).any(|word| {
+            let opcode = u16::from_be_bytes([word[0], word[1]]);
+            opcode == 0x00FE
+                || opcode == 0x00FF
+                || opcode & 0xF00F == 0xD000
+                || opcode & 0xF0FF == 0xF030
+                || opcode & 0xF0FF == 0xF075
+        });
 
-            if i >= 4096 {
-                done = true;
+        match superchip {
+            true => {
+                self.set_mode(Mode::SuperChip);
+                self.state.hires = true;
             }
+            false => self.set_mode(Mode::Classic),
         }
     }
 
-    pub fn clock(&mut self) {
-        self.state.opcode =
-            ((self.read(self.state.pc) as u16) << 8) | (self.read(self.state.pc + 1) as u16);
-
-        self.state.pc += 2;
+    pub fn set_colors(&mut self, fg_color: u32, bg_color: u32) {
+        self.fg_color = fg_color;
+        self.bg_color = bg_color;
+    }
 
-        (self.opcodes[((self.state.opcode & 0xF000u16) >> 12) as usize].operation)(self);
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        let len = (self.video_width() * self.video_height()) as usize;
+        let mut rgba = Vec::with_capacity(len * 4);
 
-        if self.state.delay_timer > 0 {
-            self.state.delay_timer -= 1;
+        for pixel in self.state.framebuffer[..len].iter() {
+            let color = match *pixel {
+                0 => self.bg_color,
+                _ => self.fg_color,
+            };
+            rgba.extend_from_slice(&color.to_be_bytes());
         }
 
-        if self.state.sound_timer > 0 {
-            self.state.sound_timer -= 1;
+        rgba
+    }
+
+    //lights or clears one pixel the way Dxyn would, so tests can pre-draw a scene. Coordinates
+    //wrap around the current resolution; under XO-CHIP only the selected planes are touched
+    pub fn set_pixel(&mut self, x: u32, y: u32, on: bool) {
+        let index = (y % self.video_height()) * self.video_width() + (x % self.video_width());
+        let planes = match self.mode {
+            Mode::XoChip => self.state.plane_mask as u32,
+            _ => 0xFFFFFFFF,
+        };
+        let pixel = &mut self.state.framebuffer[index as usize];
+        match on {
+            true => *pixel |= planes,
+            false => *pixel &= !planes,
         }
     }
 
-    fn opcodes_0_lookup(&mut self) {
-        (self.opcodes_0[(self.state.opcode & 0x000Fu16) as usize].operation)(self);
+    //blanks every plane, unlike 00E0 which respects the XO-CHIP plane mask
+    pub fn clear_framebuffer(&mut self) {
+        self.state.framebuffer.iter_mut().for_each(|x| *x = 0);
     }
 
-    fn opcodes_0_name_lookup(&mut self) -> String {
-        return (self.opcodes_0[(self.disasm_opcode & 0x000Fu16) as usize].get_disasm)(self);
+    //00E0 from the host, to clear the screen between scenes without a reset. The resolution is
+    //kept and, like the instruction, XO-CHIP only clears the selected planes
+    pub fn clear_video(&mut self) {
+        self.OP_00E0();
     }
 
-    fn opcodes_8_lookup(&mut self) {
-        (self.opcodes_8[(self.state.opcode & 0x000Fu16) as usize].operation)(self);
+    pub fn error(&self) -> Option<String> {
+        self.state.error.clone()
     }
 
-    fn opcodes_8_name_lookup(&mut self) -> String {
+    pub fn clear_error(&mut self) {
+        self.state.error = None;
+    }
+
+    pub fn set_realign_odd_pc(&mut self, realign: bool) {
+        self.realign_odd_pc = realign;
+    }
+
+    pub fn set_reset_seed(&mut self, seed: u64) {
+        self.reset_seed = Some(seed);
+    }
+
+    pub fn clear_reset_seed(&mut self) {
+        self.reset_seed = None;
+    }
+
+    pub fn save_state(&mut self) {
+        self.saved_state = self.state.clone();
+    }
+
+    //the undo log describes the timeline being thrown away, so it's cleared too
+    pub fn load_state(&mut self) {
+        self.state = self.saved_state.clone();
+        self.undo_log.clear();
+    }
+
+    pub fn disasm_map_serialised(&self) -> JsValue {
+        return JsValue::from_serde(&self.disasm_map).unwrap();
+    }
+
+    //[{ addr, text, self_modified }] in address order, unlike the object
+    //disasm_map_serialised() produces
+    pub fn disasm_list(&self) -> JsValue {
+        JsValue::from_serde(&self.disasm_lines()).unwrap()
+    }
+
+    //[{ start, end, successors }] for the code reachable from the start address
+    pub fn control_flow_graph(&self) -> JsValue {
+        JsValue::from_serde(&self.basic_blocks()).unwrap()
+    }
+
+    //{ shifts, load_store, jump_with_offset, draws } counted over the ROM's reachable code
+    pub fn analyze_rom(bytes: &[u8]) -> JsValue {
+        JsValue::from_serde(&Chip8::quirk_usage(bytes)).unwrap()
+    }
+
+    //a byte of the instruction at addr was stored to while writes were tracked
+    pub fn self_modified(&self, addr: u16) -> bool {
+        self.written.contains(&addr) || self.written.contains(&addr.wrapping_add(1))
+    }
+
+    pub fn stringify_labelled_disasm(&self) -> String {
+        self.labelled_disasm().join("\n")
+    }
+
+    pub fn memory_regions_serialised(&self) -> JsValue {
+        JsValue::from_serde(&self.memory_regions()).unwrap()
+    }
+
+    pub fn set_key(&mut self, key: u8, value: u8) {
+        match key {
+            0..=15 => self.state.keys[key as usize] = value,
+            _ => panic!("Writing key out of range"),
+        }
+    }
+
+    //state injection so compiled code can be entered directly, e.g. at a function body with
+    //its arguments already in place. Undoing past an injected value would overwrite it with
+    //state from before, so these clear the undo log.
+    pub fn set_register(&mut self, reg: u8, value: u8) {
+        match reg {
+            0..=15 => self.state.V[reg as usize] = value,
+            _ => panic!("Writing register out of range"),
+        }
+        self.undo_log.clear();
+    }
+
+    pub fn set_i(&mut self, value: u16) {
+        self.state.I = value;
+        self.undo_log.clear();
+    }
+
+    pub fn set_pc(&mut self, addr: u16) {
+        self.state.pc = addr;
+        self.undo_log.clear();
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.state.delay_timer = value;
+        self.undo_log.clear();
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.state.sound_timer = value;
+        self.undo_log.clear();
+    }
+
+    //takes effect at the next reset() or ROM load. Below 0x200 is the interpreter's own
+    //memory, which holds the fonts.
+    pub fn set_start_address(&mut self, addr: u16) {
+        if !addr.is_multiple_of(2) || addr < 0x200 || addr as usize + 1 >= self.state.ram.len() {
+            panic!(
+                "Start address {:#X} must be even and within RAM from 0x200",
+                addr
+            );
+        }
+        self.start_address = addr;
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            _ if (addr as usize) < self.state.ram.len() => return self.state.ram[addr as usize],
+            _ => panic!("Reading memory out of range"),
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            _ if (addr as usize) < self.state.ram.len() => self.state.ram[addr as usize] = data,
+            _ => panic!("Writing memory out of range"),
+        }
+        if self.track_writes {
+            self.written.insert(addr);
+        }
+    }
+
+    //warm reset: clears RAM (reinstalling the font), registers, stack, timers, display, plane
+    //selection, keys, any error and the trace, points pc at the start address and reinstalls the
+    //reset seed's RNG. Configuration (mode, quirks, breakpoints, colours, the seed itself) and
+    //the RPL flags are kept.
+    pub fn reset(&mut self) {
+        self.state.pc = self.start_address;
+        self.state.opcode = 0;
+        self.state.I = 0;
+        self.state.sp = 0;
+        self.state.delay_timer = 0;
+        self.state.sound_timer = 0;
+        self.state.error = None;
+        self.state.rng = self.reset_seed.map(SeededRng::new);
+        self.state.cycles = 0;
+        self.state.frame_count = 0;
+        self.state.waiting_for_key = false;
+        self.state.last_draw_collision = false;
+        self.state.sound_on = false;
+        self.state.sound_started = false;
+        self.state.sound_stopped = false;
+        self.state.hires = false;
+        self.state.plane_mask = 1;
+        self.rom_len = 0;
+        self.resume_breakpoint = None;
+        self.trace.clear();
+        self.undo_log.clear();
+        self.budget_used = 0;
+
+        self.state.ram.iter_mut().for_each(|x| *x = 0);
+        self.state.stack.iter_mut().for_each(|x| *x = 0);
+        self.state.V.iter_mut().for_each(|x| *x = 0);
+        self.state.framebuffer.iter_mut().for_each(|x| *x = 0);
+        self.state.keys.iter_mut().for_each(|x| *x = 0);
+
+        for i in 0..80 {
+            self.write(i, self.fontset[i as usize]);
+        }
+        self.written.clear();
+    }
+
+    //cold reset: a warm reset() that also clears the RPL flags, as if the machine had been
+    //switched off
+    pub fn power_cycle(&mut self) {
+        self.rpl_flags = [0; 16];
+        self.reset();
+    }
+
+    fn load_rom_from_file(&mut self, file_path: &str) {
+        self.reset();
+
+        let mut f = File::open(&file_path).expect("Failed to open file");
+        //let metadata = f.metadata().expect("Failed to read file metadata");
+        f.read(&mut self.state.ram[self.start_address as usize..])
+            .expect("Failed to read file into RAM buffer");
+    }
+
+    //assembled programs address themselves from 0x200, so away from there every JP, CALL and
+    //LD I into the program is moved by the same offset as the code
+    pub fn load_rom_from_assembler(&mut self, assembler: &Assembler) {
+        self.reset();
+
+        let start = self.start_address as usize;
+        self.state.ram[start..(start + assembler.binary().len())]
+            .clone_from_slice(&assembler.binary());
+        self.rom_len = assembler.binary().len();
+
+        let offset = self.start_address - 0x200;
+        if offset == 0 {
+            return;
+        }
+        for relocation in assembler.relocation_table() {
+            let at = start + (relocation.addr - 0x200) as usize;
+            let word = u16::from_be_bytes([self.state.ram[at], self.state.ram[at + 1]]);
+            let target = (word & 0x0FFF) + offset;
+            if target > 0x0FFF {
+                panic!(
+                    "Relocated target {:#X} at {:#X} is out of the 12 bit address range",
+                    target, at
+                );
+            }
+            let split = ((word & 0xF000) | target).to_be_bytes();
+            self.state.ram[at] = split[0];
+            self.state.ram[at + 1] = split[1];
+        }
+    }
+
+    pub fn load_rom_from_bytes(&mut self, buffer: &[u8]) {
+        self.reset();
+
+        let start = self.start_address as usize;
+        self.state.ram[start..(start + buffer.len())].clone_from_slice(&buffer);
+        self.rom_len = buffer.len();
+    }
+
+    //the loaded ROM as it currently sits in RAM, for saving or checksumming
+    pub fn rom_bytes(&self) -> Vec<u8> {
+        let start = self.start_address as usize;
+        self.state.ram[start..(start + self.rom_len)].to_vec()
+    }
+
+    //overwrites part of the 0x000-0x04F fontset after a ROM has been loaded (loading resets RAM
+    //and reinstalls the standard font). Fx29 points I at 5 * Vx, so each 5 byte glyph replaces
+    //the hex digit at offset / 5. There is no SCHIP Fx30 large font, so only this region is used.
+    pub fn write_font_region(&mut self, offset: u16, glyphs: &[u8]) {
+        if offset as usize + glyphs.len() > self.fontset.len() {
+            panic!("Writing font region out of range");
+        }
+
+        for (i, byte) in glyphs.iter().enumerate() {
+            self.write(offset + i as u16, *byte);
+        }
+    }
+
+    //RAM address of a hex digit's 5 byte glyph, the value Fx29 loads into I. There is no SCHIP
+    //Fx30 large font yet, so there is no hi-res equivalent.
+    pub fn font_address(&self, digit: u8) -> u16 {
+        digit as u16 * 5
+    }
+
+    pub fn disassemble(&mut self) {
+        let mut i = self.start_address;
+
+        self.disasm_opcode = 0;
+        self.disasm_map.clear();
+
+        //the whole of RAM, which is 64K for XO-CHIP
+        while (i as usize) < self.state.ram.len() - 1 {
+            self.disasm_opcode = ((self.read(i) as u16) << 8) | (self.read(i + 1) as u16);
+            //words no table decodes, usually sprite data, read as data in the compiler's syntax
+            //rather than indexing past the end of a sub-table
+            let disasm: String = match self.unhandled_opcodes(&[self.disasm_opcode]).is_empty() {
+                true => (self.opcodes[((self.disasm_opcode & 0xF000u16) >> 12) as usize]
+                    .get_disasm)(self),
+                false => format!("DW {:04X}", self.disasm_opcode),
+            };
+
+            self.disasm_map.insert(i, disasm);
+            i = match i.checked_add(2) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+    }
+
+    //executes one instruction. The timers are left alone; they only move in tick_timers().
+    pub fn clock(&mut self) {
+        if self.state.error.is_some() {
+            return;
+        }
+
+        //instructions are 2 bytes wide, so an odd pc (e.g. from a computed Bnnn) would decode
+        //the second half of one instruction and the first half of the next
+        if self.state.pc & 1 != 0 {
+            if self.realign_odd_pc {
+                self.state.pc -= 1;
+            } else {
+                self.state.error = Some(format!("Misaligned program counter {:X}", self.state.pc));
+                return;
+            }
+        }
+
+        //one bounds check covers both bytes, rather than one per read()
+        let pc = self.state.pc;
+        self.state.opcode = match self.state.ram.get(pc as usize..pc as usize + 2) {
+            Some(&[high, low]) => u16::from_be_bytes([high, low]),
+            _ => panic!("Reading memory out of range"),
+        };
+
+        if self.undo_enabled {
+            self.log_undo();
+        }
+        self.state.pc += 2;
+        self.state.cycles += Chip8::opcode_cost(self.state.opcode);
+
+        //8xy_, Ex__ and Fx__ go straight to their sub-table instead of through the
+        //opcodes_x_lookup trampolines, which are kept for the table entries themselves
+        let opcode = self.state.opcode as usize;
+        let instruction = match opcode >> 12 {
+            0x8 => self.opcodes_8.get(opcode & 0x000F),
+            0xE => self.opcodes_E.get(opcode & 0x000F),
+            0xF => self.opcodes_F.get(opcode & 0x00FF),
+            high => self.opcodes.get(high),
+        };
+        //the sub-tables are shorter than their index range, e.g. FFFF is past opcodes_F
+        let operation = match instruction {
+            Some(instruction) if !instruction.is_null() => instruction.operation,
+            _ => {
+                self.state.error = Some(format!("Unknown opcode {:04X}", opcode));
+                return;
+            }
+        };
+        operation(self);
+
+        if self.tracing {
+            self.trace.push(TraceEntry {
+                pc,
+                opcode: self.state.opcode,
+                V: self.state.V,
+                I: self.state.I,
+            });
+        }
+    }
+
+    //advances one 60Hz frame. The timers count frames rather than instructions, so the host
+    //calls this once per vsync however many clock()s it ran in between.
+    pub fn tick_timers(&mut self) {
+        self.state.frame_count += 1;
+
+        if self.state.delay_timer > 0 {
+            self.state.delay_timer -= 1;
+        }
+
+        //the tone sounds for the frame the timer counts down through, so ST = 1 beeps for one
+        //frame and the edges follow the value from before the decrement
+        let sound_on = self.state.sound_timer > 0;
+        if self.state.sound_timer > 0 {
+            self.state.sound_timer -= 1;
+        }
+
+        self.state.sound_started = sound_on && !self.state.sound_on;
+        self.state.sound_stopped = !sound_on && self.state.sound_on;
+        self.state.sound_on = sound_on;
+    }
+
+    //the sound timer went from stopped at the previous tick_timers() to running at the latest,
+    //so the host starts its tone exactly once
+    pub fn sound_started(&self) -> bool {
+        self.state.sound_started
+    }
+
+    //the sound timer was running at the previous tick_timers() and has run out at the latest
+    pub fn sound_stopped(&self) -> bool {
+        self.state.sound_stopped
+    }
+
+    //single-steps one instruction for a debugger. Like clock() this never ticks the timers, so
+    //stepping through code doesn't run the 60Hz timers down at the stepping rate.
+    pub fn step(&mut self) {
+        self.clock();
+        self.resume_breakpoint = None;
+    }
+
+    //one instruction followed by one 60Hz frame, for tools that want the timers to move with
+    //each step
+    pub fn step_with_timers(&mut self) {
+        self.clock();
+        self.tick_timers();
+    }
+
+    //executes up to `instructions` clocks, stopping early if the program faults, blocks on Fx0A
+    //or uses up the cycle budget
+    pub fn run_for(&mut self, instructions: u32) -> RunStatus {
+        for _ in 0..instructions {
+            if self.budget_exhausted() {
+                return RunStatus::TimedOut;
+            }
+            self.clock();
+            self.budget_used += 1;
+
+            if self.state.error.is_some() {
+                return RunStatus::Faulted;
+            }
+
+            if self.state.waiting_for_key {
+                return RunStatus::BlockedOnKey;
+            }
+        }
+
+        RunStatus::Running
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    //None removes the budget. Long-running programs such as games never halt, so this is for
+    //places like the playground where a program that doesn't finish is probably a bug.
+    pub fn set_cycle_budget(&mut self, budget: Option<u32>) {
+        self.cycle_budget = budget;
+        self.budget_used = 0;
+    }
+
+    fn budget_exhausted(&self) -> bool {
+        self.cycle_budget
+            .is_some_and(|budget| self.budget_used >= budget as u64)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn set_track_writes(&mut self, track: bool) {
+        self.track_writes = track;
+    }
+
+    //a lighter alternative to save_state() for stepping back a few instructions. Only a small
+    //delta is logged per instruction, so anything touching RAM, the display, the RNG or more
+    //than Vx and VF can't be undone and clears the log.
+    pub fn set_undo_log(&mut self, enabled: bool) {
+        self.undo_enabled = enabled;
+        self.undo_log.clear();
+    }
+
+    pub fn undo_len(&self) -> usize {
+        self.undo_log.len()
+    }
+
+    //reverts the last logged instruction, returning false if there is nothing to undo
+    pub fn undo(&mut self) -> bool {
+        let entry = match self.undo_log.pop_back() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.state.pc = entry.pc;
+        self.state.I = entry.I;
+        self.state.sp = entry.sp;
+        if let Some(slot) = self.state.stack.get_mut(entry.sp as usize) {
+            *slot = entry.stack_slot;
+        }
+        self.state.V[0xF] = entry.vf;
+        self.state.V[entry.x as usize] = entry.vx;
+        self.state.delay_timer = entry.delay_timer;
+        self.state.sound_timer = entry.sound_timer;
+        self.state.cycles = entry.cycles;
+        true
+    }
+
+    pub fn set_tracing(&mut self, tracing: bool) {
+        self.tracing = tracing;
+    }
+
+    pub fn trace_len(&self) -> usize {
+        self.trace.len()
+    }
+
+    //{ cycle, pc, other_pc } where the traces first differ, or null if they match
+    pub fn trace_diff(&self, other: &Chip8) -> JsValue {
+        JsValue::from_serde(&self.trace_divergence(other)).unwrap()
+    }
+
+    //{ cycles_run, stop_reason } from run_cycles()
+    pub fn run(&mut self, max_cycles: u32) -> JsValue {
+        JsValue::from_serde(&self.run_cycles(max_cycles)).unwrap()
+    }
+
+    pub fn run_until_draw(&mut self, max_cycles: u32) -> JsValue {
+        JsValue::from_serde(&self.run_cycles_until_draw(max_cycles)).unwrap()
+    }
+
+    //approximate COSMAC VIP machine cycles per instruction, ignoring data-dependent
+    //variations such as taken skips or sprite height
+    fn opcode_cost(opcode: u16) -> u64 {
+        match opcode & 0xF000 {
+            0x0000 if opcode == 0x00E0 => 24,
+            0x1000 => 12,
+            0x2000 => 26,
+            0x3000 | 0x4000 => 10,
+            0x5000 | 0x9000 => 14,
+            0x6000 => 6,
+            0x7000 => 10,
+            0x8000 => 44,
+            0xA000 => 12,
+            0xB000 => 22,
+            0xC000 => 36,
+            0xD000 => 22734,
+            0xE000 => 14,
+            _ => match opcode & 0x00FF {
+                0x1E => 16,
+                0x29 => 20,
+                0x33 => 84,
+                0x55 | 0x65 => 64,
+                _ => 10,
+            },
+        }
+    }
+
+    fn opcodes_0_lookup(&mut self) {
+        //the table is keyed on the low nibble, which would send 0000 or 0120 to CLS. Anything
+        //but the known 00xx instructions is a SYS call into machine code, which is ignored.
+        match self.state.opcode {
+            0x00E0 | 0x00EE => (),
+            0x00FE => return self.OP_00FE(),
+            0x00FF => return self.OP_00FF(),
+            _ => return self.OP_0nnn(),
+        }
+        (self.opcodes_0[(self.state.opcode & 0x000Fu16) as usize].operation)(self);
+    }
+
+    fn opcodes_0_name_lookup(&mut self) -> String {
+        match self.disasm_opcode {
+            0x00E0 | 0x00EE => (),
+            0x0000 => return String::from("NOP"),
+            0x00FE => return String::from("LOW"),
+            0x00FF => return String::from("HIGH"),
+            _ => return format!("SYS {}", Chip8::get_args_disasm_nnn(self)),
+        }
+        return (self.opcodes_0[(self.disasm_opcode & 0x000Fu16) as usize].get_disasm)(self);
+    }
+
+    fn opcodes_8_lookup(&mut self) {
+        (self.opcodes_8[(self.state.opcode & 0x000Fu16) as usize].operation)(self);
+    }
+
+    fn opcodes_8_name_lookup(&mut self) -> String {
         return (self.opcodes_8[(self.disasm_opcode & 0x000Fu16) as usize].get_disasm)(self);
     }
 
@@ -516,6 +1536,17 @@ impl Chip8 {
         return format!("V{:X}", x);
     }
 
+    //fills a slot in one of the sub-opcode tables. Registering the same slot twice is a typo in
+    //new(), so debug builds catch it rather than silently dropping the first handler.
+    fn register_opcode(table: &mut [Instruction], index: usize, instruction: Instruction) {
+        debug_assert!(
+            table[index].is_null(),
+            "opcode table slot {:X} registered twice",
+            index
+        );
+        table[index] = instruction;
+    }
+
     fn OP_null(&mut self) {
         panic!("Null operator executed!");
     }
@@ -523,7 +1554,23 @@ impl Chip8 {
     fn OP_0nnn(&mut self) {}
 
     fn OP_00E0(&mut self) {
-        self.state.framebuffer.iter_mut().for_each(|x| *x = 0)
+        match self.mode {
+            Mode::XoChip => {
+                let planes = !(self.state.plane_mask as u32);
+                self.state.framebuffer.iter_mut().for_each(|x| *x &= planes)
+            }
+            _ => self.state.framebuffer.iter_mut().for_each(|x| *x = 0),
+        }
+    }
+
+    fn OP_00FE(&mut self) {
+        self.state.hires = false;
+        self.OP_00E0();
+    }
+
+    fn OP_00FF(&mut self) {
+        self.state.hires = true;
+        self.OP_00E0();
     }
 
     fn OP_00EE(&mut self) {
@@ -605,49 +1652,83 @@ impl Chip8 {
 
     fn OP_Bnnn(&mut self) {
         let nnn = self.state.opcode & 0x0FFFu16;
+        let x = match self.quirks.jump_with_vx {
+            true => (self.state.opcode & 0x0F00u16) >> 8u32,
+            false => 0,
+        };
 
-        self.state.pc = ((self.state.V[0 as usize] as u16) + (nnn)) as u16;
+        self.state.pc = ((self.state.V[x as usize] as u16) + (nnn)) as u16;
     }
 
     fn OP_Cxkk(&mut self) {
         let x = (self.state.opcode & 0x0F00u16) >> 8u32;
         let kk = self.state.opcode & 0x00FFu16;
 
-        let mut buf = [0u8; 1];
-        getrandom::getrandom(&mut buf).expect("random number generation failed");
+        let random = match self.state.rng.as_mut() {
+            Some(rng) => rng.next_u8(),
+            None => {
+                let mut buf = [0u8; 1];
+                getrandom::getrandom(&mut buf).expect("random number generation failed");
+                buf[0]
+            }
+        };
 
-        self.state.V[x as usize] = (buf[0] as u16 & kk) as u8;
+        self.state.V[x as usize] = (random as u16 & kk) as u8;
     }
 
     fn OP_Dxyn(&mut self) {
         let x = (self.state.opcode & 0x0F00u16) >> 8u32;
         let y = (self.state.opcode & 0x00F0u16) >> 4u32;
-        let height = self.state.opcode & 0x000Fu16;
+        //SCHIP and XO-CHIP draw a 16x16 sprite, two bytes per row, for Dxy0. Classic CHIP-8
+        //draws nothing.
+        let (height, width) = match (self.state.opcode & 0x000Fu16, self.mode) {
+            (0, Mode::SuperChip | Mode::XoChip) => (16, 16),
+            (height, _) => (height, 8),
+        };
+        let row_bytes = width as u16 / 8;
 
         let x_pos = self.state.V[x as usize] as u32;
         let y_pos = self.state.V[y as usize] as u32;
 
         self.state.V[0xF] = 0;
 
-        for row in 0..height {
-            let sprite_byte = self.read(self.state.I + row);
+        //XO-CHIP reads one sprite per selected plane, back to back from I
+        let planes: Vec<u32> = match self.mode {
+            Mode::XoChip => (0..4)
+                .filter(|plane| self.state.plane_mask & (1 << plane) != 0)
+                .map(|plane| 1 << plane)
+                .collect(),
+            _ => vec![0xFFFFFFFF],
+        };
 
-            for col in 0..8 {
-                let sprite_pixel = sprite_byte & (0x80 >> col);
-                //utils::log!("y pos: {}, row: {}, width: {}, x_pos: {}, col: {}", y_pos, row, self.video_width, x_pos, col);
-                let index = ((y_pos + row as u32) % self.video_height) * self.video_width
-                    + ((x_pos + col) % self.video_width);
-                let screen_pixel = &mut self.state.framebuffer[index as usize];
+        for (sprite, plane_bits) in planes.iter().enumerate() {
+            let sprite_addr = self.state.I + sprite as u16 * height * row_bytes;
 
-                if sprite_pixel > 0 {
-                    if *screen_pixel == 0xFFFFFFFF {
-                        self.state.V[0xF] = 1;
-                    }
+            for row in 0..height {
+                let mut sprite_row = 0u32;
+                for byte in 0..row_bytes {
+                    sprite_row =
+                        (sprite_row << 8) | self.read(sprite_addr + row * row_bytes + byte) as u32;
+                }
 
-                    *screen_pixel ^= 0xFFFFFFFF;
+                for col in 0..width {
+                    let sprite_pixel = sprite_row & (1 << (width - 1 - col));
+                    let index = ((y_pos + row as u32) % self.video_height()) * self.video_width()
+                        + ((x_pos + col) % self.video_width());
+                    let screen_pixel = &mut self.state.framebuffer[index as usize];
+
+                    if sprite_pixel > 0 {
+                        if *screen_pixel & plane_bits != 0 {
+                            self.state.V[0xF] = 1;
+                        }
+
+                        *screen_pixel ^= plane_bits;
+                    }
                 }
             }
         }
+
+        self.state.last_draw_collision = self.state.V[0xF] == 1;
     }
 
     fn OP_8xy0(&mut self) {
@@ -696,42 +1777,44 @@ impl Chip8 {
         let x = (self.state.opcode & 0x0F00u16) >> 8u32;
         let y = (self.state.opcode & 0x00F0u16) >> 4u32;
 
-        if self.state.V[x as usize] > self.state.V[y as usize] {
-            self.state.V[0xF] = 1;
-        } else {
-            self.state.V[0xF] = 0;
-        }
-
-        self.state.V[x as usize] -= self.state.V[y as usize];
+        //VF is NOT borrow, and is written last so it wins when x is F
+        let not_borrow = (self.state.V[x as usize] >= self.state.V[y as usize]) as u8;
+        self.state.V[x as usize] = self.state.V[x as usize].wrapping_sub(self.state.V[y as usize]);
+        self.state.V[0xF] = not_borrow;
     }
 
     fn OP_8xy6(&mut self) {
         let x = (self.state.opcode & 0x0F00u16) >> 8u32;
+        let src = match self.quirks.shift_vy {
+            true => (self.state.opcode & 0x00F0u16) >> 4u32,
+            false => x,
+        };
 
-        self.state.V[0xF] = self.state.V[x as usize] & 0x1;
-
-        self.state.V[x as usize] >>= 1;
+        //the shifted out bit is written last so it wins when x is F
+        let shifted_out = self.state.V[src as usize] & 0x1;
+        self.state.V[x as usize] = self.state.V[src as usize] >> 1;
+        self.state.V[0xF] = shifted_out;
     }
 
     fn OP_8xy7(&mut self) {
         let x = (self.state.opcode & 0x0F00u16) >> 8u32;
         let y = (self.state.opcode & 0x00F0u16) >> 4u32;
 
-        if self.state.V[y as usize] > self.state.V[x as usize] {
-            self.state.V[0xF] = 1;
-        } else {
-            self.state.V[0xF] = 0;
-        }
-
-        self.state.V[x as usize] = self.state.V[y as usize] - self.state.V[x as usize];
+        let not_borrow = (self.state.V[y as usize] >= self.state.V[x as usize]) as u8;
+        self.state.V[x as usize] = self.state.V[y as usize].wrapping_sub(self.state.V[x as usize]);
+        self.state.V[0xF] = not_borrow;
     }
 
     fn OP_8xyE(&mut self) {
         let x = (self.state.opcode & 0x0F00u16) >> 8u32;
+        let src = match self.quirks.shift_vy {
+            true => (self.state.opcode & 0x00F0u16) >> 4u32,
+            false => x,
+        };
 
-        self.state.V[0xF] = (self.state.V[x as usize] & 0x80) >> 7u32;
-
-        self.state.V[x as usize] <<= 1;
+        let shifted_out = (self.state.V[src as usize] & 0x80) >> 7u32;
+        self.state.V[x as usize] = self.state.V[src as usize] << 1;
+        self.state.V[0xF] = shifted_out;
     }
 
     fn OP_Ex9E(&mut self) {
@@ -758,21 +1841,30 @@ impl Chip8 {
         self.state.V[x as usize] = self.state.delay_timer;
     }
 
+    //XO-CHIP: selects the bit planes (a mask of up to 4) that later draws and clears target
+    fn OP_Fn01(&mut self) {
+        if self.mode != Mode::XoChip {
+            self.state.error = Some(format!(
+                "Plane select {:04X} requires XO-CHIP mode",
+                self.state.opcode
+            ));
+            return;
+        }
+        self.state.plane_mask = ((self.state.opcode & 0x0F00) >> 8) as u8;
+    }
+
     fn OP_Fx0A(&mut self) {
         let x = (self.state.opcode & 0x0F00u16) >> 8u32;
 
-        let mut key_pressed = false;
-        for (idx, element) in self.state.keys.iter().enumerate() {
-            if self.state.keys[idx as usize] > 0 {
+        match self.state.keys.iter().position(|&key| key > 0) {
+            Some(idx) => {
                 self.state.V[x as usize] = idx as u8;
-                key_pressed = true;
-                utils::log!("{}", self.state.keys[idx as usize]);
-                break;
+                self.state.waiting_for_key = false;
+            }
+            None => {
+                self.state.pc -= 2;
+                self.state.waiting_for_key = true;
             }
-        }
-
-        if !key_pressed {
-            self.state.pc -= 2;
         }
     }
 
@@ -797,7 +1889,7 @@ impl Chip8 {
     fn OP_Fx29(&mut self) {
         let x = (self.state.opcode & 0x0F00u16) >> 8u32;
 
-        self.state.I = (self.state.V[x as usize] * 5) as u16;
+        self.state.I = self.font_address(self.state.V[x as usize]);
     }
 
     fn OP_Fx33(&mut self) {
@@ -820,7 +1912,9 @@ impl Chip8 {
             self.write(self.state.I + i, self.state.V[i as usize]);
         }
 
-        self.state.I += x + 1;
+        if self.quirks.load_store_increment {
+            self.state.I += x + 1;
+        }
     }
 
     fn OP_Fx65(&mut self) {
@@ -830,13 +1924,440 @@ impl Chip8 {
             self.state.V[i as usize] = self.read(self.state.I + i);
         }
 
-        self.state.I += x + 1;
+        if self.quirks.load_store_increment {
+            self.state.I += x + 1;
+        }
+    }
+
+    //SCHIP has 8 RPL flags, XO-CHIP 16. Classic CHIP-8 has none.
+    fn rpl_flags_range(&mut self) -> Option<usize> {
+        let x = ((self.state.opcode & 0x0F00u16) >> 8u32) as usize;
+        let flags = match self.mode {
+            Mode::Classic => 0,
+            Mode::SuperChip => 8,
+            Mode::XoChip => 16,
+        };
+        if x >= flags {
+            self.state.error = Some(format!(
+                "RPL flags {:04X} out of range for {:?} mode",
+                self.state.opcode, self.mode
+            ));
+            return None;
+        }
+        Some(x)
+    }
+
+    fn OP_Fx75(&mut self) {
+        if let Some(x) = self.rpl_flags_range() {
+            self.rpl_flags[..=x].copy_from_slice(&self.state.V[..=x]);
+        }
+    }
+
+    fn OP_Fx85(&mut self) {
+        if let Some(x) = self.rpl_flags_range() {
+            self.state.V[..=x].copy_from_slice(&self.rpl_flags[..=x]);
+        }
+    }
+}
+
+impl Chip8 {
+    fn format_radix(value: u16, bits: usize, radix: u8) -> String {
+        match radix {
+            2 => format!("0b{:0width$b}", value, width = bits),
+            10 => format!("{}", value),
+            16 => format!("0x{:0width$X}", value, width = bits / 4),
+            _ => panic!("Unsupported radix {}", radix),
+        }
+    }
+
+    //the given opcodes that would reach OP_null (or fall outside a table) when executed
+    pub fn unhandled_opcodes(&self, opcodes: &[u16]) -> Vec<u16> {
+        opcodes
+            .iter()
+            .copied()
+            .filter(|opcode| !self.is_handled(*opcode))
+            .collect()
+    }
+
+    fn is_handled(&self, opcode: u16) -> bool {
+        let instruction = match opcode >> 12 {
+            0x0 if !matches!(opcode, 0x00E0 | 0x00EE) => return true,
+            0x0 => self.opcodes_0.get((opcode & 0x000F) as usize),
+            0x8 => self.opcodes_8.get((opcode & 0x000F) as usize),
+            0xE => self.opcodes_E.get((opcode & 0x000F) as usize),
+            0xF => self.opcodes_F.get((opcode & 0x00FF) as usize),
+            top => self.opcodes.get(top as usize),
+        };
+        instruction.is_some_and(|instruction| !instruction.is_null())
+    }
+
+    //clocks up to max_cycles instructions. Stops before executing an illegal opcode or one at a
+    //breakpoint (unless the last run stopped there, so resuming makes progress), and after a
+    //jump to self or an Fx0A that found no key.
+    pub fn run_cycles(&mut self, max_cycles: u32) -> RunResult {
+        let mut cycles_run = 0;
+        let stop_reason = loop {
+            if self.state.error.is_some() {
+                break StopReason::Illegal;
+            }
+            if self.budget_exhausted() {
+                break StopReason::Timeout;
+            }
+            if cycles_run == max_cycles {
+                break StopReason::MaxCycles;
+            }
+            let resuming = cycles_run == 0 && self.resume_breakpoint == Some(self.state.pc);
+            if !resuming && self.breakpoints.contains(&self.state.pc) {
+                self.resume_breakpoint = Some(self.state.pc);
+                break StopReason::Breakpoint;
+            }
+            let pc = self.state.pc as usize;
+            let next = match (self.state.ram.get(pc), self.state.ram.get(pc + 1)) {
+                (Some(high), Some(low)) => ((*high as u16) << 8) | (*low as u16),
+                _ => break StopReason::Illegal,
+            };
+            if !self.is_handled(next) {
+                break StopReason::Illegal;
+            }
+
+            self.clock();
+            self.resume_breakpoint = None;
+            cycles_run += 1;
+
+            //a halted program isn't stuck, so spinning on the jump to itself is free
+            if next == 0x1000 | pc as u16 {
+                break StopReason::Halt;
+            }
+            self.budget_used += 1;
+            if self.state.waiting_for_key {
+                break StopReason::KeyWait;
+            }
+        };
+
+        RunResult {
+            cycles_run,
+            stop_reason,
+        }
+    }
+
+    //clocks until a Dxyn or 00E0 has executed, so a debugger can advance one rendered frame.
+    //Otherwise stops for the same reasons as run_cycles().
+    pub fn run_cycles_until_draw(&mut self, max_cycles: u32) -> RunResult {
+        let mut cycles_run = 0;
+        let stop_reason = loop {
+            if cycles_run == max_cycles {
+                break StopReason::MaxCycles;
+            }
+            let result = self.run_cycles(1);
+            cycles_run += result.cycles_run;
+            if result.stop_reason != StopReason::MaxCycles {
+                break result.stop_reason;
+            }
+            if self.state.opcode & 0xF000 == 0xD000 || self.state.opcode == 0x00E0 {
+                break StopReason::Draw;
+            }
+        };
+
+        RunResult {
+            cycles_run,
+            stop_reason,
+        }
+    }
+
+    //true for instructions that change nothing beyond what an UndoEntry records
+    fn reversible(opcode: u16) -> bool {
+        match opcode >> 12 {
+            0x0 => !matches!(opcode, 0x00E0 | 0x00FE | 0x00FF),
+            0xC | 0xD => false,
+            0xF => matches!(opcode & 0x00FF, 0x07 | 0x15 | 0x18 | 0x1E | 0x29),
+            _ => true,
+        }
+    }
+
+    fn log_undo(&mut self) {
+        if !Chip8::reversible(self.state.opcode) {
+            self.undo_log.clear();
+            return;
+        }
+        if self.undo_log.len() == UNDO_LOG_LEN {
+            self.undo_log.pop_front();
+        }
+        let x = ((self.state.opcode & 0x0F00) >> 8) as u8;
+        self.undo_log.push_back(UndoEntry {
+            pc: self.state.pc,
+            I: self.state.I,
+            sp: self.state.sp,
+            stack_slot: self
+                .state
+                .stack
+                .get(self.state.sp as usize)
+                .copied()
+                .unwrap_or(0),
+            x,
+            vx: self.state.V[x as usize],
+            vf: self.state.V[0xF],
+            delay_timer: self.state.delay_timer,
+            sound_timer: self.state.sound_timer,
+            cycles: self.state.cycles,
+        });
+    }
+
+    //compares the recorded traces cycle by cycle, including the registers each instruction
+    //left, so an RND that rolled differently shows up where it ran rather than at the branch
+    pub fn trace_divergence(&self, other: &Chip8) -> Option<TraceDivergence> {
+        let cycle = self
+            .trace
+            .iter()
+            .zip(other.trace.iter())
+            .position(|(ours, theirs)| ours != theirs)
+            .unwrap_or_else(|| self.trace.len().min(other.trace.len()));
+
+        if cycle == self.trace.len() && cycle == other.trace.len() {
+            return None;
+        }
+        Some(TraceDivergence {
+            cycle,
+            pc: self.trace.get(cycle).map(|entry| entry.pc),
+            other_pc: other.trace.get(cycle).map(|entry| entry.pc),
+        })
+    }
+
+    pub fn memory_regions(&self) -> Vec<MemoryRegion> {
+        let program_end = self.start_address + self.rom_len as u16;
+        let mut regions = vec![
+            MemoryRegion::new("fontset", 0x000, 0x04F),
+            MemoryRegion::new("reserved", 0x050, self.start_address - 1),
+        ];
+
+        if self.rom_len > 0 {
+            regions.push(MemoryRegion::new(
+                "program",
+                self.start_address,
+                program_end - 1,
+            ));
+        }
+        if (program_end as usize) < self.state.ram.len() {
+            let ram_end = (self.state.ram.len() - 1) as u16;
+            regions.push(MemoryRegion::new("free", program_end, ram_end));
+        }
+
+        regions
+    }
+
+    //addresses execution can continue at after the instruction at pc. A CALL continues at its
+    //target and, once it returns, after itself. 00EE and Bnnn depend on runtime state, so they
+    //have none. Addresses past the end of RAM, which 64K XO-CHIP RAM can reach, are dropped.
+    fn instruction_successors(&self, pc: u16) -> Vec<u16> {
+        let opcode =
+            ((self.state.ram[pc as usize] as u16) << 8) | self.state.ram[pc as usize + 1] as u16;
+        let nnn = opcode & 0x0FFF;
+        let next = pc.checked_add(2);
+        let skip = pc.checked_add(4);
+        let successors = match opcode >> 12 {
+            0x0 if opcode == 0x00EE => vec![],
+            0x1 => vec![Some(nnn)],
+            0x2 => vec![Some(nnn), next],
+            0x3 | 0x4 => vec![next, skip],
+            0x5 | 0x9 if opcode & 0x000F == 0 => vec![next, skip],
+            0xB => vec![],
+            0xE if matches!(opcode & 0x00FF, 0x9E | 0xA1) => vec![next, skip],
+            _ => vec![next],
+        };
+        successors
+            .into_iter()
+            .flatten()
+            .filter(|addr| (*addr as usize + 1) < self.state.ram.len())
+            .collect()
+    }
+
+    //splits the code reachable from the start address into basic blocks. A block ends at any instruction
+    //that can do more than fall through, or just before the target of one. Loops show up as a
+    //successor at or before the block's own start.
+    pub fn basic_blocks(&self) -> Vec<BasicBlock> {
+        let in_ram = |addr: &u16| (*addr as usize + 1) < self.state.ram.len();
+        let mut successors: HashMap<u16, Vec<u16>> = HashMap::new();
+        let mut leaders: HashSet<u16> = HashSet::from([self.start_address]);
+        let mut pending = vec![self.start_address];
+
+        while let Some(pc) = pending.pop() {
+            if successors.contains_key(&pc) || !in_ram(&pc) {
+                continue;
+            }
+            let next = self.instruction_successors(pc);
+            if next[..] != *pc.checked_add(2).as_slice() {
+                leaders.extend(next.iter().filter(|addr| in_ram(addr)));
+            }
+            pending.extend(next.iter());
+            successors.insert(pc, next);
+        }
+
+        let mut addrs: Vec<u16> = successors.keys().cloned().collect();
+        addrs.sort_unstable();
+
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        for addr in addrs {
+            match blocks.last_mut() {
+                Some(block) if !leaders.contains(&addr) && block.successors == [addr] => {
+                    block.end = addr;
+                    block.successors = successors[&addr].clone();
+                }
+                _ => blocks.push(BasicBlock {
+                    start: addr,
+                    end: addr,
+                    successors: successors[&addr].clone(),
+                }),
+            }
+        }
+        blocks
+    }
+
+    //only instructions in basic_blocks() are counted, so data that happens to decode as a
+    //quirky opcode doesn't skew the result. ROMs too big for 4K are read with XO-CHIP's 64K,
+    //and anything past the end of that is ignored.
+    pub fn quirk_usage(bytes: &[u8]) -> QuirkUsage {
+        let mut c8 = Chip8::new();
+        if bytes.len() > c8.state.ram.len() - c8.start_address as usize {
+            c8.set_profile("xo-chip");
+        }
+        let fits = c8.state.ram.len() - c8.start_address as usize;
+        c8.load_rom_from_bytes(&bytes[..bytes.len().min(fits)]);
+
+        let mut usage = QuirkUsage::default();
+        for block in c8.basic_blocks() {
+            for pc in (block.start..=block.end).step_by(2) {
+                let opcode = ((c8.state.ram[pc as usize] as u16) << 8)
+                    | c8.state.ram[pc as usize + 1] as u16;
+                match opcode & 0xF000 {
+                    0x8000 if matches!(opcode & 0x000F, 0x6 | 0xE) => usage.shifts += 1,
+                    0xF000 if matches!(opcode & 0x00FF, 0x55 | 0x65) => usage.load_store += 1,
+                    0xB000 => usage.jump_with_offset += 1,
+                    0xD000 => usage.draws += 1,
+                    _ => (),
+                }
+            }
+        }
+        usage
+    }
+
+    pub fn disasm_lines(&self) -> Vec<DisasmLine> {
+        let mut lines: Vec<DisasmLine> = self
+            .disasm_map
+            .iter()
+            .map(|(addr, text)| DisasmLine {
+                addr: *addr,
+                text: text.clone(),
+                self_modified: self.self_modified(*addr),
+            })
+            .collect();
+        lines.sort_unstable_by_key(|line| line.addr);
+        lines
+    }
+
+    //disasm_map in address order, with an `L_xxxx:` line ahead of every JP/CALL target and the
+    //jumps rewritten to name the label. Bnnn is left alone as its target depends on a register.
+    //Instructions the program has overwritten are marked after the rewriting.
+    pub fn labelled_disasm(&self) -> Vec<String> {
+        let mut addrs: Vec<u16> = self.disasm_map.keys().cloned().collect();
+        addrs.sort_unstable();
+
+        let jump = |disasm: &str| -> Option<(&'static str, u16)> {
+            let (mnemonic, target) = match disasm.split_once(' ') {
+                Some(("JP", target)) => ("JP", target),
+                Some(("CALL", target)) => ("CALL", target),
+                _ => return None,
+            };
+            u16::from_str_radix(target, 16)
+                .ok()
+                .map(|addr| (mnemonic, addr))
+        };
+
+        let targets: HashSet<u16> = addrs
+            .iter()
+            .filter_map(|addr| jump(&self.disasm_map[addr]).map(|(_, target)| target))
+            .collect();
+
+        let mut lines = Vec::new();
+        for addr in addrs {
+            if targets.contains(&addr) {
+                lines.push(format!("L_{:04X}:", addr));
+            }
+            let disasm = &self.disasm_map[&addr];
+            let line = match jump(disasm) {
+                Some((mnemonic, target)) => format!("{:04X}: {} L_{:04X}", addr, mnemonic, target),
+                None => format!("{:04X}: {}", addr, disasm),
+            };
+            match self.self_modified(addr) {
+                true => lines.push(format!("{}  ⚠ self-modified", line)),
+                false => lines.push(line),
+            }
+        }
+        lines
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Chip8;
+    use super::{
+        BasicBlock, Chip8, MemoryRegion, Mode, QuirkUsage, Quirks, RunResult, RunStatus,
+        StopReason, TraceDivergence,
+    };
+    use crate::assembler::Assembler;
+    use crate::compiler::{asm_bytes_len, Compiler, Opcode};
+    use crate::lexer::Lexer;
+
+    //lexes, compiles and assembles src with default options, then clocks the ROM
+    fn compile_and_run(src: &str, cycles: u32) -> Chip8 {
+        compile_and_run_with(src, cycles, |_, _| {}).1
+    }
+
+    //configure sets compiler options and emulator settings (such as the reset seed) before the
+    //program is compiled and loaded
+    fn compile_and_run_with(
+        src: &str,
+        cycles: u32,
+        configure: impl FnOnce(&mut Compiler, &mut Chip8),
+    ) -> (Compiler, Chip8) {
+        let mut l = Lexer::new(src);
+        l.lex();
+        run_compiler(Compiler::new_from_lexer(&l), src, cycles, configure)
+    }
+
+    //compile_and_run_with for a lexer set up by the caller, e.g. with include sources
+    fn run_compiler(
+        mut c: Compiler,
+        src: &str,
+        cycles: u32,
+        configure: impl FnOnce(&mut Compiler, &mut Chip8),
+    ) -> (Compiler, Chip8) {
+        let mut c8 = Chip8::new();
+        configure(&mut c, &mut c8);
+        c.compile();
+        assert!(!c.had_error(), "{}", c.render_errors(src));
+        let a = Assembler::assemble_compiler(&c);
+
+        c8.load_rom_from_assembler(&a);
+        for _ in 0..cycles {
+            c8.clock();
+        }
+        (c, c8)
+    }
+
+    #[test]
+    pub fn test_opcode_tables_complete() {
+        let documented: [u16; 36] = [
+            0x00E0, 0x00EE, 0x00FE, 0x00FF, 0x1000, 0x2000, 0x3000, 0x4000, 0x5000, 0x6000, 0x7000,
+            0x8000, 0x8001, 0x8002, 0x8003, 0x8004, 0x8005, 0x8006, 0x8007, 0x800E, 0x9000, 0xA000,
+            0xB000, 0xC000, 0xD000, 0xE09E, 0xE0A1, 0xF007, 0xF00A, 0xF015, 0xF018, 0xF01E, 0xF029,
+            0xF033, 0xF055, 0xF065,
+        ];
+        let c8 = Chip8::new();
+
+        assert_eq!(c8.unhandled_opcodes(&documented), Vec::<u16>::new());
+        assert_eq!(
+            c8.unhandled_opcodes(&[0x8008, 0xE0FF, 0xF000, 0xF0FF]),
+            vec![0x8008, 0xE0FF, 0xF000, 0xF0FF]
+        );
+    }
 
     #[test]
     pub fn test_00E0() {
@@ -850,6 +2371,41 @@ mod tests {
         assert_eq!(clone, c8.framebuffer());
     }
 
+    #[test]
+    pub fn test_0000_nop() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 6] = [0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05]; //LD V0, 0; LD F, V0; DRW V0, V0, 5
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        c8.clock();
+        let drawn = c8.state.framebuffer;
+
+        c8.clock(); //0000 past the end of the ROM
+
+        assert_eq!(c8.pc(), 0x208);
+        assert_eq!(drawn, c8.state.framebuffer);
+    }
+
+    #[test]
+    pub fn test_0nnn_sys_ignored() {
+        let mut c8 = Chip8::new();
+        //SYS 123; SYS 120 (low nibble shared with CLS); SYS 2AE (shared with RET)
+        c8.load_rom_from_bytes(&[0x01, 0x23, 0x01, 0x20, 0x02, 0xAE]);
+        c8.state.framebuffer[0] = 0xFFFFFFFF;
+        for _ in 0..3 {
+            c8.clock();
+        }
+
+        assert_eq!(c8.pc(), 0x206);
+        assert_eq!(c8.sp(), 0);
+        assert_eq!(c8.state.framebuffer[0], 0xFFFFFFFF);
+        assert!(c8.unhandled_opcodes(&[0x0123, 0x0120, 0x02AE]).is_empty());
+
+        c8.disassemble();
+        assert_eq!(c8.disasm_map[&0x200], "SYS 123");
+    }
+
     #[test]
     pub fn test_00EE() {
         let mut c8 = Chip8::new();
@@ -924,6 +2480,55 @@ mod tests {
         assert_eq!(c8.state.V[0x3], 0x65);
     }
 
+    #[test]
+    pub fn test_register() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 2] = [0x6A, 0x42]; //LD VA, 42
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+
+        assert_eq!(c8.register(0xA), Some(0x42));
+        assert_eq!(c8.register(0xF), Some(0));
+        assert_eq!(c8.register(16), None);
+    }
+
+    #[test]
+    pub fn test_dump_registers() {
+        let mut c8 = Chip8::new();
+        //LD V0, 5; LD VF, FF; LD I, 2AB
+        let code: [u8; 6] = [0x60, 0x05, 0x6F, 0xFF, 0xA2, 0xAB];
+        c8.load_rom_from_bytes(&code);
+        for _ in 0..3 {
+            c8.clock();
+        }
+
+        let hex = c8.dump_registers(16);
+        assert!(hex.starts_with("V0: 0x05\nV1: 0x00\n"));
+        assert!(hex.contains("VF: 0xFF\n"));
+        assert!(hex.ends_with("I: 0x02AB\nPC: 0x0206\nSP: 0x00\nDT: 0x00\nST: 0x00"));
+        assert!(c8.dump_registers(2).starts_with("V0: 0b00000101\n"));
+        assert!(c8.dump_registers(10).contains("I: 683\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported radix 8")]
+    pub fn test_dump_registers_bad_radix() {
+        let c8 = Chip8::new();
+        c8.dump_registers(8);
+    }
+
+    #[test]
+    pub fn test_dump_stack() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 4] = [0x22, 0x02, 0x22, 0x02]; //CALL 202; CALL 202
+        c8.load_rom_from_bytes(&code);
+        assert_eq!(c8.dump_stack(), "");
+
+        c8.clock();
+        c8.clock();
+        assert_eq!(c8.dump_stack(), "0: 0x0202\n1: 0x0204");
+    }
+
     #[test]
     pub fn test_7xkk() {
         let mut c8 = Chip8::new();
@@ -994,6 +2599,26 @@ mod tests {
         assert_eq!(c8.state.V[0x5], 0x0 - 0x0);
     }
 
+    #[test]
+    pub fn test_8xy5_borrow() {
+        let mut c8 = Chip8::new();
+        //LD V5, 1; LD V7, 3; SUB V5, V7; LD V6, 3; SUB V6, V6
+        let code: [u8; 10] = [0x65, 0x01, 0x67, 0x03, 0x85, 0x75, 0x66, 0x03, 0x86, 0x65];
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        c8.clock();
+
+        assert_eq!(c8.state.V[0x5], 0xFE);
+        assert_eq!(c8.state.V[0xF], 0);
+
+        c8.clock();
+        c8.clock();
+
+        assert_eq!(c8.state.V[0x6], 0);
+        assert_eq!(c8.state.V[0xF], 1);
+    }
+
     #[test]
     pub fn test_8xy6() {
         let mut c8 = Chip8::new();
@@ -1004,6 +2629,59 @@ mod tests {
         assert_eq!(c8.state.V[0x5], 0x0 >> 1);
     }
 
+    #[test]
+    pub fn test_8xy6_shift_modes() {
+        //LD V5, 0x10; LD V7, 0x0B; SHR V5, V7
+        let code = [0x65, 0x10, 0x67, 0x0B, 0x85, 0x76];
+        let run = |shift_vy: bool| {
+            let mut c8 = Chip8::new();
+            c8.load_rom_from_bytes(&code);
+            c8.set_shift_vy(shift_vy);
+            c8.clock();
+            c8.clock();
+            c8.clock();
+            (c8.state.V[0x5], c8.state.V[0x7], c8.state.V[0xF])
+        };
+
+        //CHIP-48 shifts V5 in place
+        assert_eq!(run(false), (0x08, 0x0B, 0));
+        //COSMAC VIP shifts V7 into V5, leaving V7 alone
+        assert_eq!(run(true), (0x05, 0x0B, 1));
+    }
+
+    #[test]
+    pub fn test_8xyE_shift_modes() {
+        //LD V5, 0x81; LD V7, 0x42; SHL V5, V7
+        let code = [0x65, 0x81, 0x67, 0x42, 0x85, 0x7E];
+        let run = |shift_vy: bool| {
+            let mut c8 = Chip8::new();
+            c8.load_rom_from_bytes(&code);
+            c8.set_shift_vy(shift_vy);
+            c8.clock();
+            c8.clock();
+            c8.clock();
+            (c8.state.V[0x5], c8.state.V[0x7], c8.state.V[0xF])
+        };
+
+        assert_eq!(run(false), (0x02, 0x42, 1));
+        assert_eq!(run(true), (0x84, 0x42, 0));
+    }
+
+    #[test]
+    pub fn test_shift_vf_ordering() {
+        let mut c8 = Chip8::new();
+        //LD VF, 2; SHR VF; LD VF, 0x40; SHL VF
+        c8.load_rom_from_bytes(&[0x6F, 0x02, 0x8F, 0xF6, 0x6F, 0x40, 0x8F, 0xFE]);
+        c8.clock();
+        c8.clock();
+        //the result (1) is overwritten by the bit shifted out
+        assert_eq!(c8.state.V[0xF], 0);
+        c8.clock();
+        c8.clock();
+        //and so is 0x80
+        assert_eq!(c8.state.V[0xF], 0);
+    }
+
     #[test]
     pub fn test_8xy7() {
         let mut c8 = Chip8::new();
@@ -1014,6 +2692,34 @@ mod tests {
         assert_eq!(c8.state.V[0x5], 0x0 - 0x0);
     }
 
+    #[test]
+    pub fn test_8xy7_borrow() {
+        let mut c8 = Chip8::new();
+        //LD V5, 3; LD V7, 1; SUBN V5, V7
+        let code: [u8; 6] = [0x65, 0x03, 0x67, 0x01, 0x85, 0x77];
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        c8.clock();
+
+        assert_eq!(c8.state.V[0x5], 0xFE);
+        assert_eq!(c8.state.V[0xF], 0);
+    }
+
+    #[test]
+    pub fn test_8xy7_vf_ordering() {
+        let mut c8 = Chip8::new();
+        //LD VF, 2; LD V1, 5; SUBN VF, V1
+        let code: [u8; 6] = [0x6F, 0x02, 0x61, 0x05, 0x8F, 0x17];
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        c8.clock();
+
+        //the difference (3) is overwritten by the not-borrow flag
+        assert_eq!(c8.state.V[0xF], 1);
+    }
+
     #[test]
     pub fn test_8xyE() {
         let mut c8 = Chip8::new();
@@ -1055,111 +2761,1074 @@ mod tests {
     }
 
     #[test]
-    pub fn test_Cxkk() {
+    pub fn test_Bnnn_jump_with_vx() {
         let mut c8 = Chip8::new();
-        let code: [u8; 2] = [0xC0, 0x00]; //RND V0, 00
+        let code: [u8; 4] = [0x65, 0x02, 0xB5, 0x70]; //LD V5, 2; JP V5, 570
         c8.load_rom_from_bytes(&code);
+        c8.set_mode(Mode::SuperChip);
+        c8.clock();
         c8.clock();
 
-        assert_eq!(c8.state.V[0], 0);
+        assert_eq!(c8.pc(), 0x572);
     }
 
+    //cargo test --release bench_clock -- --ignored --nocapture
     #[test]
-    pub fn test_Dxyn() {
+    #[ignore]
+    pub fn bench_clock() {
+        //ALU, 8xy_ and Fx__ sub-table ops, a skip and a jump back, so every dispatch path is hot
+        let rom = [
+            0x60, 0x01, 0x80, 0x14, 0x72, 0x01, 0xA3, 0x00, 0xF2, 0x1E, 0x81, 0x23, 0x31, 0x00,
+            0x12, 0x00, 0x12, 0x00,
+        ];
         let mut c8 = Chip8::new();
-        let code: [u8; 2] = [0xD0, 0x01]; //DRW V0, V0, 1
-        c8.load_rom_from_bytes(&code);
-        c8.clock();
+        c8.load_rom_from_bytes(&rom);
 
-        assert_eq!(c8.state.framebuffer[0], 0xFFFFFFFF);
+        let cycles = 10_000_000;
+        let start = std::time::Instant::now();
+        for _ in 0..cycles {
+            c8.clock();
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{} clocks in {:?}, {:.2} ns/clock",
+            cycles,
+            elapsed,
+            elapsed.as_nanos() as f64 / cycles as f64
+        );
+        assert!(c8.error().is_none());
     }
 
     #[test]
-    pub fn test_Ex9E() {
+    pub fn test_sound_edges() {
         let mut c8 = Chip8::new();
-        let code: [u8; 2] = [0xE0, 0x9E]; //SKP V0
-        c8.load_rom_from_bytes(&code);
-        c8.clock();
-
-        assert_eq!(c8.pc(), 0x202);
+        c8.set_sound_timer(1);
+        assert!(!c8.sound_started());
+
+        c8.tick_timers();
+        assert_eq!(c8.sound_timer(), 0);
+        assert!(c8.sound_started());
+        assert!(!c8.sound_stopped());
+
+        c8.tick_timers();
+        assert!(!c8.sound_started());
+        assert!(c8.sound_stopped());
+
+        c8.tick_timers();
+        assert!(!c8.sound_started());
+        assert!(!c8.sound_stopped());
+
+        //a longer beep starts on its first frame and stops once it has counted down
+        c8.set_sound_timer(3);
+        c8.tick_timers();
+        assert!(c8.sound_started());
+        c8.tick_timers();
+        c8.tick_timers();
+        assert!(!c8.sound_started());
+        assert!(!c8.sound_stopped());
+        c8.tick_timers();
+        assert!(c8.sound_stopped());
     }
 
     #[test]
-    pub fn test_ExA1() {
+    pub fn test_set_pixel_collision() {
         let mut c8 = Chip8::new();
-        let code: [u8; 2] = [0xE0, 0xA1]; //SKNP V0
-        c8.load_rom_from_bytes(&code);
+        //LD I, 206; DRW V0, V0, 1; sprite 0x80
+        c8.load_rom_from_bytes(&[0xA2, 0x06, 0xD0, 0x01, 0x00, 0x00, 0x80, 0x00]);
+        c8.set_pixel(64, 32, true);
+        c8.clock();
         c8.clock();
+        assert_eq!(c8.register(0xF), Some(1));
+        assert_eq!(c8.state.framebuffer[0], 0);
 
-        assert_eq!(c8.pc(), 0x204);
+        //reset() also clears RAM, so load the ROM again
+        c8.load_rom_from_bytes(&[0xA2, 0x06, 0xD0, 0x01, 0x00, 0x00, 0x80, 0x00]);
+        c8.set_pixel(0, 0, true);
+        c8.clear_framebuffer();
+        c8.clock();
+        c8.clock();
+        assert_eq!(c8.register(0xF), Some(0));
+        assert_ne!(c8.state.framebuffer[0], 0);
     }
 
     #[test]
-    pub fn test_Fx07() {
+    pub fn test_disassemble_xo_chip_ram() {
         let mut c8 = Chip8::new();
-        let code: [u8; 6] = [0x60, 0x05, 0xF0, 0x15, 0xF0, 0x07]; //LD V0, 5; LD DT, V0; LD V0, DT
-        c8.load_rom_from_bytes(&code);
-        c8.clock();
-        c8.clock();
-        c8.clock();
+        c8.disassemble();
+        assert_eq!(c8.disasm_map.len(), (4096 - 0x200) / 2);
 
-        assert_eq!(c8.state.V[0], 0x4);
+        c8.set_profile("xo-chip");
+        c8.state.ram[0x1200..0x1202].copy_from_slice(&[0x60, 0x05]);
+        c8.state.ram[0xFFFE..].copy_from_slice(&[0x12, 0x00]);
+        c8.disassemble();
+        assert_eq!(c8.disasm_map.len(), (65536 - 0x200) / 2);
+        assert_eq!(c8.disasm_map[&0x1200], "LD V0, 5");
+        assert_eq!(c8.disasm_map[&0xFFFE], "JP 200");
     }
 
     #[test]
-    pub fn test_Fx0A() {
+    pub fn test_clear_video() {
         let mut c8 = Chip8::new();
-        let code: [u8; 2] = [0xF0, 0x0A]; //LD V0, K
-        c8.load_rom_from_bytes(&code);
+        c8.set_mode(Mode::SuperChip);
+        //HIGH; LD I, 208; DRW V0, V0, 1; JP 206; sprite 0xFF
+        c8.load_rom_from_bytes(&[0x00, 0xFF, 0xA2, 0x08, 0xD0, 0x01, 0x12, 0x06, 0xFF, 0x00]);
         c8.clock();
         c8.clock();
         c8.clock();
+        c8.set_pixel(127, 63, true);
+        assert!(c8.state.framebuffer.iter().any(|pixel| *pixel != 0));
 
-        assert_eq!(c8.pc(), 0x200);
+        c8.clear_video();
+        assert!(c8.state.framebuffer.iter().all(|pixel| *pixel == 0));
+        assert_eq!(c8.video_width(), 128);
+        assert_eq!(c8.pc(), 0x206);
     }
 
     #[test]
-    pub fn test_Fx15() {
+    pub fn test_disassemble_data_words() {
         let mut c8 = Chip8::new();
-        let code: [u8; 4] = [0x60, 0x05, 0xF0, 0x15]; //LD V0, 5; LD DT, V0;
-        c8.load_rom_from_bytes(&code);
-        c8.clock();
-        c8.clock();
-
-        assert_eq!(c8.delay_timer(), 0x4);
+        c8.load_rom_from_bytes(&[0xFF, 0xFF, 0xF0, 0xF0, 0x80, 0x0F, 0x60, 0x05]);
+        c8.disassemble();
+        assert_eq!(c8.disasm_map[&0x200], "DW FFFF");
+        assert_eq!(c8.disasm_map[&0x202], "DW F0F0");
+        assert_eq!(c8.disasm_map[&0x204], "DW 800F");
+        assert_eq!(c8.disasm_map[&0x206], "LD V0, 5");
     }
 
     #[test]
-    pub fn test_Fx18() {
-        let mut c8 = Chip8::new();
+    pub fn test_stringify_asm_matches_disassembly() {
+        let src = "var a = 5; fn f(x) { x = x + 1; I = 300; } f(a);
+            while (a != 0) { a = a - 1; DRAW(a, 3, 5); } DT = a; var k = KEY();";
+        let (c, mut c8) = compile_and_run_with(src, 0, |_, _| {});
+        c8.disassemble();
+        let disasm = c8.disasm_lines();
+
+        let asm = c.stringify_asm();
+        assert_eq!(asm.lines().count(), c.asm().len());
+        for (i, line) in asm.lines().enumerate() {
+            assert_eq!(line, disasm[i].text, "at {:X}", disasm[i].addr);
+        }
+    }
+
+    #[test]
+    pub fn test_Bnnn_disasm_follows_quirk() {
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_bytes(&[0xB5, 0x70]);
+        c8.disassemble();
+        assert_eq!(c8.disasm_map[&0x200], "JP V0, 570");
+
+        c8.set_mode(Mode::SuperChip);
+        c8.disassemble();
+        assert_eq!(c8.disasm_map[&0x200], "JP V5, 570");
+    }
+
+    #[test]
+    pub fn test_profiles() {
+        let mut c8 = Chip8::new();
+        let expected = [
+            ("cosmac-vip", Mode::Classic, true, true, 4096),
+            ("chip48", Mode::Classic, false, false, 4096),
+            ("superchip", Mode::SuperChip, false, false, 4096),
+            ("xo-chip", Mode::XoChip, true, true, 65536),
+        ];
+        assert_eq!(Chip8::profile_names().len(), expected.len());
+
+        for (profile, mode, shift_vy, load_store_increment, ram_size) in expected.iter() {
+            c8.set_profile(profile);
+
+            assert_eq!(c8.mode(), *mode);
+            assert_eq!(c8.quirks.shift_vy, *shift_vy);
+            assert_eq!(c8.quirks.load_store_increment, *load_store_increment);
+            assert_eq!(c8.state.ram.len(), *ram_size);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown profile")]
+    pub fn test_unknown_profile() {
+        let mut c8 = Chip8::new();
+        c8.set_profile("chip-9");
+    }
+
+    #[test]
+    pub fn test_load_rom_auto_superchip() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 4] = [0x00, 0xFF, 0x12, 0x02]; //HIGH; JP 202
+        c8.load_rom_auto(&code);
+
+        assert_eq!(c8.mode(), Mode::SuperChip);
+        assert_eq!(c8.quirks, Quirks::superchip());
+        assert_eq!((c8.video_width(), c8.video_height()), (128, 64));
+    }
+
+    #[test]
+    pub fn test_load_rom_auto_classic() {
+        let mut c8 = Chip8::new();
+        c8.set_mode(Mode::SuperChip);
+        let code: [u8; 6] = [0x00, 0xE0, 0xD0, 0x15, 0x12, 0x04]; //CLS; DRW V0, V1, 5; JP 204
+        c8.load_rom_auto(&code);
+
+        assert_eq!(c8.mode(), Mode::Classic);
+        assert_eq!(c8.quirks, Quirks::classic());
+        assert_eq!((c8.video_width(), c8.video_height()), (64, 32));
+    }
+
+    #[test]
+    pub fn test_rom_bytes() {
+        let mut c8 = Chip8::new();
+        assert!(c8.rom_bytes().is_empty());
+
+        let code: [u8; 5] = [0x60, 0x05, 0x12, 0x02, 0xAB];
+        c8.load_rom_from_bytes(&code);
+        assert_eq!(c8.rom_bytes(), code.to_vec());
+
+        c8.reset();
+        assert!(c8.rom_bytes().is_empty());
+    }
+
+    #[test]
+    pub fn test_Cxkk() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 2] = [0xC0, 0x00]; //RND V0, 00
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+
+        assert_eq!(c8.state.V[0], 0);
+    }
+
+    #[test]
+    pub fn test_estimated_cycles() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 8] = [0x60, 0x05, 0xD0, 0x01, 0xF0, 0x33, 0x12, 0x00]; //LD V0, 5; DRW V0, V0, 1; LD B, V0; JP 200
+        c8.load_rom_from_bytes(&code);
+        for _ in 0..4 {
+            c8.clock();
+        }
+
+        assert_eq!(c8.estimated_cycles(), 6 + 22734 + 84 + 12);
+
+        c8.reset();
+        assert_eq!(c8.estimated_cycles(), 0);
+    }
+
+    #[test]
+    pub fn test_rpl_flags_survive_warm_reset() {
+        let mut c8 = Chip8::new();
+        c8.set_mode(Mode::SuperChip);
+        //LD V0, 7; LD V1, 9; LD R, V1
+        c8.load_rom_from_bytes(&[0x60, 0x07, 0x61, 0x09, 0xF1, 0x75]);
+        for _ in 0..3 {
+            c8.clock();
+        }
+
+        c8.reset();
+        assert_eq!((c8.register(0), c8.register(1)), (Some(0), Some(0)));
+
+        //LD V1, R
+        c8.load_rom_from_bytes(&[0xF1, 0x85]);
+        c8.clock();
+        assert_eq!((c8.register(0), c8.register(1)), (Some(7), Some(9)));
+
+        c8.power_cycle();
+        c8.load_rom_from_bytes(&[0xF1, 0x85]);
+        c8.set_register(0, 1);
+        c8.clock();
+        assert_eq!((c8.register(0), c8.register(1)), (Some(0), Some(0)));
+    }
+
+    #[test]
+    pub fn test_rpl_flags_range() {
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_bytes(&[0xF0, 0x75]);
+        c8.clock();
+        assert!(c8.state.error.is_some());
+
+        c8.set_mode(Mode::SuperChip);
+        c8.load_rom_from_bytes(&[0xF8, 0x75]);
+        c8.clock();
+        assert!(c8.state.error.is_some());
+
+        c8.set_mode(Mode::XoChip);
+        c8.load_rom_from_bytes(&[0xFF, 0x75]);
+        c8.clock();
+        assert!(c8.state.error.is_none());
+    }
+
+    #[test]
+    pub fn test_reset_seed() {
+        let mut c8 = Chip8::new();
+        c8.set_reset_seed(1234);
+        let code: [u8; 4] = [0xC0, 0xFF, 0xC1, 0xFF]; //RND V0, FF; RND V1, FF
+
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        let first = (c8.state.V[0], c8.state.V[1]);
+
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        assert_eq!((c8.state.V[0], c8.state.V[1]), first);
+
+        c8.set_reset_seed(4321);
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        assert_ne!((c8.state.V[0], c8.state.V[1]), first);
+    }
+
+    #[test]
+    pub fn test_rand_variable_mask_run() {
+        let (_, c8) = compile_and_run_with(
+            "var m = 5; var a = RAND(m); var b = RAND(m); var c = RAND(m); var d = RAND(m);",
+            100,
+            |_, c8| c8.set_reset_seed(99),
+        );
+
+        for reg in 1..=4 {
+            let value = c8.register(reg).unwrap();
+            assert_eq!(value & !5, 0, "V{} = {}", reg, value);
+        }
+    }
+
+    #[test]
+    pub fn test_is_pressed_run() {
+        for pressed in [0, 1].iter() {
+            //keys are cleared when the ROM loads, so they're pressed before running it
+            let (c, mut c8) = compile_and_run_with("var down = is_pressed(5);", 0, |_, _| {});
+            c8.set_key(5, *pressed);
+            c8.set_key(4, 1);
+            for _ in 0..c.asm().len() {
+                c8.clock();
+            }
+            assert_eq!(c8.register(0), Some(*pressed));
+        }
+    }
+
+    #[test]
+    pub fn test_trace_divergence() {
+        //LD V1, 1; RND V0, FF; SE V0, 0; ADD V1, 1; JP 208
+        let code: [u8; 10] = [0x61, 0x01, 0xC0, 0xFF, 0x30, 0x00, 0x71, 0x01, 0x12, 0x08];
+        let traced_run = |seed: u64| {
+            let mut c8 = Chip8::new();
+            c8.set_reset_seed(seed);
+            c8.set_tracing(true);
+            c8.load_rom_from_bytes(&code);
+            for _ in 0..8 {
+                c8.clock();
+            }
+            c8
+        };
+
+        let first = traced_run(1234);
+        assert_eq!(first.trace_len(), 8);
+        assert_eq!(first.trace_divergence(&traced_run(1234)), None);
+        assert_eq!(
+            first.trace_divergence(&traced_run(4321)),
+            Some(TraceDivergence {
+                cycle: 1,
+                pc: Some(0x202),
+                other_pc: Some(0x202),
+            })
+        );
+
+        let mut shorter = traced_run(1234);
+        shorter.trace.truncate(5);
+        assert_eq!(
+            first.trace_divergence(&shorter),
+            Some(TraceDivergence {
+                cycle: 5,
+                pc: Some(0x208),
+                other_pc: None,
+            })
+        );
+    }
+
+    #[test]
+    pub fn test_Dxyn() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 2] = [0xD0, 0x01]; //DRW V0, V0, 1
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+
+        assert_eq!(c8.state.framebuffer[0], 0xFFFFFFFF);
+    }
+
+    #[test]
+    pub fn test_Dxy0() {
+        //DRW V0, V0, 0; then a 16x16 sprite whose rows alternate between full and empty
+        let mut code = vec![0xD0, 0x00];
+        for row in 0..16 {
+            let byte = if row % 2 == 0 { 0xFF } else { 0x00 };
+            code.extend_from_slice(&[byte, byte]);
+        }
+
+        for mode in [Mode::SuperChip, Mode::XoChip] {
+            let mut c8 = Chip8::new();
+            c8.set_mode(mode);
+            c8.load_rom_from_bytes(&code);
+            c8.set_i(0x202);
+            c8.clock();
+
+            let width = c8.video_width() as usize;
+            for row in 0..16 {
+                for col in 0..17 {
+                    let lit = c8.state.framebuffer[row * width + col] != 0;
+                    assert_eq!(lit, row % 2 == 0 && col < 16, "row {} col {}", row, col);
+                }
+            }
+            assert_eq!(c8.state.framebuffer[16 * width], 0);
+            assert_eq!(c8.register(0xF), Some(0));
+
+            c8.set_pc(0x200);
+            c8.clock();
+            assert_eq!(c8.register(0xF), Some(1));
+            assert!(c8.state.framebuffer.iter().all(|pixel| *pixel == 0));
+        }
+    }
+
+    #[test]
+    pub fn test_Dxy0_classic_draws_nothing() {
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_bytes(&[0xD0, 0x00, 0x80, 0x80]);
+        c8.set_i(0x202);
+        c8.clock();
+
+        assert!(c8.state.framebuffer.iter().all(|pixel| *pixel == 0));
+        c8.disassemble();
+        assert_eq!(c8.disasm_lines()[0].text, "DRW V0, V0, 0");
+    }
+
+    #[test]
+    pub fn test_Fn01() {
+        let mut c8 = Chip8::new();
+        c8.set_mode(Mode::XoChip);
+        //DRW V0, V0, 1 on plane 1; PLANE 2; DRW V0, V0, 1; sprite 0xF0
+        let code: [u8; 8] = [0xD0, 0x01, 0xF2, 0x01, 0xD0, 0x01, 0xF0, 0x00];
+        c8.load_rom_from_bytes(&code);
+        c8.set_i(0x206);
+        c8.clock();
+        let before = c8.state.framebuffer;
+        c8.clock();
+        c8.clock();
+
+        for (pixel, (was, now)) in before.iter().zip(c8.state.framebuffer.iter()).enumerate() {
+            let plane_2 = if pixel < 4 { 0b10 } else { 0 };
+            assert_eq!(now ^ was, plane_2, "pixel {}", pixel);
+            assert_eq!(now & 0b01, was & 0b01, "pixel {}", pixel);
+        }
+        assert_eq!(c8.state.framebuffer[0], 0b11);
+        assert!(!c8.last_draw_collision());
+    }
+
+    #[test]
+    pub fn test_Fn01_reset_by_load() {
+        let mut c8 = Chip8::new();
+        c8.set_mode(Mode::XoChip);
+        //PLANE 0
+        c8.load_rom_from_bytes(&[0xF0, 0x01]);
+        c8.clock();
+
+        //DRW V0, V0, 1; sprite 0x80, drawn to plane 1 again
+        c8.load_rom_from_bytes(&[0xD0, 0x01, 0x80, 0x00]);
+        c8.set_i(0x202);
+        c8.clock();
+        assert_eq!(c8.state.framebuffer[0], 0b01);
+    }
+
+    #[test]
+    pub fn test_Fn01_requires_xo_chip() {
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_bytes(&[0xF2, 0x01]);
+        c8.clock();
+        assert!(c8.error().is_some());
+    }
+
+    #[test]
+    pub fn test_clock_unknown_opcode() {
+        //past the end of opcodes_F, past the end of opcodes_8, and a gap inside opcodes_F
+        for rom in [[0xFF, 0xFF], [0x80, 0x0F], [0xF0, 0x02]] {
+            let mut c8 = Chip8::new();
+            c8.load_rom_from_bytes(&rom);
+            c8.clock();
+            assert!(c8.error().unwrap().starts_with("Unknown opcode"));
+        }
+    }
+
+    #[test]
+    pub fn test_last_draw_collision() {
+        let mut c8 = Chip8::new();
+        //DRW V0, V0, 1; DRW V0, V0, 1; LD VF, 0; DRW V1, V1, 1
+        let code: [u8; 8] = [0xD0, 0x01, 0xD0, 0x01, 0x6F, 0x00, 0xD1, 0x11];
+        c8.load_rom_from_bytes(&code);
+        c8.set_register(1, 8);
+
+        c8.clock();
+        assert!(!c8.last_draw_collision());
+        c8.clock();
+        assert!(c8.last_draw_collision());
+        c8.clock();
+        assert_eq!(c8.state.V[0xF], 0);
+        assert!(c8.last_draw_collision());
+        c8.clock();
+        assert!(!c8.last_draw_collision());
+    }
+
+    #[test]
+    pub fn test_Ex9E() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 2] = [0xE0, 0x9E]; //SKP V0
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+
+        assert_eq!(c8.pc(), 0x202);
+    }
+
+    #[test]
+    pub fn test_ExA1() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 2] = [0xE0, 0xA1]; //SKNP V0
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+
+        assert_eq!(c8.pc(), 0x204);
+    }
+
+    #[test]
+    pub fn test_Fx07() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 6] = [0x60, 0x05, 0xF0, 0x15, 0xF0, 0x07]; //LD V0, 5; LD DT, V0; LD V0, DT
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        c8.tick_timers();
+        c8.clock();
+
+        assert_eq!(c8.state.V[0], 0x4);
+    }
+
+    #[test]
+    pub fn test_Fx0A() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 2] = [0xF0, 0x0A]; //LD V0, K
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        c8.clock();
+
+        assert_eq!(c8.pc(), 0x200);
+    }
+
+    #[test]
+    pub fn test_run_for_blocked_on_key() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 4] = [0xF0, 0x0A, 0x60, 0x01]; //LD V0, K; LD V0, 1
+        c8.load_rom_from_bytes(&code);
+
+        assert_eq!(c8.run_for(10), RunStatus::BlockedOnKey);
+        assert_eq!(c8.pc(), 0x200);
+
+        c8.set_key(0x5, 1);
+        assert_eq!(c8.run_for(1), RunStatus::Running);
+        assert_eq!(c8.state.V[0], 0x5);
+        assert_eq!(c8.pc(), 0x202);
+    }
+
+    #[test]
+    pub fn test_run_stop_reasons() {
+        let run = |rom: &[u8], breakpoint: Option<u16>, max_cycles: u32| {
+            let mut c8 = Chip8::new();
+            c8.load_rom_from_bytes(rom);
+            if let Some(addr) = breakpoint {
+                c8.add_breakpoint(addr);
+            }
+            let result = c8.run_cycles(max_cycles);
+            (result, c8.pc())
+        };
+        let result = |cycles_run, stop_reason| RunResult {
+            cycles_run,
+            stop_reason,
+        };
+
+        //LD V0, 1; ADD V0, 1; JP 0x202
+        let looping = [0x60, 0x01, 0x70, 0x01, 0x12, 0x02];
+        assert_eq!(
+            run(&looping, None, 10),
+            (result(10, StopReason::MaxCycles), 0x204)
+        );
+        assert_eq!(
+            run(&looping, Some(0x204), 10),
+            (result(2, StopReason::Breakpoint), 0x204)
+        );
+
+        //LD V0, 1; JP 0x202
+        assert_eq!(
+            run(&[0x60, 0x01, 0x12, 0x02], None, 10),
+            (result(2, StopReason::Halt), 0x202)
+        );
+
+        //LD V0, 1; LD V1, K
+        assert_eq!(
+            run(&[0x60, 0x01, 0xF1, 0x0A], None, 10),
+            (result(2, StopReason::KeyWait), 0x202)
+        );
+
+        //LD V0, 1; 0xF0FF has no handler and is not executed
+        assert_eq!(
+            run(&[0x60, 0x01, 0xF0, 0xFF], None, 10),
+            (result(1, StopReason::Illegal), 0x202)
+        );
+    }
+
+    #[test]
+    pub fn test_cycle_budget() {
+        let mut c8 = Chip8::new();
+        //LD V0, 1; LD V1, 2; JP 0x200, a loop that never jumps to itself
+        c8.load_rom_from_bytes(&[0x60, 0x01, 0x61, 0x02, 0x12, 0x00]);
+        c8.set_cycle_budget(Some(100));
+
+        let result = c8.run_cycles(1000);
+        assert_eq!(
+            result,
+            RunResult {
+                cycles_run: 100,
+                stop_reason: StopReason::Timeout
+            }
+        );
+        //the budget is shared by every later call until it's reset
+        assert_eq!(c8.run_cycles(10).cycles_run, 0);
+        assert_eq!(c8.run_for(10), RunStatus::TimedOut);
+
+        c8.set_cycle_budget(Some(50));
+        assert_eq!(c8.run_for(30), RunStatus::Running);
+        assert_eq!(c8.run_for(30), RunStatus::TimedOut);
+        assert_eq!(c8.run_cycles(10).stop_reason, StopReason::Timeout);
+
+        c8.set_cycle_budget(None);
+        assert_eq!(c8.run_cycles(1000).stop_reason, StopReason::MaxCycles);
+    }
+
+    #[test]
+    pub fn test_cycle_budget_ignores_halt() {
+        let mut c8 = Chip8::new();
+        //LD V0, 1; JP 202
+        c8.load_rom_from_bytes(&[0x60, 0x01, 0x12, 0x02]);
+        c8.set_cycle_budget(Some(3));
+
+        assert_eq!(c8.run_cycles(1).stop_reason, StopReason::MaxCycles);
+        for _ in 0..10 {
+            assert_eq!(c8.run_cycles(1).stop_reason, StopReason::Halt);
+        }
+    }
+
+    #[test]
+    pub fn test_run_resumes_from_breakpoint() {
+        let mut c8 = Chip8::new();
+        //ADD V0, 1; JP 0x200
+        c8.load_rom_from_bytes(&[0x70, 0x01, 0x12, 0x00]);
+        c8.add_breakpoint(0x200);
+
+        //a run starting on a breakpoint it hasn't stopped at yet stops straight away
+        let result = c8.run_cycles(100);
+        assert_eq!(result.stop_reason, StopReason::Breakpoint);
+        assert_eq!(result.cycles_run, 0);
+        for laps in 1..=3 {
+            let result = c8.run_cycles(100);
+            assert_eq!(result.stop_reason, StopReason::Breakpoint);
+            assert_eq!(result.cycles_run, 2);
+            assert_eq!(c8.register(0), Some(laps));
+        }
+
+        c8.remove_breakpoint(0x200);
+        assert_eq!(c8.run_cycles(100).stop_reason, StopReason::MaxCycles);
+    }
+
+    #[test]
+    pub fn test_single_cycle_runs_stop_at_breakpoint() {
+        let mut c8 = Chip8::new();
+        //LD V0, 1; LD V1, 2; JP 0x200
+        c8.load_rom_from_bytes(&[0x60, 0x01, 0x61, 0x02, 0x12, 0x00]);
+        c8.add_breakpoint(0x202);
+
+        //the page clocks with run(1), which has to stop at the breakpoint and then resume
+        let reasons: Vec<(StopReason, u16)> = (0..5)
+            .map(|_| (c8.run_cycles(1).stop_reason, c8.pc()))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (StopReason::MaxCycles, 0x202),
+                (StopReason::Breakpoint, 0x202),
+                (StopReason::MaxCycles, 0x204),
+                (StopReason::MaxCycles, 0x200),
+                (StopReason::MaxCycles, 0x202),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_run_until_draw() {
+        let mut c8 = Chip8::new();
+        //LD V0, 1; LD V1, 2; DRW V0, V1, 1; LD V2, 3; CLS; JP 20A
+        let code: [u8; 12] = [
+            0x60, 0x01, 0x61, 0x02, 0xD0, 0x11, 0x62, 0x03, 0x00, 0xE0, 0x12, 0x0A,
+        ];
+        c8.load_rom_from_bytes(&code);
+
+        let result = |cycles_run, stop_reason| RunResult {
+            cycles_run,
+            stop_reason,
+        };
+        assert_eq!(c8.run_cycles_until_draw(100), result(3, StopReason::Draw));
+        assert_eq!(c8.pc(), 0x206);
+        assert_eq!(c8.run_cycles_until_draw(100), result(2, StopReason::Draw));
+        assert_eq!(c8.pc(), 0x20A);
+        assert_eq!(c8.run_cycles_until_draw(100), result(1, StopReason::Halt));
+
+        c8.load_rom_from_bytes(&code);
+        c8.add_breakpoint(0x202);
+        assert_eq!(
+            c8.run_cycles_until_draw(100),
+            result(1, StopReason::Breakpoint)
+        );
+        assert_eq!(c8.run_cycles_until_draw(100), result(2, StopReason::Draw));
+
+        c8.clear_breakpoints();
+        c8.load_rom_from_bytes(&code);
+        c8.set_cycle_budget(Some(2));
+        assert_eq!(
+            c8.run_cycles_until_draw(100),
+            result(2, StopReason::Timeout)
+        );
+    }
+
+    #[test]
+    pub fn test_Fx15() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 4] = [0x60, 0x05, 0xF0, 0x15]; //LD V0, 5; LD DT, V0;
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+        assert_eq!(c8.delay_timer(), 0x5);
+
+        c8.tick_timers();
+        assert_eq!(c8.delay_timer(), 0x4);
+    }
+
+    #[test]
+    pub fn test_set_timers() {
+        let mut c8 = Chip8::new();
+        c8.set_delay_timer(10);
+        c8.set_sound_timer(1);
+        assert_eq!(c8.delay_timer(), 10);
+        assert_eq!(c8.sound_timer(), 1);
+
+        c8.tick_timers();
+        assert_eq!(c8.delay_timer(), 9);
+        assert_eq!(c8.sound_timer(), 0);
+        c8.tick_timers();
+        assert_eq!(c8.sound_timer(), 0);
+
+        //LD V0, DT
+        c8.load_rom_from_bytes(&[0xF0, 0x07]);
+        c8.set_delay_timer(42);
+        c8.clock();
+        assert_eq!(c8.register(0), Some(42));
+    }
+
+    #[test]
+    pub fn test_undo() {
+        //LD V0, 5; LD V1, FF; ADD V0, V1; CALL 20A; JP 208; RET
+        let code = [
+            0x60, 0x05, 0x61, 0xFF, 0x80, 0x14, 0x22, 0x0A, 0x12, 0x08, 0x00, 0xEE,
+        ];
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_bytes(&code);
+        c8.set_undo_log(true);
+
+        let mut states = Vec::new();
+        for _ in 0..5 {
+            states.push((c8.pc(), c8.state.V, c8.sp(), c8.estimated_cycles()));
+            c8.clock();
+        }
+        assert_eq!(c8.register(0), Some(4));
+        assert_eq!(c8.register(0xF), Some(1));
+        assert_eq!(c8.undo_len(), 5);
+
+        while let Some(state) = states.pop() {
+            assert!(c8.undo());
+            assert_eq!((c8.pc(), c8.state.V, c8.sp(), c8.estimated_cycles()), state);
+        }
+        assert!(!c8.undo());
+    }
+
+    #[test]
+    pub fn test_undo_stops_at_irreversible() {
+        //LD V0, 1; RND V1, FF; LD V0, 2
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_bytes(&[0x60, 0x01, 0xC1, 0xFF, 0x60, 0x02]);
+        c8.set_undo_log(true);
+        for _ in 0..3 {
+            c8.clock();
+        }
+
+        assert!(c8.undo());
+        assert_eq!(c8.register(0), Some(1));
+        assert_eq!(c8.pc(), 0x204);
+        assert!(!c8.undo());
+    }
+
+    #[test]
+    pub fn test_undo_cleared_by_load_state() {
+        //LD V0, 1; LD V1, 2; ADD V0, V1
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_bytes(&[0x60, 0x01, 0x61, 0x02, 0x80, 0x14]);
+        c8.set_undo_log(true);
+        c8.save_state();
+        for _ in 0..3 {
+            c8.clock();
+        }
+        assert_eq!(c8.undo_len(), 3);
+
+        c8.load_state();
+        let restored = (c8.pc(), c8.state.V, c8.I(), c8.sp());
+        assert!(!c8.undo());
+        assert_eq!((c8.pc(), c8.state.V, c8.I(), c8.sp()), restored);
+
+        c8.clock();
+        c8.set_register(0, 9);
+        assert!(!c8.undo());
+        assert_eq!(c8.register(0), Some(9));
+    }
+
+    #[test]
+    pub fn test_step_leaves_timers() {
+        let mut c8 = Chip8::new();
+        //JP 200
+        c8.load_rom_from_bytes(&[0x12, 0x00]);
+        c8.set_delay_timer(10);
+        c8.set_sound_timer(10);
+
+        c8.step();
+        c8.step();
+        assert_eq!(c8.delay_timer(), 10);
+        assert_eq!(c8.sound_timer(), 10);
+
+        c8.step_with_timers();
+        assert_eq!(c8.delay_timer(), 9);
+        assert_eq!(c8.sound_timer(), 9);
+        assert_eq!(c8.pc(), 0x200);
+    }
+
+    #[test]
+    pub fn test_timers_tick_per_frame() {
+        let mut c8 = Chip8::new();
+        //LD V0, 200; LD DT, V0; JP 204
+        let code: [u8; 6] = [0x60, 0xC8, 0xF0, 0x15, 0x12, 0x04];
+        c8.load_rom_from_bytes(&code);
+
+        for _ in 0..3 {
+            c8.run_for(37);
+            c8.tick_timers();
+        }
+        c8.run_for(1000);
+
+        assert_eq!(c8.frame_count(), 3);
+        assert_eq!(c8.delay_timer(), 200 - 3);
+
+        c8.reset();
+        assert_eq!(c8.frame_count(), 0);
+    }
+
+    #[test]
+    pub fn test_Fx18() {
+        let mut c8 = Chip8::new();
         let code: [u8; 4] = [0x60, 0x05, 0xF0, 0x18]; //LD V0, 5; LD ST, V0;
         c8.load_rom_from_bytes(&code);
         c8.clock();
         c8.clock();
+        assert_eq!(c8.sound_timer(), 0x5);
+
+        c8.tick_timers();
+        assert_eq!(c8.sound_timer(), 0x4);
+    }
+
+    #[test]
+    pub fn test_Fx1E() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 4] = [0x60, 0x05, 0xF0, 0x1E]; //LD V0, 5; ADD I, Vx
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+
+        assert_eq!(c8.I(), 5);
+    }
+
+    #[test]
+    pub fn test_Fx29() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 4] = [0x60, 0x05, 0xF0, 0x29]; //LD V0, 5; LD F, V0
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
 
-        assert_eq!(c8.sound_timer(), 0x4);
+        assert_eq!(c8.I(), 25);
     }
 
     #[test]
-    pub fn test_Fx1E() {
+    pub fn test_write_font_region() {
         let mut c8 = Chip8::new();
-        let code: [u8; 4] = [0x60, 0x05, 0xF0, 0x1E]; //LD V0, 5; ADD I, Vx
+        //LD V0, 2; LD F, V0; LD V1, 0; DRW V1, V1, 5
+        let code: [u8; 8] = [0x60, 0x02, 0xF0, 0x29, 0x61, 0x00, 0xD1, 0x15];
         c8.load_rom_from_bytes(&code);
-        c8.clock();
-        c8.clock();
+        c8.write_font_region(2 * 5, &[0x81, 0x00, 0x00, 0x00, 0x00]);
+        for _ in 0..4 {
+            c8.clock();
+        }
 
-        assert_eq!(c8.I(), 5);
+        assert_eq!(c8.I(), 10);
+        assert_eq!(c8.state.framebuffer[0], 0xFFFFFFFF);
+        assert_eq!(c8.state.framebuffer[1], 0);
+        assert_eq!(c8.state.framebuffer[7], 0xFFFFFFFF);
+        assert_eq!(c8.state.framebuffer[64], 0);
     }
 
     #[test]
-    pub fn test_Fx29() {
+    pub fn test_basic_blocks() {
+        //LD V0, 3; loop: ADD V0, FF; SE V0, 0; JP loop; halt: JP halt
+        let code: [u8; 10] = [0x60, 0x03, 0x70, 0xFF, 0x30, 0x00, 0x12, 0x02, 0x12, 0x08];
         let mut c8 = Chip8::new();
-        let code: [u8; 4] = [0x60, 0x05, 0xF0, 0x29]; //LD V0, 5; LD F, V0
         c8.load_rom_from_bytes(&code);
+
+        let block = |start: u16, end: u16, successors: Vec<u16>| BasicBlock {
+            start,
+            end,
+            successors,
+        };
+        assert_eq!(
+            c8.basic_blocks(),
+            vec![
+                block(0x200, 0x200, vec![0x202]),
+                block(0x202, 0x204, vec![0x206, 0x208]),
+                block(0x206, 0x206, vec![0x202]),
+                block(0x208, 0x208, vec![0x208]),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_basic_blocks_call() {
+        //CALL sub; JP 202; sub: RET
+        let code: [u8; 6] = [0x22, 0x04, 0x12, 0x02, 0x00, 0xEE];
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_bytes(&code);
+
+        let blocks = c8.basic_blocks();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].successors, vec![0x204, 0x202]);
+        assert_eq!(blocks[1].successors, vec![0x202]);
+        assert!(blocks[2].successors.is_empty());
+    }
+
+    #[test]
+    pub fn test_basic_blocks_end_of_xo_chip_ram() {
+        //CLS, then zeroed RAM falls through to the last word of the 64K
+        let mut c8 = Chip8::new();
+        c8.set_profile("xo-chip");
+        c8.load_rom_from_bytes(&[0x00, 0xE0]);
+
+        let blocks = c8.basic_blocks();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].start, 0x200);
+        assert_eq!(blocks[0].end, 0xFFFE);
+        assert!(blocks[0].successors.is_empty());
+    }
+
+    #[test]
+    pub fn test_start_address() {
+        let mut c8 = Chip8::new();
+        c8.set_start_address(0x600);
+        //LD V0, 7; JP 602
+        c8.load_rom_from_bytes(&[0x60, 0x07, 0x16, 0x02]);
+        assert_eq!(c8.pc(), 0x600);
+        assert_eq!(c8.state.ram[0x200], 0);
+
         c8.clock();
-        c8.clock();
+        assert_eq!(c8.register(0), Some(7));
+        assert_eq!(c8.pc(), 0x602);
+        assert_eq!(c8.rom_bytes(), vec![0x60, 0x07, 0x16, 0x02]);
+        assert_eq!(c8.run_cycles(10).stop_reason, StopReason::Halt);
+
+        //the page loads compiled programs through the assembler
+        let (_, c8) = compile_and_run_with("var a = 9;", 1, |_, c8| c8.set_start_address(0x600));
+        assert_eq!(c8.state.ram[0x200], 0);
+        assert_eq!(c8.rom_bytes(), vec![0x60, 0x09]);
+        assert_eq!(c8.register(0), Some(9));
+    }
 
-        assert_eq!(c8.I(), 25);
+    #[test]
+    pub fn test_start_address_relocates_assembled_jumps() {
+        let src = "var a = 0; while (a != 5) { a = a + 1; } var b = 9;";
+        let (_, c8) = compile_and_run_with(src, 200, |_, c8| c8.set_start_address(0x600));
+        assert_eq!(c8.register(0), Some(5));
+        assert_eq!(c8.register(1), Some(9));
+        assert!(c8.pc() >= 0x600);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be even and within RAM")]
+    pub fn test_start_address_odd() {
+        let mut c8 = Chip8::new();
+        c8.set_start_address(0x601);
+    }
+
+    #[test]
+    #[should_panic(expected = "within RAM from 0x200")]
+    pub fn test_start_address_below_program_area() {
+        let mut c8 = Chip8::new();
+        c8.set_start_address(0x40);
+    }
+
+    #[test]
+    pub fn test_quirk_usage() {
+        //SHR V1; SHL V2; JP V0, 208; then unreachable past the computed jump: JP 206; SHR V3
+        let rom = [0x81, 0x06, 0x82, 0x2E, 0xB2, 0x08, 0x12, 0x06, 0x83, 0x36];
+        assert_eq!(
+            Chip8::quirk_usage(&rom),
+            QuirkUsage {
+                shifts: 2,
+                load_store: 0,
+                jump_with_offset: 1,
+                draws: 0,
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_quirk_usage_large_rom() {
+        //SHR V1, then zeroed words falling through past the 4K a classic machine has
+        let mut rom = vec![0; 5000];
+        rom[..2].copy_from_slice(&[0x81, 0x06]);
+        rom[4998..].copy_from_slice(&[0x81, 0x06]);
+        assert_eq!(Chip8::quirk_usage(&rom).shifts, 2);
+
+        let rom = vec![0; 70000];
+        assert_eq!(Chip8::quirk_usage(&rom), QuirkUsage::default());
+    }
+
+    #[test]
+    pub fn test_font_address() {
+        let c8 = Chip8::new();
+        assert_eq!(c8.font_address(0x0), 0);
+        assert_eq!(c8.font_address(0xA), 50);
+        assert_eq!(c8.font_address(0xF), 75);
+    }
+
+    #[test]
+    #[should_panic(expected = "Writing font region out of range")]
+    pub fn test_write_font_region_out_of_range() {
+        let mut c8 = Chip8::new();
+        c8.write_font_region(78, &[0xF0, 0x90, 0xF0]);
     }
 
     #[test]
@@ -1195,6 +3864,421 @@ mod tests {
         assert_eq!(c8.I(), 0x9);
     }
 
+    #[test]
+    pub fn test_framebuffer_rgba() {
+        let mut c8 = Chip8::new();
+        c8.set_colors(0x33FF66FF, 0x000000FF);
+        let code: [u8; 2] = [0xD0, 0x01]; //DRW V0, V0, 1
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+
+        let rgba = c8.framebuffer_rgba();
+        assert_eq!(rgba.len(), 64 * 32 * 4);
+        assert_eq!(rgba[0..4], [0x33, 0xFF, 0x66, 0xFF]);
+        assert_eq!(rgba[8 * 4..8 * 4 + 4], [0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    pub fn test_odd_pc() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 2] = [0x12, 0x03]; //JP 203
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+
+        assert_eq!(
+            c8.error(),
+            Some(String::from("Misaligned program counter 203"))
+        );
+        assert_eq!(c8.pc(), 0x203);
+
+        c8.clock();
+        assert_eq!(c8.pc(), 0x203);
+    }
+
+    #[test]
+    pub fn test_odd_pc_realign() {
+        let mut c8 = Chip8::new();
+        c8.set_realign_odd_pc(true);
+        let code: [u8; 4] = [0x12, 0x03, 0x63, 0x65]; //JP 203; LD V3, 65
+        c8.load_rom_from_bytes(&code);
+        c8.clock();
+        c8.clock();
+
+        assert_eq!(c8.error(), None);
+        assert_eq!(c8.state.V[0x3], 0x65);
+        assert_eq!(c8.pc(), 0x204);
+    }
+
+    #[test]
+    pub fn test_variable_comparison_branches() {
+        let c8 = compile_and_run(
+            "var a = 4; var b = 4; var c = 0; if (a == b) c = 1; if (a != b) c = 2;",
+            20,
+        );
+
+        assert_eq!(c8.state.V[2], 1);
+    }
+
+    #[test]
+    pub fn test_constant_comparison_branches() {
+        let c8 = compile_and_run(
+            "var a = 5; var b = 0; if (a == 5) b = 1; if (a != 5) b = 2;",
+            20,
+        );
+
+        assert_eq!(c8.state.V[1], 1);
+    }
+
+    #[test]
+    pub fn test_hline_draws_pixels() {
+        let c8 = compile_and_run("hline(0, 0, 8);", 100);
+
+        let lit = c8.state.framebuffer.iter().filter(|p| **p != 0).count();
+        assert_eq!(lit, 8);
+        assert!(c8.state.framebuffer[..8].iter().all(|p| *p == 0xFFFFFFFF));
+    }
+
+    #[test]
+    pub fn test_array_round_trip() {
+        let (_, c8) = compile_and_run_with(
+            "var a[4]; var v = 9; a[2] = v; a[3] = 5; var x = a[2];",
+            100,
+            |c, _| c.set_bounds_checks(true),
+        );
+
+        assert_eq!(c8.register(1), Some(9));
+    }
+
+    #[test]
+    pub fn test_array_out_of_bounds_write_trapped() {
+        let (_, c8) = compile_and_run_with("var a[2]; var i = 3; a[i] = 7;", 100, |c, _| {
+            c.set_bounds_checks(true)
+        });
+        let rom = c8.rom_bytes();
+
+        //stuck on the trap, the jump after the halt, with nothing written past the program
+        let trap = 0x200 + rom.len() as u16 - 4;
+        assert_eq!(c8.state.pc, trap);
+        assert!(c8.state.ram[0x200 + rom.len()..0x210 + rom.len()]
+            .iter()
+            .all(|byte| *byte == 0));
+        assert_eq!(&c8.state.ram[0x200..0x200 + rom.len()], &rom[..]);
+    }
+
+    #[test]
+    pub fn test_include_function() {
+        let src = "include \"timer\";\narm(2, 3);";
+        let mut l = Lexer::new(src);
+        l.add_source("timer", "fn arm(a, b) {\n  DT = a + b;\n}");
+        l.lex();
+        let (_, c8) = run_compiler(Compiler::new_from_lexer(&l), src, 40, |_, _| {});
+
+        assert_eq!(c8.delay_timer(), 5);
+        assert_eq!(c8.sp(), 0);
+    }
+
+    #[test]
+    pub fn test_compile_and_run_arithmetic() {
+        let c8 = compile_and_run("var a = 2 + 4; var b = a - 1; a = a + b;", 20);
+        assert_eq!(c8.register(0), Some(11));
+        assert_eq!(c8.register(1), Some(5));
+    }
+
+    #[test]
+    pub fn test_compile_and_run_loop() {
+        let c8 = compile_and_run(
+            "var n = 3; var sum = 0; while (n != 0) { sum = sum + n; n = n - 1; }",
+            100,
+        );
+        assert_eq!(c8.register(0), Some(0));
+        assert_eq!(c8.register(1), Some(6));
+    }
+
+    #[test]
+    pub fn test_data_table_run() {
+        let c8 = compile_and_run(
+            "data squares = [0, 1, 4, 9, 16, 25];\nvar n = squares[4];",
+            20,
+        );
+        assert_eq!(c8.register(0), Some(16));
+    }
+
+    #[test]
+    pub fn test_switch_run() {
+        for (x, r) in [(1, 10), (2, 20), (3, 30), (7, 99)] {
+            let src = format!(
+                "var x = {}; var r = 0;\n\
+                switch (x) {{ case 1: r = 10; case 2: r = 20; case 3: r = 30; default: r = 99; }}",
+                x
+            );
+            let c8 = compile_and_run(&src, 30);
+            assert_eq!(c8.register(1), Some(r), "x = {}", x);
+        }
+    }
+
+    #[test]
+    pub fn test_wait_run() {
+        let mut c8 = compile_and_run("var n = 3; wait(n); n = 7;", 10);
+        for _ in 0..2 {
+            c8.tick_timers();
+            c8.run_for(10);
+            assert_eq!(c8.register(0), Some(3));
+        }
+        c8.tick_timers();
+        c8.run_for(10);
+        assert_eq!(c8.register(0), Some(7));
+    }
+
+    //the program from compiler::tests::test_draw_rand_key_delay_I, run to completion with a
+    //held key, a fixed seed and the timers ticked every 100 instructions
+    fn run_draw_rand_key_delay(seed: u64) -> (Chip8, u32) {
+        let src = "
+        var testvar = 10;
+
+        fn drawrand(times, delay) {
+            I = 20;
+            while(times != 0) {
+               times = times - 1;
+               KEY();
+               DT = delay;
+               while (DT != 0) {}
+               DRAW(RAND(255),RAND(255),5);
+            }
+        }
+        drawrand(testvar, 50);
+        while(1 == 1) {7;}";
+        let (c, mut c8) = compile_and_run_with(src, 0, |_, c8| c8.set_reset_seed(seed));
+        let call_index = c
+            .asm()
+            .iter()
+            .position(|op| matches!(op, Opcode::CALL(_)))
+            .unwrap();
+        let return_addr = asm_bytes_len(call_index + 1);
+        c8.set_key(0x5, 1);
+
+        let mut draws = 0;
+        for _ in 0..1000 {
+            for _ in 0..100 {
+                c8.clock();
+                assert!(c8.error().is_none(), "{:?}", c8.error());
+                if c8.state.opcode & 0xF000 == 0xD000 {
+                    draws += 1;
+                }
+            }
+            c8.tick_timers();
+            //back from drawrand and spinning in the outer loop
+            if c8.sp() == 0 && c8.pc() >= return_addr {
+                //ten 50 frame delays
+                assert!(c8.frame_count() >= 10 * 50);
+                return (c8, draws);
+            }
+        }
+        panic!("drawrand never returned, stuck at {:X}", c8.pc());
+    }
+
+    #[test]
+    pub fn test_draw_rand_key_delay_run() {
+        let (c8, draws) = run_draw_rand_key_delay(1234);
+        assert_eq!(draws, 10);
+        assert!(c8.state.framebuffer.iter().any(|pixel| *pixel != 0));
+
+        let (again, _) = run_draw_rand_key_delay(1234);
+        assert_eq!(again.state.framebuffer, c8.state.framebuffer);
+    }
+
+    #[test]
+    pub fn test_chained_assignment_run() {
+        let c8 = compile_and_run("var a = 1; var b = 2; var c = 3; a = b = c = c + 2;", 20);
+        assert_eq!(c8.register(0), Some(5));
+        assert_eq!(c8.register(1), Some(5));
+        assert_eq!(c8.register(2), Some(5));
+    }
+
+    #[test]
+    pub fn test_main_entry_run() {
+        let src = "fn setup() { var s = 9; } fn main() { var x = 3; setup(); DT = x + 1; }";
+        let (c, mut c8) = compile_and_run_with(src, 0, |c, _| c.set_main_entry(true));
+        let main = match c.asm()[0] {
+            Opcode::CALL(addr) => addr.value(),
+            ref other => panic!("expected a CALL to main, found {}", other),
+        };
+        c8.clock();
+        assert_eq!(c8.pc(), main);
+        for _ in 0..100 {
+            c8.clock();
+        }
+        //main returned to the halt loop after the CALL
+        assert_eq!(c8.pc(), 0x202);
+        assert_eq!(c8.sp(), 0);
+        //main's registers are gone with its frame, but the timer it set isn't
+        assert_eq!(c8.delay_timer(), 4);
+    }
+
+    #[test]
+    pub fn test_boolean_value_run() {
+        let c8 = compile_and_run(
+            "var t = (3 == 3); var f = (3 != 3); var a = 2; var lt = a < 5; \
+            var sum = (a == 2) + (a > 1) + 1; a = slt(200, 10) and a == 2;",
+            60,
+        );
+        assert_eq!(c8.register(0), Some(1));
+        assert_eq!(c8.register(1), Some(0));
+        assert_eq!(c8.register(2), Some(1));
+        assert_eq!(c8.register(3), Some(1));
+        assert_eq!(c8.register(4), Some(3));
+    }
+
+    #[test]
+    pub fn test_discarded_expression_run() {
+        let c8 = compile_and_run("1 + 2; 3 == 4; 5 < 6; var a = 7; var b = a + 1;", 40);
+        assert_eq!(c8.register(0), Some(7));
+        assert_eq!(c8.register(1), Some(8));
+    }
+
+    #[test]
+    pub fn test_grouped_condition_run() {
+        let c8 = compile_and_run("var a = 1; var r = 0; if ((a == 1)) r = 5;", 20);
+        assert_eq!(c8.register(1), Some(5));
+
+        let c8 = compile_and_run(
+            "var a = 3; var n = 0; while ((a != 0)) { a = a - 1; n = n + 1; }",
+            100,
+        );
+        assert_eq!(c8.register(0), Some(0));
+        assert_eq!(c8.register(1), Some(3));
+
+        let c8 = compile_and_run(
+            "var a = 3; var r = (a == 3) or (a == 4); var s = (a == 4) or (a == 5);",
+            40,
+        );
+        assert_eq!(c8.register(1), Some(1));
+        assert_eq!(c8.register(2), Some(0));
+    }
+
+    #[test]
+    pub fn test_function_reads_globals_run() {
+        let src = "var g = 7; var h = 3;\nfn inner() { DT = g + h; }\n\
+            fn outer(b) { var t = 1; inner(); }\nouter(5);";
+        for leaf in [false, true] {
+            let (_, c8) = compile_and_run_with(src, 100, |c, _| c.set_leaf_functions(leaf));
+            assert_eq!(c8.delay_timer(), 10);
+        }
+
+        //a no-argument function reading a global, called straight from top-level code
+        let src = "var g = 4; var n = 0;\nfn show() { DT = g; }\nn = 2; show();";
+        let (_, c8) = compile_and_run_with(src, 100, |c, _| c.set_leaf_functions(true));
+        assert_eq!(c8.delay_timer(), 4);
+    }
+
+    #[test]
+    pub fn test_grouped_condition_operand_run() {
+        let c8 = compile_and_run("var a = 1; var x = 5 + (a == 1); var y = 5 + (a == 2);", 40);
+        assert_eq!(c8.register(1), Some(6));
+        assert_eq!(c8.register(2), Some(5));
+
+        let c8 = compile_and_run("var a = 1; var x = (a == 1) + 5; var y = -(a == 1);", 40);
+        assert_eq!(c8.register(1), Some(6));
+        assert_eq!(c8.register(2), Some(255));
+    }
+
+    #[test]
+    pub fn test_slt_run() {
+        let c8 = compile_and_run(
+            "var r = 0; if (slt(200, 10)) r = r + 1; if (200 < 10) r = r + 10; \
+            if (slt(10, 200)) r = r + 10; if (slt(3, 5)) r = r + 2; if (slt(5, 5)) r = r + 10;",
+            60,
+        );
+        assert_eq!(c8.register(0), Some(3));
+    }
+
+    #[test]
+    pub fn test_comparison_branches() {
+        let c8 = compile_and_run(
+            "var r = 0; if (1 + 2 < 4) r = r + 1; if (4 > 1 + 2) r = r + 1; \
+            if (3 <= 3) r = r + 1; if (3 >= 4) r = r + 10; if (4 < 4) r = r + 10;",
+            40,
+        );
+
+        assert_eq!(c8.state.V[0], 3);
+    }
+
+    #[test]
+    pub fn test_run_function_body_with_injected_args() {
+        //the function body starts at 0x202, just after the JP over it
+        let mut l = Lexer::new("fn double(a, b, c) { c = c + c; } double(1, 2, 3);");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        let mut a = Assembler::new_from_compiler(&c);
+        a.assemble();
+
+        let mut c8 = Chip8::new();
+        c8.load_rom_from_assembler(&a);
+        c8.set_register(2, 7);
+        c8.set_i(0x300);
+        c8.set_pc(0x202);
+        //LD V3, V2; LD V4, V2; ADD V3, V4; LD V2, V3
+        for _ in 0..4 {
+            c8.clock();
+        }
+
+        assert_eq!(c8.state.V[2], 14);
+        assert_eq!(c8.I(), 0x300);
+        assert_eq!(c8.pc(), 0x20A);
+    }
+
+    #[test]
+    #[should_panic(expected = "Writing register out of range")]
+    pub fn test_set_register_out_of_range() {
+        let mut c8 = Chip8::new();
+        c8.set_register(16, 1);
+    }
+
+    #[test]
+    pub fn test_leaf_functions_run() {
+        let sources = [
+            "var a = 5; var b = 0; fn add(x, y) { x = x + y; } add(a, 3); b = a + 1;",
+            //a..k fill V0-VA, reaching add's registers V9-VC, so the call keeps its frame
+            "var a = 5; var b = 0; var c = 3; var d = 4; var e = 5; var f = 6; var g = 7; \
+            var h = 8; var i = 9; var j = 10; var k = 11; fn add(x, y) { x = x + y; } \
+            add(a, 3); b = a + 1;",
+        ];
+        for src in sources.iter() {
+            for leaf in [false, true].iter() {
+                let (_, c8) = compile_and_run_with(src, 100, |c, _| c.set_leaf_functions(*leaf));
+
+                assert_eq!((c8.state.V[0], c8.state.V[1]), (5, 6), "{} {}", src, leaf);
+                assert_eq!(c8.sp(), 0);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_leaf_function_arrays_run() {
+        let src = "var a[4]; var r = 0; var s = 0; \
+            fn f() { var x = a[0]; var y = a[1]; a[2] = 9; } f(); r = a[2];";
+        for checks in [false, true].iter() {
+            let (_, mut c8) = compile_and_run_with(src, 0, |c, _| {
+                c.set_leaf_functions(true);
+                c.set_bounds_checks(*checks);
+            });
+            assert_eq!(c8.run_cycles(1000).stop_reason, StopReason::Halt);
+            assert_eq!(c8.state.V[0], 9, "{}", checks);
+        }
+    }
+
+    #[test]
+    pub fn test_memory_regions() {
+        let mut c8 = Chip8::new();
+        let code: [u8; 6] = [0x60, 0x05, 0x61, 0x06, 0x12, 0x04];
+        c8.load_rom_from_bytes(&code);
+
+        let regions = c8.memory_regions();
+        assert_eq!(regions[2], MemoryRegion::new("program", 0x200, 0x205));
+        assert_eq!(regions[3], MemoryRegion::new("free", 0x206, 0xFFF));
+    }
+
     #[test]
     pub fn test_disasm_1nnn() {
         let mut c8 = Chip8::new();
@@ -1213,6 +4297,109 @@ mod tests {
         assert_eq!("6AD", c8.get_args_disasm_nnn());
     }
 
+    #[test]
+    pub fn test_disasm_nop() {
+        let mut c8 = Chip8::new();
+        c8.disasm_opcode = 0x0000;
+
+        assert_eq!("NOP", c8.opcodes_0_name_lookup());
+    }
+
+    #[test]
+    pub fn test_labelled_disasm() {
+        let mut c8 = Chip8::new();
+        //LD V0, 3; ADD V0, -1; SE V0, 0; JP 202; CALL 20C; RET
+        let code: [u8; 12] = [
+            0x60, 0x03, 0x70, 0xFF, 0x30, 0x00, 0x12, 0x02, 0x22, 0x0C, 0x00, 0xEE,
+        ];
+        c8.load_rom_from_bytes(&code);
+        c8.disassemble();
+
+        assert_eq!(
+            c8.labelled_disasm()[..9],
+            [
+                "0200: LD V0, 3",
+                "L_0202:",
+                "0202: ADD V0, FF",
+                "0204: SE V0, 0",
+                "0206: JP L_0202",
+                "0208: CALL L_020C",
+                "020A: RET",
+                "L_020C:",
+                "020C: NOP",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_disasm_lines() {
+        let mut c8 = Chip8::new();
+        //LD V0, 3; JP 200; RET
+        c8.load_rom_from_bytes(&[0x60, 0x03, 0x12, 0x00, 0x00, 0xEE]);
+        c8.disassemble();
+        let lines = c8.disasm_lines();
+
+        assert_eq!(lines.len(), c8.disasm_map.len());
+        assert!(lines.windows(2).all(|pair| pair[0].addr < pair[1].addr));
+        let program: Vec<(u16, &str)> = lines
+            .iter()
+            .filter(|line| (0x200..0x206).contains(&line.addr))
+            .map(|line| (line.addr, line.text.as_str()))
+            .collect();
+        assert_eq!(
+            program,
+            vec![(0x200, "LD V0, 3"), (0x202, "JP 200"), (0x204, "RET")]
+        );
+    }
+
+    #[test]
+    pub fn test_disasm_self_modified() {
+        let mut c8 = Chip8::new();
+        c8.set_track_writes(true);
+        //LD V0, 61; LD V1, 05; LD I, 20A; LD [I], V1; JP 208; then 0000 overwritten with 6105
+        let code: [u8; 12] = [
+            0x60, 0x61, 0x61, 0x05, 0xA2, 0x0A, 0xF1, 0x55, 0x12, 0x08, 0x00, 0x00,
+        ];
+        c8.load_rom_from_bytes(&code);
+        for _ in 0..5 {
+            c8.clock();
+        }
+        c8.disassemble();
+
+        assert_eq!(c8.disasm_map[&0x20A], "LD V1, 5");
+        let flagged: Vec<u16> = c8
+            .disasm_lines()
+            .iter()
+            .filter(|line| line.self_modified)
+            .map(|line| line.addr)
+            .collect();
+        assert_eq!(flagged, vec![0x20A]);
+    }
+
+    #[test]
+    pub fn test_labelled_disasm_self_modified_jump() {
+        let mut c8 = Chip8::new();
+        c8.set_track_writes(true);
+        //LD V0, 12; LD V1, 08; LD I, 20A; LD [I], V1; then 0000 overwritten with JP 208
+        let code: [u8; 12] = [
+            0x60, 0x12, 0x61, 0x08, 0xA2, 0x0A, 0xF1, 0x55, 0x00, 0xE0, 0x00, 0x00,
+        ];
+        c8.load_rom_from_bytes(&code);
+        for _ in 0..4 {
+            c8.clock();
+        }
+        c8.disassemble();
+
+        let lines = c8.labelled_disasm();
+        assert!(lines.contains(&String::from("L_0208:")));
+        assert!(lines.contains(&String::from("020A: JP L_0208  ⚠ self-modified")));
+        assert!(lines.contains(&String::from("0208: CLS")));
+        assert!(c8
+            .disasm_lines()
+            .iter()
+            .any(|line| line.addr == 0x20A && line.self_modified));
+    }
+
     #[test]
     pub fn test_disasm_xkk() {
         let mut c8 = Chip8::new();
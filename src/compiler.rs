@@ -5,12 +5,14 @@ use crate::utils;
 use wasm_bindgen::prelude::*;
 
 use std::array::IntoIter;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Debug;
 use std::iter::FromIterator;
+use std::ops::Range;
 
 use num_enum::TryFromPrimitive;
+use serde::Serialize;
 use std::convert::TryFrom;
 
 use CompileRuleType::*;
@@ -18,7 +20,7 @@ use Opcode::*;
 
 type CompileFn = fn(&mut Compiler, bool);
 
-#[derive(PartialEq, PartialOrd, TryFromPrimitive)]
+#[derive(PartialEq, PartialOrd, Clone, Copy, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Precedence {
     None,
@@ -26,8 +28,9 @@ pub enum Precedence {
     Or,
     And,
     Equality,
-    Term,   /* + and - */
-    Factor, /* * and / */
+    Comparison, /* < > <= >= */
+    Term,       /* + and - */
+    Factor,     /* * and / */
     Primary,
 }
 
@@ -36,28 +39,195 @@ pub struct Variable {
     name: String,
     reg_index: u16,
     scope_depth: u16,
+    line: u32,
+    read: bool,
 }
 
 impl Variable {
-    pub fn new(name: String, reg_index: u16, scope_depth: u16) -> Variable {
+    pub fn new(name: String, reg_index: u16, scope_depth: u16, line: u32) -> Variable {
         Variable {
             name,
             reg_index,
             scope_depth,
+            line,
+            read: false,
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+pub enum ParseEventKind {
+    Enter,
+    Exit,
+}
+
+//entering or leaving a declaration/statement/expression. start and end are token indices,
+//on Enter both are the first token of the node, on Exit end is the last token consumed
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct ParseEvent {
+    pub kind: ParseEventKind,
+    pub node: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ParseEvent {
+    pub fn new(kind: ParseEventKind, node: &str, start: usize, end: usize) -> ParseEvent {
+        ParseEvent {
+            kind,
+            node: String::from(node),
+            start,
+            end,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymbolKind {
+    Function,
+    Argument,
+    Variable,
+    Array,
+    Data,
+}
+
+//a name declared anywhere in the program, for the editor's autocomplete. line is where it was
+//declared, variables and arguments are only in scope from there to the end of their block.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub line: u32,
+}
+
+impl Symbol {
+    pub fn new(name: &str, kind: SymbolKind, line: u32) -> Symbol {
+        Symbol {
+            name: String::from(name),
+            kind,
+            line,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct CompileWarning {
+    pub line: u32,
+    pub message: String,
+}
+
+impl CompileWarning {
+    pub fn new(line: u32, message: String) -> CompileWarning {
+        CompileWarning { line, message }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct CompileError {
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+    //included source the error is in, None for the main source
+    pub file: Option<String>,
+}
+
+impl CompileError {
+    pub fn new(line: u32, column: u32, message: String) -> CompileError {
+        CompileError {
+            line,
+            column,
+            message,
+            file: None,
+        }
+    }
+
+    //rustc-style diagnostic: the message, a 1-based line:column location, the offending source
+    //line and a caret under the error column
+    pub fn render(&self, src: &str) -> String {
+        let source_line = src.lines().nth(self.line as usize).unwrap_or("");
+        let number = (self.line + 1).to_string();
+        let gutter = " ".repeat(number.len());
+        //keep tabs so the caret lines up with the source line
+        let indent: String = source_line
+            .chars()
+            .take(self.column as usize)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let file = match &self.file {
+            Some(file) => format!("{}:", file),
+            None => String::new(),
+        };
+
+        format!(
+            "error: {}\n{}--> {}{}:{}\n{} |\n{} | {}\n{} | {}^",
+            self.message,
+            gutter,
+            file,
+            number,
+            self.column + 1,
+            gutter,
+            number,
+            source_line,
+            gutter,
+            indent
+        )
+    }
+}
+
+//fixed size byte array stored in the data region
+#[derive(Clone)]
+pub struct Array {
+    //index of the first word in Compiler::data
+    data_index: usize,
+    len: u8,
+}
+
+impl Array {
+    fn words(&self) -> Range<usize> {
+        self.data_index..self.data_index + (self.len as usize).div_ceil(2)
+    }
+}
+
+//where an expression left its result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExpressionValue {
+    //pushed onto the register stack
+    Reg(Reg),
+    //a comparison, which pushes nothing and skips the next instruction when it holds
+    Skip,
+}
+
+//what has been declared so far, restored when the code that declared more is discarded
+struct Declarations {
+    data_len: usize,
+    symbols_len: usize,
+    arrays: HashMap<String, Array>,
+    functions: HashMap<String, Function>,
+}
+
+//a folded constant condition operand, see Compiler::constant_condition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConstantValue {
+    Byte(u8),
+    Bool(bool),
+}
+
+#[derive(Clone)]
 pub struct Function {
-    start_addr: u16,
+    start_addr: Addr,
     args: Vec<String>,
+    //first register of a leaf function compiled without frame management
+    leaf_base: Option<u16>,
 }
 
 impl Function {
-    pub fn new(start_addr: u16) -> Function {
+    pub fn new(start_addr: Addr) -> Function {
         Function {
             start_addr,
             args: Vec::new(),
+            leaf_base: None,
         }
     }
 }
@@ -83,84 +253,306 @@ impl CompileRule {
     }
 }
 
+//register index V0-VF, the x/y fields of an instruction
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Reg(u8);
+
+impl Reg {
+    pub fn new(index: u16) -> Reg {
+        match index {
+            0x0..=0xF => Reg(index as u8),
+            _ => panic!("Register V{:X} out of range", index),
+        }
+    }
+
+    pub fn value(self) -> u16 {
+        self.0 as u16
+    }
+}
+
+//4 bit immediate, the sprite height of DRW
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Nibble(u8);
+
+impl Nibble {
+    pub fn new(value: u16) -> Nibble {
+        match value {
+            0x0..=0xF => Nibble(value as u8),
+            _ => panic!("Nibble {:X} out of range", value),
+        }
+    }
+
+    pub fn value(self) -> u16 {
+        self.0 as u16
+    }
+}
+
+//12 bit address operand of JP, CALL and LD I
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Addr(u16);
+
+impl Addr {
+    pub fn new(addr: u16) -> Addr {
+        match addr {
+            0x000..=0xFFF => Addr(addr),
+            _ => panic!("Address {:X} out of range", addr),
+        }
+    }
+
+    pub fn value(self) -> u16 {
+        self.0
+    }
+}
+
+//frame pointer and scratch register used by function calls
+const FRAME_POINTER: Reg = Reg(0xD);
+const SCRATCH: Reg = Reg(0xE);
+//largest countdown loop bound that set_unroll_loops() will unroll
+const UNROLL_LIMIT: u16 = 4;
+//VF, set by SUB to 1 when there was no borrow
+const FLAG: Reg = Reg(0xF);
+//registers below FRAME_POINTER available to a program
+const PROGRAM_REGS: u16 = 0xD;
+//where top-level code's registers are saved when it calls a function: push_frame stores them
+//at 5 * VD, and VD is still 0 outside functions
+const GLOBALS_FRAME: u16 = 0;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Opcode {
-    LDRegByte(u16, u16),
-    LDRegReg(u16, u16),
-    AddRegReg(u16, u16),
-    SubRegReg(u16, u16),
-    SERegReg(u16, u16),
-    SNERegReg(u16, u16),
-    LDFReg(u16),
-    LDIReg(u16),
-    LDRegI(u16),
-    LDDTReg(u16),
-    LDRegDT(u16),
-    LDSTReg(u16),
-    LDRegKey(u16),
-    LDIAddr(u16),
-    RNDRegByte(u16, u16),
-    DRWRegRegNibble(u16, u16, u16),
-    JP(u16),
-    CALL(u16),
+    LDRegByte(Reg, u8),
+    LDRegReg(Reg, Reg),
+    AddRegReg(Reg, Reg),
+    SubRegReg(Reg, Reg),
+    AndRegReg(Reg, Reg),
+    XorRegReg(Reg, Reg),
+    SERegReg(Reg, Reg),
+    SNERegReg(Reg, Reg),
+    SERegByte(Reg, u8),
+    SNERegByte(Reg, u8),
+    LDFReg(Reg),
+    LDIReg(Reg),
+    LDRegI(Reg),
+    LDDTReg(Reg),
+    LDRegDT(Reg),
+    LDSTReg(Reg),
+    LDRegKey(Reg),
+    SKPReg(Reg),
+    SKNPReg(Reg),
+    LDIAddr(Addr),
+    AddIReg(Reg),
+    RNDRegByte(Reg, u8),
+    DRWRegRegNibble(Reg, Reg, Nibble),
+    JP(Addr),
+    CALL(Addr),
     RET,
+    Nop,
+    //raw data word placed after the code, e.g. a sprite
+    DW(u16),
 }
 
-/*impl fmt::Display for Opcode {
+//mnemonics match the emulator's disassembler output
+impl fmt::Display for Opcode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            LDRegByte(reg, byte) => write!(f, "LD V{}, {}", reg, byte),
-            LDRegReg(reg1, reg2) => write!(f, "LD V{}, V{}", reg1, reg2),
-            AddRegReg(reg1, reg2) => write!(f, "ADD V{}, V{}", reg1, reg2),
-            SubRegReg(reg1, reg2) => write!(f, "SUB V{}, V{}", reg1, reg2),
-            SERegReg(reg1, reg2) => write!(f, "SE V{}, V{}", reg1, reg2),
-            SNERegReg(reg1, reg2) => write!(f, "SNE V{}, V{}", reg1, reg2),
-            LDFReg(reg) => write!(f, "LD F, V{}", reg),
-            LDIReg(reg) => write!(f, "LD [I], V{}", reg),
-            LDRegI(reg) => write!(f, "LD V{}, I[]", reg),
-            JP(addr) => write!(f, "JP {}", addr),
-            CALL(addr) => write!(f, "CALL {}", addr),
+            LDRegByte(reg, byte) => write!(f, "LD V{:X}, {:X}", reg.value(), byte),
+            LDRegReg(reg1, reg2) => write!(f, "LD V{:X}, V{:X}", reg1.value(), reg2.value()),
+            AddRegReg(reg1, reg2) => write!(f, "ADD V{:X}, V{:X}", reg1.value(), reg2.value()),
+            SubRegReg(reg1, reg2) => write!(f, "SUB V{:X}, V{:X}", reg1.value(), reg2.value()),
+            AndRegReg(reg1, reg2) => write!(f, "AND V{:X}, V{:X}", reg1.value(), reg2.value()),
+            XorRegReg(reg1, reg2) => write!(f, "XOR V{:X}, V{:X}", reg1.value(), reg2.value()),
+            SERegReg(reg1, reg2) => write!(f, "SE V{:X}, V{:X}", reg1.value(), reg2.value()),
+            SNERegReg(reg1, reg2) => write!(f, "SNE V{:X}, V{:X}", reg1.value(), reg2.value()),
+            SERegByte(reg, byte) => write!(f, "SE V{:X}, {:X}", reg.value(), byte),
+            SNERegByte(reg, byte) => write!(f, "SNE V{:X}, {:X}", reg.value(), byte),
+            LDFReg(reg) => write!(f, "LD F, V{:X}", reg.value()),
+            LDIReg(reg) => write!(f, "LD [I], V{:X}", reg.value()),
+            LDRegI(reg) => write!(f, "LD V{:X}, [I]", reg.value()),
+            LDDTReg(reg) => write!(f, "LD DT, V{:X}", reg.value()),
+            LDRegDT(reg) => write!(f, "LD V{:X}, DT", reg.value()),
+            LDSTReg(reg) => write!(f, "LD ST, V{:X}", reg.value()),
+            LDRegKey(reg) => write!(f, "LD V{:X}, K", reg.value()),
+            SKPReg(reg) => write!(f, "SKP V{:X}", reg.value()),
+            SKNPReg(reg) => write!(f, "SKNP V{:X}", reg.value()),
+            LDIAddr(addr) => write!(f, "LD I, {:X}", addr.value()),
+            AddIReg(reg) => write!(f, "ADD I, V{:X}", reg.value()),
+            RNDRegByte(reg, byte) => write!(f, "RND V{:X}, {:X}", reg.value(), byte),
+            DRWRegRegNibble(reg1, reg2, nib) => {
+                write!(
+                    f,
+                    "DRW V{:X}, V{:X}, {:X}",
+                    reg1.value(),
+                    reg2.value(),
+                    nib.value()
+                )
+            }
+            JP(addr) => write!(f, "JP {:X}", addr.value()),
+            CALL(addr) => write!(f, "CALL {:X}", addr.value()),
             RET => write!(f, "RET"),
+            Opcode::Nop => write!(f, "NOP"),
+            DW(word) => write!(f, "DW {:04X}", word),
         }
     }
-}*/
-
-impl fmt::Display for Opcode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
-    }
 }
 
 pub fn asm_bytes_len(len: usize) -> u16 {
     (len as u16 * 2) + 0x200
 }
 
+fn octo_label(addr: u16) -> String {
+    format!("L_{:03X}", addr)
+}
+
+//Octo (https://github.com/JohnEarnest/Octo) syntax for a single opcode. Octo's
+//`if .. then` runs the next instruction when the condition holds, so it is the
+//inverse of the skip condition.
+fn octo_statement(opcode: &Opcode) -> String {
+    match opcode {
+        LDRegByte(reg, byte) => format!("v{:x} := {}", reg.value(), byte),
+        LDRegReg(reg1, reg2) => format!("v{:x} := v{:x}", reg1.value(), reg2.value()),
+        AddRegReg(reg1, reg2) => format!("v{:x} += v{:x}", reg1.value(), reg2.value()),
+        SubRegReg(reg1, reg2) => format!("v{:x} -= v{:x}", reg1.value(), reg2.value()),
+        AndRegReg(reg1, reg2) => format!("v{:x} &= v{:x}", reg1.value(), reg2.value()),
+        XorRegReg(reg1, reg2) => format!("v{:x} ^= v{:x}", reg1.value(), reg2.value()),
+        SERegReg(reg1, reg2) => format!("if v{:x} != v{:x} then", reg1.value(), reg2.value()),
+        SNERegReg(reg1, reg2) => format!("if v{:x} == v{:x} then", reg1.value(), reg2.value()),
+        SERegByte(reg, byte) => format!("if v{:x} != {} then", reg.value(), byte),
+        SNERegByte(reg, byte) => format!("if v{:x} == {} then", reg.value(), byte),
+        LDFReg(reg) => format!("i := hex v{:x}", reg.value()),
+        LDIReg(reg) => format!("save v{:x}", reg.value()),
+        LDRegI(reg) => format!("load v{:x}", reg.value()),
+        LDDTReg(reg) => format!("delay := v{:x}", reg.value()),
+        LDRegDT(reg) => format!("v{:x} := delay", reg.value()),
+        LDSTReg(reg) => format!("buzzer := v{:x}", reg.value()),
+        LDRegKey(reg) => format!("v{:x} := key", reg.value()),
+        SKPReg(reg) => format!("if v{:x} -key then", reg.value()),
+        SKNPReg(reg) => format!("if v{:x} key then", reg.value()),
+        LDIAddr(addr) => format!("i := 0x{:03X}", addr.value()),
+        AddIReg(reg) => format!("i += v{:x}", reg.value()),
+        RNDRegByte(reg, byte) => format!("v{:x} := random {}", reg.value(), byte),
+        DRWRegRegNibble(reg1, reg2, nib) => format!(
+            "sprite v{:x} v{:x} {}",
+            reg1.value(),
+            reg2.value(),
+            nib.value()
+        ),
+        JP(addr) => format!("jump {}", octo_label(addr.value())),
+        CALL(addr) => octo_label(addr.value()),
+        RET => String::from("return"),
+        //Octo has no no-op mnemonic, so emit the raw 0000 word
+        Opcode::Nop => String::from("0x00 0x00"),
+        DW(word) => {
+            let bytes = word.to_be_bytes();
+            format!("0x{:02X} 0x{:02X}", bytes[0], bytes[1])
+        }
+    }
+}
+
 #[wasm_bindgen]
 pub struct Compiler {
     tokens: Vec<Token>,
     current: usize,
     previous: usize,
     reg_stack_top: u16,
+    //index into variables of the innermost function's first argument
+    fn_scope_start: usize,
+    //the function body being compiled reads a top-level variable, see load_global
+    reads_globals: bool,
+    //address of the first top-level code that isn't a function body
+    entry_point: Option<u16>,
+    //high-water mark of reg_stack_top, i.e. the most V registers live at once
+    max_reg_stack_top: u16,
     scope_depth: u16,
     variables: Vec<Variable>,
     functions: HashMap<String, Function>,
     asm: Vec<Opcode>,
     ram_line_map: HashMap<u16, u32>,
+    warnings: Vec<CompileWarning>,
+    errors: Vec<CompileError>,
+    //precedence the current prefix rule is being compiled at
+    prefix_precedence: Precedence,
+    //retarget jumps that land on another JP once compilation finishes
+    coalesce_jumps: bool,
+    //replace small constant countdown loops with copies of their body
+    unroll_loops: bool,
+    //compile functions that make no calls into the top registers without a frame
+    leaf_functions: bool,
+    //compile only the taken branch of an if whose condition is made of literals
+    fold_constant_conditions: bool,
+    //start at `fn main()` when the program declares one, rather than at the first top-level
+    //statement
+    main_entry: bool,
+    //asm index of the CALL to main at 0x200, once main_entry has found a main to call
+    call_main: Option<usize>,
+    record_parse_events: bool,
+    parse_events: Vec<ParseEvent>,
+    //every declaration in source order, scoped or not
+    symbols: Vec<Symbol>,
+    //words placed after the code once compilation finishes
+    data: Vec<u16>,
+    //(asm index of an LDIAddr, index into data) pairs patched when the data is placed
+    data_refs: Vec<(usize, usize)>,
+    arrays: HashMap<String, Array>,
+    //set by a syntax error until the parser resynchronises at the next statement
+    panic_mode: bool,
+    //sources the lexer could include, for rendering errors located in them
+    sources: HashMap<String, String>,
+    //check array indices at runtime, jumping to a trap when out of bounds
+    bounds_checks: bool,
+    //asm indices of JPs to the out-of-bounds trap, patched when the data is placed
+    trap_refs: Vec<usize>,
+    //check subtractions at runtime, jumping to the same trap when they borrow
+    underflow_checks: bool,
+    //registers holding a value known at compile time, forgotten at every branch
+    known_bytes: HashMap<u16, u8>,
+    //variables that may be live at once before declaring another is an error
+    max_locals: u16,
 }
 
 #[wasm_bindgen]
 impl Compiler {
     pub fn new_from_lexer(lexer: &Lexer) -> Compiler {
         Compiler {
-            tokens: lexer.tokens().clone(),
+            //layout trivia from lex_with_trivia() means nothing to the parser
+            tokens: lexer
+                .tokens()
+                .iter()
+                .filter(|token| !token.token_type.is_trivia())
+                .cloned()
+                .collect(),
             current: 0,
             previous: 0,
             reg_stack_top: 0,
+            fn_scope_start: 0,
+            reads_globals: false,
+            entry_point: None,
+            max_reg_stack_top: 0,
             scope_depth: 0,
             variables: Vec::new(),
             functions: HashMap::new(),
             asm: Vec::new(),
             ram_line_map: HashMap::new(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            prefix_precedence: Precedence::None,
+            coalesce_jumps: false,
+            unroll_loops: false,
+            leaf_functions: false,
+            fold_constant_conditions: false,
+            main_entry: false,
+            call_main: None,
+            record_parse_events: false,
+            parse_events: Vec::new(),
+            symbols: Vec::new(),
+            data: Vec::new(),
+            data_refs: Vec::new(),
+            arrays: HashMap::new(),
+            panic_mode: false,
+            sources: lexer.sources().clone(),
+            bounds_checks: false,
+            trap_refs: Vec::new(),
+            underflow_checks: false,
+            known_bytes: HashMap::new(),
+            max_locals: PROGRAM_REGS,
         }
     }
 
@@ -168,16 +560,105 @@ impl Compiler {
         return JsValue::from_serde(&self.ram_line_map).unwrap();
     }
 
+    pub fn warnings_serialised(&self) -> JsValue {
+        JsValue::from_serde(&self.warnings).unwrap()
+    }
+
+    pub fn errors_serialised(&self) -> JsValue {
+        JsValue::from_serde(&self.errors).unwrap()
+    }
+
+    pub fn render_errors(&self, src: &str) -> String {
+        self.errors
+            .iter()
+            .map(|error| match &error.file {
+                Some(file) => error.render(self.sources.get(file).map_or("", String::as_str)),
+                None => error.render(src),
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n")
+    }
+
+    pub fn set_record_parse_events(&mut self, record: bool) {
+        self.record_parse_events = record;
+    }
+
+    pub fn parse_events(&self) -> JsValue {
+        JsValue::from_serde(&self.parse_events).unwrap()
+    }
+
+    //JSON serialised Symbols
+    pub fn symbols(&self) -> JsValue {
+        JsValue::from_serde(&self.symbols).unwrap()
+    }
+
+    pub fn set_coalesce_jumps(&mut self, coalesce: bool) {
+        self.coalesce_jumps = coalesce;
+    }
+
+    pub fn set_unroll_loops(&mut self, unroll: bool) {
+        self.unroll_loops = unroll;
+    }
+
+    pub fn set_leaf_functions(&mut self, leaf: bool) {
+        self.leaf_functions = leaf;
+    }
+
+    pub fn set_fold_constant_conditions(&mut self, fold: bool) {
+        self.fold_constant_conditions = fold;
+    }
+
+    pub fn set_main_entry(&mut self, main_entry: bool) {
+        self.main_entry = main_entry;
+    }
+
+    pub fn set_bounds_checks(&mut self, checks: bool) {
+        self.bounds_checks = checks;
+    }
+
+    pub fn set_underflow_checks(&mut self, checks: bool) {
+        self.underflow_checks = checks;
+    }
+
+    //a soft limit below the 13 registers the program has, e.g. to leave room for temporaries
+    pub fn set_max_locals(&mut self, max: u16) {
+        self.max_locals = max.min(PROGRAM_REGS);
+    }
+
+    //VD-VF are reserved for the frame pointer, scratch and flags, leaving 13 for the program
+    pub fn max_register_pressure(&self) -> u16 {
+        self.max_reg_stack_top
+    }
+
+    //where execution reaches the program's own code, past the JPs over any function bodies.
+    //Programs with only functions run off the end of the code.
+    pub fn entry_point(&self) -> u16 {
+        self.entry_point.unwrap_or(0x200)
+    }
+
+    pub fn had_error(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
     fn get_rule(&self, token: &Token) -> CompileRule {
         match token.token_type() {
-            Plus | Minus => CompileRule::new(
+            Plus => CompileRule::new(
                 Precedence::Term,
                 Infix {
                     infix: Compiler::binary,
                 },
             ),
-            Equals | Semicolon | RightParen | Comma => CompileRule::new(Precedence::None, Neither),
-            Number(_) => CompileRule::new(
+            Minus => CompileRule::new(
+                Precedence::Term,
+                PrefixAndInfix {
+                    prefix: Compiler::unary,
+                    infix: Compiler::binary,
+                },
+            ),
+            Equals | Semicolon | RightParen | Comma | RightBracket => {
+                CompileRule::new(Precedence::None, Neither)
+            }
+            Number(_) | NumberOutOfRange(_) => CompileRule::new(
                 Precedence::None,
                 Prefix {
                     prefix: Compiler::number,
@@ -195,6 +676,12 @@ impl Compiler {
                     infix: Compiler::binary,
                 },
             ),
+            Less | Greater | LessEquals | GreaterEquals => CompileRule::new(
+                Precedence::Comparison,
+                Infix {
+                    infix: Compiler::binary,
+                },
+            ),
             And => CompileRule::new(
                 Precedence::And,
                 Infix {
@@ -237,47 +724,176 @@ impl Compiler {
                     prefix: Compiler::key,
                 },
             ),
-            _ => panic!(
-                "cant find rule for {} in get_rule()",
-                token.token_type().to_string()
+            LeftParen => CompileRule::new(
+                Precedence::None,
+                Prefix {
+                    prefix: Compiler::grouping,
+                },
             ),
+            IsPressed => CompileRule::new(
+                Precedence::None,
+                Prefix {
+                    prefix: Compiler::is_pressed,
+                },
+            ),
+            Slt => CompileRule::new(
+                Precedence::None,
+                Prefix {
+                    prefix: Compiler::slt,
+                },
+            ),
+            _ => CompileRule::new(Precedence::None, Neither),
         }
     }
 
-    fn compile_precedence(&mut self, precedence: Precedence) {
+    //a comparison operand is turned into 1 or 0 before any operator but `and`/`or` uses it
+    fn compile_precedence(&mut self, precedence: Precedence) -> ExpressionValue {
+        let reg_stack_top = self.reg_stack_top;
         self.advance();
         let assign_allowed = precedence <= Precedence::Assignment;
 
         let prev = self.tokens[self.previous].clone();
+        self.prefix_precedence = precedence;
 
         match self.get_rule(&prev).rule_type {
             Prefix { prefix } => prefix(self, assign_allowed),
             PrefixAndInfix { prefix, .. } => prefix(self, assign_allowed),
-            _ => panic!(
-                "no prefix rule in compile_precedence() for {}",
-                prev.token_type()
-            ),
+            _ => {
+                self.syntax_error_at(
+                    self.previous,
+                    format!("expected an expression, found {}", prev.token_type()),
+                );
+                //stand in for the missing value so the register stack stays balanced
+                self.inc_reg_stack_top();
+            }
         }
 
         while precedence <= self.get_rule(&self.tokens[self.current]).precedence {
             self.advance();
+            let operator = self.tokens[self.previous].token_type();
+            if !matches!(operator, And | Or) && self.reg_stack_top == reg_stack_top {
+                self.skip_to_value();
+            }
             match self.get_rule(&self.tokens[self.previous]).rule_type {
                 Infix { infix } => infix(self, assign_allowed),
                 PrefixAndInfix { prefix, infix } => infix(self, assign_allowed),
                 _ => (),
             }
         }
+
+        match self.reg_stack_top > reg_stack_top {
+            true => ExpressionValue::Reg(self.peek_reg_stack(0)),
+            false => ExpressionValue::Skip,
+        }
+    }
+
+    fn error(&mut self, message: String) {
+        self.error_at(self.previous, message);
+    }
+
+    //errors that follow a syntax error in the same statement are usually knock-on effects
+    fn error_at(&mut self, token_index: usize, message: String) {
+        if self.panic_mode {
+            return;
+        }
+        let token = &self.tokens[token_index];
+        let mut error = CompileError::new(token.line, token.column, message);
+        error.file = token.file.clone();
+        self.errors.push(error);
+    }
+
+    //reports malformed source and skips to the end of the statement once it has been parsed
+    fn syntax_error_at(&mut self, token_index: usize, message: String) {
+        self.error_at(token_index, message);
+        self.panic_mode = true;
+    }
+
+    //discards tokens up to the next statement boundary after a syntax error
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while !self.check(EndOfFile) {
+            if self.tokens[self.previous].token_type() == Semicolon {
+                return;
+            }
+            match self.tokens[self.current].token_type() {
+                RightBrace | LeftBrace | Var | Data | Fn | If | While | Switch | Draw | HLine
+                | VLine | Wait => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    //registers are 8 bits wide, so negative literals are stored as their two's complement
+    fn byte_immediate(&mut self, value: i32) -> u8 {
+        match value {
+            -128..=-1 => (256 + value) as u8,
+            0..=255 => value as u8,
+            _ => {
+                self.error(format!(
+                    "number {} is out of range for a byte (-128 to 255)",
+                    value
+                ));
+                0
+            }
+        }
     }
 
     fn emit(&mut self, opcode: Opcode) {
-        let line = self.tokens[self.previous].line;
-        self.ram_line_map
-            .insert(asm_bytes_len(self.asm.len()), line);
+        //lines in included sources would point at the wrong text in the editor
+        let token = &self.tokens[self.previous];
+        if token.file.is_none() {
+            self.ram_line_map
+                .insert(asm_bytes_len(self.asm.len()), token.line);
+        }
+        self.track_known_bytes(&opcode);
         self.asm.push(opcode);
     }
 
+    //follows literal loads through copies in straight-line code. Anything that branches or
+    //calls could be a join point, so everything is forgotten there, as it is where a forward
+    //jump lands (see patch_jump_here).
+    fn track_known_bytes(&mut self, opcode: &Opcode) {
+        match opcode {
+            LDRegByte(x, byte) => {
+                self.known_bytes.insert(x.value(), *byte);
+            }
+            LDRegReg(x, y) => match self.known_bytes.get(&y.value()).copied() {
+                Some(byte) => {
+                    self.known_bytes.insert(x.value(), byte);
+                }
+                None => {
+                    self.known_bytes.remove(&x.value());
+                }
+            },
+            AddRegReg(x, _)
+            | SubRegReg(x, _)
+            | AndRegReg(x, _)
+            | XorRegReg(x, _)
+            | LDRegDT(x)
+            | LDRegKey(x)
+            | RNDRegByte(x, _) => {
+                self.known_bytes.remove(&x.value());
+                self.known_bytes.remove(&FLAG.value());
+            }
+            LDRegI(x) => self.known_bytes.retain(|reg, _| *reg > x.value()),
+            DRWRegRegNibble(..) => {
+                self.known_bytes.remove(&FLAG.value());
+            }
+            SERegReg(..) | SNERegReg(..) | SERegByte(..) | SNERegByte(..) | SKPReg(_)
+            | SKNPReg(_) | JP(_) | CALL(_) | RET => self.known_bytes.clear(),
+            _ => {}
+        }
+    }
+
+    //points the forward JP at asm_index to the next instruction, which can then be reached by
+    //falling through or by the jump, so no register is known there any more
+    fn patch_jump_here(&mut self, asm_index: usize) {
+        self.asm[asm_index] = JP(Addr::new(asm_bytes_len(self.asm.len())));
+        self.known_bytes.clear();
+    }
+
     pub fn lookup_variable_register(&self, name: String) -> Option<u16> {
-        for var in self.variables.iter().rev() {
+        for var in self.variables[self.fn_scope_start..].iter().rev() {
             if var.name == name {
                 return Some(var.reg_index);
             }
@@ -285,10 +901,80 @@ impl Compiler {
         return None;
     }
 
+    //register of a variable being read or assigned, reporting an error if it isn't visible.
+    //Function bodies only see their own arguments and locals, plus reads of top-level
+    //variables through load_global: everything else declared outside lives in registers the
+    //callee reuses for its own values.
+    fn variable_reg(&mut self, name: &str, token_index: usize) -> Option<Reg> {
+        let reg = self
+            .lookup_variable_register(name.to_string())
+            .map(Reg::new);
+        if reg.is_none() {
+            let message = match self.variables.iter().any(|var| var.name == name) {
+                true if self.global_register(name).is_some() => format!(
+                    "`{}` is a top-level variable, functions can only read it",
+                    name
+                ),
+                true => format!(
+                    "`{}` is declared outside this function, pass it as an argument instead",
+                    name
+                ),
+                false => format!("`{}` is not declared", name),
+            };
+            self.error_at(token_index, message);
+        }
+        reg
+    }
+
+    //register a top-level variable had when top-level code called into the current function
+    fn global_register(&self, name: &str) -> Option<u16> {
+        self.variables[..self.fn_scope_start]
+            .iter()
+            .rev()
+            .find(|var| var.name == name && var.scope_depth == 0)
+            .map(|var| var.reg_index)
+    }
+
+    //pushes a top-level variable's value, read from the frame top-level code saved when it
+    //called in. Like array reads this moves I. The function can't be a leaf, whose frameless
+    //calls would leave the saved copy stale.
+    fn load_global(&mut self, reg: u16) {
+        let result = self.top_reg();
+        let v0 = Reg::new(0);
+        self.emit(LDIAddr(Addr::new(GLOBALS_FRAME + reg)));
+        if result == v0 {
+            self.emit(LDRegI(v0));
+        } else {
+            self.emit(LDRegReg(SCRATCH, v0));
+            self.emit(LDRegI(v0));
+            self.emit(LDRegReg(result, v0));
+            self.emit(LDRegReg(v0, SCRATCH));
+        }
+        self.reads_globals = true;
+    }
+
+    fn mark_variable_read(&mut self, name: &str) {
+        if let Some(var) = self.variables.iter_mut().rev().find(|var| var.name == name) {
+            var.read = true;
+        }
+    }
+
+    fn warn_if_unread(&mut self, var: &Variable) {
+        if !var.read {
+            self.warnings.push(CompileWarning::new(
+                var.line,
+                format!("variable {} is declared but never read", var.name),
+            ));
+        }
+    }
+
+    //frees the registers of the scope's variables. Scopes nest, so they're always on top of the
+    //register stack.
     pub fn clear_current_scope(&mut self) {
         for i in (0..self.variables.len()).rev() {
             if self.variables[i].scope_depth == self.scope_depth {
-                self.variables.remove(i);
+                let var = self.variables.remove(i);
+                self.warn_if_unread(&var);
                 self.reg_stack_top -= 1;
             }
         }
@@ -302,22 +988,66 @@ impl Compiler {
             .join("\n")
     }
 
+    pub fn stringify_octo(&self) -> String {
+        let targets = self
+            .asm
+            .iter()
+            .filter_map(|op| match op {
+                JP(addr) | CALL(addr) => Some(addr.value()),
+                _ => None,
+            })
+            .collect::<std::collections::BTreeSet<u16>>();
+
+        let mut lines = vec![String::from(": main")];
+        for i in 0..=self.asm.len() {
+            let addr = asm_bytes_len(i);
+            if targets.contains(&addr) {
+                lines.push(format!(": {}", octo_label(addr)));
+            }
+            if let Some(op) = self.asm.get(i) {
+                lines.push(format!("\t{}", octo_statement(op)));
+            }
+        }
+
+        lines.join("\n")
+    }
+
+    //anything pushed past VC would land in the frame pointer, scratch or flags register
     pub fn inc_reg_stack_top(&mut self) {
         self.reg_stack_top += 1;
+        self.max_reg_stack_top = self.max_reg_stack_top.max(self.reg_stack_top);
+        if self.reg_stack_top > PROGRAM_REGS {
+            let line = self.tokens[self.previous].line;
+            self.syntax_error_at(
+                self.previous,
+                format!(
+                    "register exhaustion at line {}: expression needs more than {} registers",
+                    line + 1,
+                    PROGRAM_REGS
+                ),
+            );
+        }
     }
 
     pub fn dec_reg_stack_top(&mut self) {
         self.reg_stack_top -= 1;
     }
 
-    fn peek_reg_stack(&self, depth: u16) -> u16 {
-        self.reg_stack_top - 1 - depth
+    fn peek_reg_stack(&self, depth: u16) -> Reg {
+        Reg::new(self.reg_stack_top - 1 - depth)
+    }
+
+    fn top_reg(&self) -> Reg {
+        Reg::new(self.reg_stack_top)
     }
 
+    //stops on the trailing EndOfFile, so truncated source reads as a missing token
     fn advance(&mut self) {
         self.previous = self.current;
 
-        self.current += 1;
+        if self.current + 1 < self.tokens.len() {
+            self.current += 1;
+        }
     }
 
     fn check(&self, token: TokenType) -> bool {
@@ -328,89 +1058,273 @@ impl Compiler {
         let cur = self.tokens[self.current].clone().token_type();
         match cur == token {
             true => self.advance(),
-            false => panic!(
-                "token {} didn't match in consume(), found {} instead",
-                token.to_string(),
-                cur.to_string()
-            ),
+            false => {
+                self.syntax_error_at(self.current, format!("expected {}, found {}", token, cur))
+            }
         }
     }
 
     pub fn compile(&mut self) {
+        self.emit_main_entry();
+
         while !self.check(EndOfFile) {
             //self.advance();
             self.declaration();
         }
+
+        if let Some(asm_index) = self.call_main {
+            match self.functions.get("main") {
+                Some(main) => self.asm[asm_index] = CALL(main.start_addr),
+                //declared only in a branch that was folded away
+                None => self.error_at(self.current, String::from("`main` is not declared")),
+            }
+        }
+
+        if self.entry_point.is_none() {
+            self.entry_point = Some(asm_bytes_len(self.asm.len()));
+        }
+
+        for var in self.variables.clone().iter() {
+            self.warn_if_unread(var);
+        }
+
+        self.place_data();
+
+        if self.coalesce_jumps {
+            self.coalesce_jump_chains();
+        }
+    }
+
+    //emits LD I pointing at the given data words, reusing an identical run already in the data
+    //region. The address is a placeholder until place_data() runs.
+    fn emit_load_data(&mut self, words: &[u16]) {
+        //arrays are writable so constant data never shares their words
+        let arrays: Vec<Range<usize>> = self.arrays.values().map(Array::words).collect();
+        let index = match self
+            .data
+            .windows(words.len())
+            .enumerate()
+            .position(|(start, run)| {
+                run == words
+                    && arrays
+                        .iter()
+                        .all(|array| start + words.len() <= array.start || start >= array.end)
+            }) {
+            Some(index) => index,
+            None => {
+                self.data.extend_from_slice(words);
+                self.data.len() - words.len()
+            }
+        };
+        self.emit_load_data_at(index);
+    }
+
+    fn emit_load_data_at(&mut self, data_index: usize) {
+        self.data_refs.push((self.asm.len(), data_index));
+        self.emit(LDIAddr(Addr::new(0)));
+    }
+
+    //appends the data region after a jump-to-self that stops execution falling into it, and
+    //the out-of-bounds trap if anything jumps to it
+    fn place_data(&mut self) {
+        if self.data.is_empty() && self.trap_refs.is_empty() {
+            return;
+        }
+
+        let halt = asm_bytes_len(self.asm.len());
+        self.emit(JP(Addr::new(halt)));
+        if !self.trap_refs.is_empty() {
+            let trap = Addr::new(asm_bytes_len(self.asm.len()));
+            self.emit(JP(trap));
+            for asm_index in self.trap_refs.iter() {
+                self.asm[*asm_index] = JP(trap);
+            }
+        }
+        let data_start = self.asm.len();
+        for (asm_index, data_index) in self.data_refs.iter() {
+            self.asm[*asm_index] = LDIAddr(Addr::new(asm_bytes_len(data_start + data_index)));
+        }
+        for word in self.data.clone() {
+            self.asm.push(DW(word));
+        }
+    }
+
+    //points every JP whose target is itself a JP at the end of the chain. Instructions are only
+    //retargeted, never removed, so addresses and ram_line_map stay valid. A chain that loops
+    //back on itself stops at the first repeated address.
+    fn coalesce_jump_chains(&mut self) {
+        for i in 0..self.asm.len() {
+            if let JP(mut target) = self.asm[i] {
+                let mut visited = HashSet::new();
+                while visited.insert(target.value()) {
+                    match self.opcode_at(target.value()) {
+                        Some(JP(next)) => target = *next,
+                        _ => break,
+                    }
+                }
+                self.asm[i] = JP(target);
+            }
+        }
+    }
+
+    fn opcode_at(&self, addr: u16) -> Option<&Opcode> {
+        match addr.checked_sub(0x200) {
+            Some(offset) if offset % 2 == 0 => self.asm.get(offset as usize / 2),
+            _ => None,
+        }
+    }
+
+    //with set_main_entry() and a `fn main()` in the program, 0x200 calls main and then halts,
+    //and everything at the top level has to be a definition. The CALL is patched once main has
+    //been compiled.
+    fn emit_main_entry(&mut self) {
+        let declares_main = self.tokens.windows(2).any(|pair| {
+            pair[0].token_type == Fn && pair[1].token_type == Identifier(String::from("main"))
+        });
+        if !self.main_entry || !declares_main {
+            return;
+        }
+
+        self.entry_point = Some(asm_bytes_len(0));
+        self.call_main = Some(self.asm.len());
+        self.asm.push(CALL(Addr::new(0)));
+        //main returning leaves nothing to run
+        self.asm.push(JP(Addr::new(asm_bytes_len(1))));
     }
 
     pub fn declaration(&mut self) {
+        let start = self.enter_node("declaration");
+        if self.scope_depth == 0 && !self.check(Fn) && self.entry_point.is_none() {
+            self.entry_point = Some(asm_bytes_len(self.asm.len()));
+        }
+        if self.scope_depth == 0 && self.call_main.is_some() && !self.check(Fn) && !self.check(Data)
+        {
+            self.error_at(
+                self.current,
+                String::from(
+                    "only definitions are allowed outside functions when `main` is the entry point",
+                ),
+            );
+        }
+
         if self.check(Fn) {
             self.advance();
             self.fn_declaration();
         } else if self.check(Var) {
             self.advance();
             self.var_declaration();
+        } else if self.check(Data) {
+            self.advance();
+            self.data_declaration();
         } else {
             self.statement();
         }
+        if self.panic_mode {
+            self.synchronize();
+        }
+        self.exit_node("declaration", start);
+    }
+
+    fn enter_node(&mut self, node: &str) -> usize {
+        if self.record_parse_events {
+            self.parse_events.push(ParseEvent::new(
+                ParseEventKind::Enter,
+                node,
+                self.current,
+                self.current,
+            ));
+        }
+        self.current
+    }
+
+    fn exit_node(&mut self, node: &str, start: usize) {
+        if self.record_parse_events {
+            self.parse_events.push(ParseEvent::new(
+                ParseEventKind::Exit,
+                node,
+                start,
+                self.previous,
+            ));
+        }
     }
 
     pub fn fn_declaration(&mut self) {
         let mut cur_arg_assigned_reg = 0;
         let mut has_args = false;
         let mut fn_name = String::from("");
-        match self.tokens[self.current].clone().token_type {
-            Identifier(name) => {
-                self.advance();
+        match self.declared_name() {
+            Some(name) => {
                 fn_name = name.clone();
+                self.declare_symbol(&name, SymbolKind::Function);
                 self.functions.insert(
                     name.clone(),
-                    Function::new(asm_bytes_len(self.asm.len()) + 2),
+                    Function::new(Addr::new(asm_bytes_len(self.asm.len()) + 2)),
                 );
             }
-            _ => panic!("identifier name must follow fn keyword"),
+            None => {
+                self.syntax_error_at(self.current, String::from("expected a function name"));
+                return;
+            }
         }
 
+        //the body only sees variables from its arguments onwards
+        let fn_scope_start_backup = self.fn_scope_start;
+        self.fn_scope_start = self.variables.len();
+
         self.consume(LeftParen);
         if !self.check(RightParen) {
-            self.advance();
             has_args = true;
-            match self.tokens[self.previous].clone().token_type() {
-                Identifier(name) => {
+            match self.declared_name() {
+                Some(name) => {
                     self.functions
                         .get_mut(&fn_name)
                         .expect(&format!("function {} not found", &fn_name))
                         .args
                         .push(name.clone());
+                    self.declare_symbol(&name, SymbolKind::Argument);
                     self.variables.push(Variable::new(
                         name.clone(),
                         cur_arg_assigned_reg,
-                        self.scope_depth,
+                        self.scope_depth + 1,
+                        self.tokens[self.previous].line,
                     ));
                 }
-                _ => panic!("non-identifier matched while parsing function args"),
+                None => {
+                    self.syntax_error_at(self.current, String::from("expected a parameter name"))
+                }
             }
             while self.check(Comma) {
                 cur_arg_assigned_reg += 1;
                 self.advance();
-                self.advance();
-                match self.tokens[self.previous].clone().token_type() {
-                    Identifier(name) => {
+                match self.declared_name() {
+                    Some(name) => {
                         self.functions
                             .get_mut(&fn_name)
                             .expect(&format!("function {} not found", &fn_name))
                             .args
                             .push(name.clone());
+                        self.declare_symbol(&name, SymbolKind::Argument);
                         self.variables.push(Variable::new(
                             name.clone(),
                             cur_arg_assigned_reg,
-                            self.scope_depth,
+                            self.scope_depth + 1,
+                            self.tokens[self.previous].line,
                         ));
                     }
-                    _ => panic!("non-identifier matched while parsing function args"),
+                    None => {
+                        self.syntax_error_at(
+                            self.current,
+                            String::from("expected a parameter name"),
+                        );
+                        break;
+                    }
                 }
             }
         }
+        //the CALL at 0x200 passes nothing
+        if has_args && self.call_main.is_some() && fn_name == "main" {
+            self.error_at(self.current, String::from("`main` can't take arguments"));
+        }
 
         self.consume(RightParen);
         self.consume(LeftBrace);
@@ -422,55 +1336,184 @@ impl Compiler {
             true => self.reg_stack_top = cur_arg_assigned_reg + 1,
             false => self.reg_stack_top = cur_arg_assigned_reg,
         }
+        self.max_reg_stack_top = self.max_reg_stack_top.max(self.reg_stack_top);
 
         let jp_over_fn_asm_index = self.asm.len();
-        self.emit(JP(0));
+        self.emit(JP(Addr::new(0)));
+
+        let body_start = self.current;
+        let diagnostics = (
+            self.errors.len(),
+            self.warnings.len(),
+            self.parse_events.len(),
+        );
+        //declarations in the body are made again by the recompile
+        let declarations = self.declarations();
+        let max_reg_stack_top_backup = self.max_reg_stack_top;
+        self.max_reg_stack_top = self.reg_stack_top;
+        let reads_globals_backup = self.reads_globals;
+        self.reads_globals = false;
         self.block();
-        self.pop_frame();
+        let pressure = self.max_reg_stack_top;
+        self.max_reg_stack_top = self.max_reg_stack_top.max(max_reg_stack_top_backup);
+        let reads_globals = self.reads_globals;
+        self.reads_globals = reads_globals_backup;
 
-        self.asm[jp_over_fn_asm_index] = JP(asm_bytes_len(self.asm.len()));
+        let makes_calls = self.asm[jp_over_fn_asm_index..]
+            .iter()
+            .any(|op| matches!(op, CALL(_)));
+        if self.leaf_functions && !makes_calls && !reads_globals && pressure <= PROGRAM_REGS {
+            self.discard_asm_from(jp_over_fn_asm_index + 1);
+            self.restore_declarations(declarations);
+            self.errors.truncate(diagnostics.0);
+            self.warnings.truncate(diagnostics.1);
+            self.parse_events.truncate(diagnostics.2);
+            self.recompile_as_leaf(&fn_name, body_start, pressure);
+            //the moved registers aren't extra pressure
+            self.max_reg_stack_top = pressure.max(max_reg_stack_top_backup);
+            self.emit(RET);
+        } else {
+            self.pop_frame();
+        }
+
+        self.patch_jump_here(jp_over_fn_asm_index);
 
         self.clear_current_scope();
         self.scope_depth -= 1;
+        self.fn_scope_start = fn_scope_start_backup;
 
         self.reg_stack_top = reg_stack_top_backup;
     }
 
+    //compiles a function body again with its registers moved to the top of the program's
+    //registers, so callers whose live registers all sit below can skip the frame. The caller
+    //has already discarded the first compilation, its diagnostics and its declarations.
+    fn recompile_as_leaf(&mut self, fn_name: &str, body_start: usize, pressure: u16) {
+        let base = PROGRAM_REGS - pressure;
+        let function = self.functions.get_mut(fn_name).unwrap();
+        function.leaf_base = Some(base);
+        let num_args = function.args.len();
+
+        //the arguments are the first variables in the function's scope
+        self.variables.truncate(self.fn_scope_start + num_args);
+        for (i, arg) in self.variables[self.fn_scope_start..].iter_mut().enumerate() {
+            arg.reg_index = base + i as u16;
+            arg.read = false;
+        }
+        self.reg_stack_top = base + num_args as u16;
+
+        self.previous = body_start - 1;
+        self.current = body_start;
+        self.block();
+    }
+
+    //records a name just read by declared_name()
+    fn declare_symbol(&mut self, name: &str, kind: SymbolKind) {
+        let line = self.tokens[self.previous].line;
+        self.symbols.push(Symbol::new(name, kind, line));
+    }
+
+    //name introduced by a var, fn or argument declaration. Built-in register and function
+    //keywords are reported as errors but still returned so the declaration parses as usual.
+    fn declared_name(&mut self) -> Option<String> {
+        let name = match self.tokens[self.current].token_type() {
+            Identifier(name) => name,
+            DT => String::from("DT"),
+            ST => String::from("ST"),
+            I => String::from("I"),
+            Rand => String::from("RAND"),
+            Draw => String::from("DRAW"),
+            Key => String::from("KEY"),
+            HLine => String::from("hline"),
+            VLine => String::from("vline"),
+            IsPressed => String::from("is_pressed"),
+            Slt => String::from("slt"),
+            Wait => String::from("wait"),
+            _ => return None,
+        };
+        self.advance();
+
+        match self.tokens[self.previous].token_type() {
+            DT | ST | I => self.error(format!("`{}` is a reserved CHIP-8 register name", name)),
+            Rand | Draw | Key | HLine | VLine | IsPressed | Slt | Wait => {
+                self.error(format!("`{}` is a reserved built-in function name", name))
+            }
+            _ => (),
+        }
+        Some(name)
+    }
+
     pub fn push_frame(&mut self) {
-        self.emit(LDFReg(0xD));
-        self.emit(LDIReg(0xD));
-        self.emit(LDRegByte(0xE, 3));
-        self.emit(AddRegReg(0xD, 0xE));
+        self.emit(LDFReg(FRAME_POINTER));
+        self.emit(LDIReg(FRAME_POINTER));
+        self.emit(LDRegByte(SCRATCH, 3));
+        self.emit(AddRegReg(FRAME_POINTER, SCRATCH));
     }
 
     pub fn pop_frame(&mut self) {
-        self.emit(LDRegByte(0xE, 3));
-        self.emit(SubRegReg(0xD, 0xE));
-        //self.emit(LDRegReg(0xF, self.reg_stack_top));
-        self.emit(LDFReg(0xD));
-        self.emit(LDRegI(0xD));
-        //self.emit(LDRegReg(self.reg_stack_top, 0xF));
+        self.restore_frame();
         self.emit(RET);
     }
 
+    //reloads the registers saved by push_frame
+    fn restore_frame(&mut self) {
+        self.emit(LDRegByte(SCRATCH, 3));
+        self.emit(SubRegReg(FRAME_POINTER, SCRATCH));
+        //self.emit(LDRegReg(0xF, self.reg_stack_top));
+        self.emit(LDFReg(FRAME_POINTER));
+        self.emit(LDRegI(FRAME_POINTER));
+        //self.emit(LDRegReg(self.top_reg(), 0xF));
+    }
+
     pub fn var_declaration(&mut self) {
-        match self.tokens[self.current].clone().token_type() {
-            Identifier(name) => {
-                self.advance();
-                self.variables.push(Variable::new(
-                    name.clone(),
-                    self.reg_stack_top,
-                    self.scope_depth,
-                ));
-                match self.tokens[self.current].clone().token_type() {
+        match self.declared_name() {
+            Some(name) if self.check(LeftBracket) => {
+                self.array_declaration(name);
+                return;
+            }
+            Some(name) => {
+                self.declare_symbol(&name, SymbolKind::Variable);
+                let name_index = self.previous;
+                let line = self.tokens[name_index].line;
+                //reported ahead of the initialiser, which would otherwise push into VD itself
+                let exhausted = self.reg_stack_top >= self.max_locals;
+                if exhausted {
+                    self.syntax_error_at(
+                        name_index,
+                        format!(
+                            "register exhaustion at line {}: too many simultaneous locals (max {})",
+                            line + 1,
+                            self.max_locals
+                        ),
+                    );
+                }
+                let value = match self.tokens[self.current].clone().token_type() {
                     Equals => {
                         self.advance();
-                        self.expression()
+                        self.value_expression()
                     }
-                    _ => panic!("initialiser must be present in variable declaration"),
+                    _ => {
+                        self.syntax_error_at(
+                            self.current,
+                            format!("expected `=` and an initialiser for `{}`", name),
+                        );
+                        return;
+                    }
+                };
+                if exhausted {
+                    self.dec_reg_stack_top();
+                    self.consume(Semicolon);
+                    return;
                 }
+                //the initialiser's register on top of the stack becomes the variable's
+                let reg_index = value.value();
+                self.variables
+                    .push(Variable::new(name, reg_index, self.scope_depth, line));
+            }
+            None => {
+                self.syntax_error_at(self.current, String::from("expected a variable name"));
+                return;
             }
-            _ => panic!("identifier must follow after var keyword"),
         }
 
         if self.check(Equals) {
@@ -481,7 +1524,83 @@ impl Compiler {
         self.consume(Semicolon);
     }
 
+    //`var name[len];` reserves len zeroed bytes in the data region, 1 to 255 so any index fits
+    //in a register
+    fn array_declaration(&mut self, name: String) {
+        self.declare_symbol(&name, SymbolKind::Array);
+        self.consume(LeftBracket);
+        let len = match self.tokens[self.current].token_type() {
+            Number(len @ 1..=0xFF) => len,
+            _ => {
+                self.error_at(
+                    self.current,
+                    String::from("array length must be a number literal from 1 to 255"),
+                );
+                1
+            }
+        };
+        self.advance();
+        self.consume(RightBracket);
+        self.consume(Semicolon);
+
+        let array = Array {
+            data_index: self.data.len(),
+            len: len as u8,
+        };
+        self.data.resize(array.words().end, 0);
+        self.arrays.insert(name, array);
+    }
+
+    //`data name = [b0, b1, ...];` places the bytes in the data region as an array, so the table
+    //can be indexed like one and its address loaded with `I = name;`
+    fn data_declaration(&mut self) {
+        let name = match self.declared_name() {
+            Some(name) => name,
+            None => {
+                self.syntax_error_at(self.current, String::from("expected a data label"));
+                return;
+            }
+        };
+        self.declare_symbol(&name, SymbolKind::Data);
+        self.consume(Equals);
+        self.consume(LeftBracket);
+        let mut bytes = Vec::new();
+        while !self.check(RightBracket) && !self.check(EndOfFile) {
+            match self.tokens[self.current].token_type() {
+                Number(byte @ 0..=0xFF) => bytes.push(byte as u8),
+                _ => self.error_at(
+                    self.current,
+                    String::from("data bytes must be number literals from 0 to 255"),
+                ),
+            }
+            self.advance();
+            if !self.check(RightBracket) {
+                self.consume(Comma);
+            }
+        }
+        self.consume(RightBracket);
+        self.consume(Semicolon);
+
+        if bytes.is_empty() || bytes.len() > 0xFF {
+            self.error_at(
+                self.previous,
+                format!("`{}` must hold from 1 to 255 bytes", name),
+            );
+            return;
+        }
+        let array = Array {
+            data_index: self.data.len(),
+            len: bytes.len() as u8,
+        };
+        for pair in bytes.chunks(2) {
+            let low = pair.get(1).copied().unwrap_or(0);
+            self.data.push(((pair[0] as u16) << 8) | low as u16);
+        }
+        self.arrays.insert(name, array);
+    }
+
     fn statement(&mut self) {
+        let start = self.enter_node("statement");
         if self.check(LeftBrace) {
             self.advance();
             self.scope_depth += 1;
@@ -495,12 +1614,48 @@ impl Compiler {
         } else if self.check(While) {
             self.advance();
             self.while_statement();
+        } else if self.check(Switch) {
+            self.advance();
+            self.switch_statement();
         } else if self.check(Draw) {
             self.advance();
             self.draw_statement();
+        } else if self.check(HLine) {
+            self.advance();
+            self.line_statement(true);
+        } else if self.check(VLine) {
+            self.advance();
+            self.line_statement(false);
+        } else if self.check(Wait) {
+            self.advance();
+            self.wait_statement();
+        } else if self.check(TokenType::Nop) {
+            self.advance();
+            self.consume(Semicolon);
+            self.emit(Opcode::Nop);
+        } else if self.check(Include) {
+            //the lexer splices every well-formed include it can resolve
+            self.advance();
+            let include_index = self.previous;
+            let name = self.tokens[self.current].token_type();
+            self.advance();
+            if !self.check(Semicolon) {
+                self.syntax_error_at(self.current, String::from("expected `;` after include"));
+            } else {
+                self.advance();
+                let message = match name {
+                    Str(name) if self.sources.contains_key(&name) => {
+                        format!("cannot include `{}`: it includes itself", name)
+                    }
+                    Str(name) => format!("cannot include `{}`: no such source", name),
+                    _ => String::from("expected a source name in quotes after `include`"),
+                };
+                self.error_at(include_index, message);
+            }
         } else {
             self.expression_statement();
         }
+        self.exit_node("statement", start);
     }
 
     fn block(&mut self) {
@@ -511,141 +1666,809 @@ impl Compiler {
         self.consume(RightBrace);
     }
 
+    //true if the parenthesised condition starting at the current token reads no variables,
+    //registers, keys or random numbers, so it is always or never taken
+    fn condition_is_constant(&self) -> bool {
+        let mut depth = 0;
+        for token in self.tokens[self.current..].iter() {
+            match token.token_type {
+                LeftParen => depth += 1,
+                RightParen if depth == 0 => break,
+                RightParen => depth -= 1,
+                Identifier(_) | DT | ST | I | Rand | Key => return false,
+                EndOfFile => break,
+                _ => (),
+            }
+        }
+        true
+    }
+
+    fn warn_if_constant_condition(&mut self) {
+        if self.condition_is_constant() {
+            let line = self.tokens[self.current].line;
+            self.warnings.push(CompileWarning::new(
+                line,
+                String::from("condition is constant"),
+            ));
+        }
+    }
+
+    //folds a condition made only of number literals the way the generated code would evaluate
+    //it: 8 bit wrapping arithmetic and unsigned comparisons. Returns the outcome and the index
+    //of the closing paren, or None for anything else, including conditions that read state or
+    //have side effects, which still have to be compiled.
+    fn constant_condition(&self) -> Option<(bool, usize)> {
+        let mut pos = self.current;
+        match self.fold_or(&mut pos)? {
+            ConstantValue::Bool(taken) if self.tokens[pos].token_type == RightParen => {
+                Some((taken, pos))
+            }
+            _ => None,
+        }
+    }
+
+    fn fold_or(&self, pos: &mut usize) -> Option<ConstantValue> {
+        let mut lhs = self.fold_and(pos)?;
+        while self.tokens[*pos].token_type == Or {
+            *pos += 1;
+            lhs = match (lhs, self.fold_and(pos)?) {
+                (ConstantValue::Bool(a), ConstantValue::Bool(b)) => ConstantValue::Bool(a || b),
+                _ => return None,
+            };
+        }
+        Some(lhs)
+    }
+
+    fn fold_and(&self, pos: &mut usize) -> Option<ConstantValue> {
+        let mut lhs = self.fold_comparison(pos)?;
+        while self.tokens[*pos].token_type == And {
+            *pos += 1;
+            lhs = match (lhs, self.fold_comparison(pos)?) {
+                (ConstantValue::Bool(a), ConstantValue::Bool(b)) => ConstantValue::Bool(a && b),
+                _ => return None,
+            };
+        }
+        Some(lhs)
+    }
+
+    //equality and ordering together, as comparing a comparison is never constant anyway
+    fn fold_comparison(&self, pos: &mut usize) -> Option<ConstantValue> {
+        let mut lhs = self.fold_term(pos)?;
+        loop {
+            let op = self.tokens[*pos].token_type();
+            if !matches!(
+                op,
+                EqualsEquals | NotEquals | Less | Greater | LessEquals | GreaterEquals
+            ) {
+                return Some(lhs);
+            }
+            *pos += 1;
+            let (a, b) = match (lhs, self.fold_term(pos)?) {
+                (ConstantValue::Byte(a), ConstantValue::Byte(b)) => (a, b),
+                _ => return None,
+            };
+            lhs = ConstantValue::Bool(match op {
+                EqualsEquals => a == b,
+                NotEquals => a != b,
+                Less => a < b,
+                Greater => a > b,
+                LessEquals => a <= b,
+                _ => a >= b,
+            });
+        }
+    }
+
+    fn fold_term(&self, pos: &mut usize) -> Option<ConstantValue> {
+        let mut lhs = self.fold_unary(pos)?;
+        loop {
+            let op = self.tokens[*pos].token_type();
+            if !matches!(op, Plus | Minus) {
+                return Some(lhs);
+            }
+            *pos += 1;
+            let (a, b) = match (lhs, self.fold_unary(pos)?) {
+                (ConstantValue::Byte(a), ConstantValue::Byte(b)) => (a, b),
+                _ => return None,
+            };
+            lhs = ConstantValue::Byte(match op {
+                Plus => a.wrapping_add(b),
+                _ => a.wrapping_sub(b),
+            });
+        }
+    }
+
+    fn fold_unary(&self, pos: &mut usize) -> Option<ConstantValue> {
+        match self.tokens[*pos].token_type() {
+            //a negative literal has to fit byte_immediate's -128 lower bound
+            Minus => match self.tokens[*pos + 1].token_type() {
+                Number(num) if num <= 128 => {
+                    *pos += 2;
+                    Some(ConstantValue::Byte((num as u8).wrapping_neg()))
+                }
+                Number(_) => None,
+                _ => {
+                    *pos += 1;
+                    match self.fold_primary(pos)? {
+                        ConstantValue::Byte(value) => {
+                            Some(ConstantValue::Byte(value.wrapping_neg()))
+                        }
+                        ConstantValue::Bool(_) => None,
+                    }
+                }
+            },
+            _ => self.fold_primary(pos),
+        }
+    }
+
+    fn fold_primary(&self, pos: &mut usize) -> Option<ConstantValue> {
+        match self.tokens[*pos].token_type() {
+            Number(num) if num <= 255 => {
+                *pos += 1;
+                Some(ConstantValue::Byte(num as u8))
+            }
+            LeftParen => {
+                *pos += 1;
+                let value = self.fold_or(pos)?;
+                if self.tokens[*pos].token_type != RightParen {
+                    return None;
+                }
+                *pos += 1;
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+
+    //drops the code emitted from asm_index on, along with everything that refers into it
+    fn discard_asm_from(&mut self, asm_index: usize) {
+        self.asm.truncate(asm_index);
+        let end = asm_bytes_len(asm_index);
+        self.ram_line_map.retain(|addr, _| *addr < end);
+        self.data_refs.retain(|(index, _)| *index < asm_index);
+        self.trap_refs.retain(|index| *index < asm_index);
+        self.known_bytes.clear();
+    }
+
+    fn declarations(&self) -> Declarations {
+        Declarations {
+            data_len: self.data.len(),
+            symbols_len: self.symbols.len(),
+            arrays: self.arrays.clone(),
+            functions: self.functions.clone(),
+        }
+    }
+
+    //forgets functions, arrays, data and symbols declared since, along with their code
+    fn restore_declarations(&mut self, declarations: Declarations) {
+        self.data.truncate(declarations.data_len);
+        self.symbols.truncate(declarations.symbols_len);
+        self.arrays = declarations.arrays;
+        self.functions = declarations.functions;
+    }
+
     fn if_statement(&mut self) {
         self.consume(LeftParen);
+        self.warn_if_constant_condition();
+        let folded = match self.fold_constant_conditions {
+            true => self.constant_condition(),
+            false => None,
+        };
+        if let Some((taken, right_paren)) = folded {
+            self.previous = right_paren - 1;
+            self.current = right_paren;
+            self.consume(RightParen);
+            return self.constant_if_statement(taken);
+        }
         self.expression();
         self.consume(RightParen);
 
         let jp_asm_index = self.asm.len();
-        self.emit(JP(0));
+        self.emit(JP(Addr::new(0)));
         self.statement();
 
         if self.check(Else) {
-            self.asm[jp_asm_index] = JP(asm_bytes_len(self.asm.len()) + 2);
+            self.asm[jp_asm_index] = JP(Addr::new(asm_bytes_len(self.asm.len()) + 2));
             self.advance();
             let jp_asm_index = self.asm.len();
-            self.emit(JP(0));
+            self.emit(JP(Addr::new(0)));
             self.statement();
-            self.asm[jp_asm_index] = JP(asm_bytes_len(self.asm.len()));
+            self.patch_jump_here(jp_asm_index);
         } else {
-            self.asm[jp_asm_index] = JP(asm_bytes_len(self.asm.len()));
+            self.patch_jump_here(jp_asm_index);
+        }
+    }
+
+    //both branches are still compiled for their diagnostics, but only the taken one keeps its
+    //code and declarations, so no comparison or jumps are emitted. A function declared in the
+    //other branch is gone along with its body.
+    fn constant_if_statement(&mut self, taken: bool) {
+        let then_asm_index = self.asm.len();
+        let declarations = self.declarations();
+        self.statement();
+        if !taken {
+            self.discard_asm_from(then_asm_index);
+            self.restore_declarations(declarations);
+        }
+
+        if self.check(Else) {
+            self.advance();
+            let else_asm_index = self.asm.len();
+            let declarations = self.declarations();
+            self.statement();
+            if taken {
+                self.discard_asm_from(else_asm_index);
+                self.restore_declarations(declarations);
+            }
+        }
+    }
+
+    //`switch (x) { case 1: ... case 2: ... default: ... }` tests each case in turn with an SE
+    //that skips the JP to the next case on a match. Every case body ends with a jump past the
+    //switch, so cases never fall through. default, if present, must come last.
+    fn switch_statement(&mut self) {
+        self.consume(LeftParen);
+        let value = self.value_expression();
+        self.consume(RightParen);
+        self.consume(LeftBrace);
+
+        let mut jp_end_asm_indices = Vec::new();
+        let mut seen_default = false;
+        while !self.check(RightBrace) && !self.check(EndOfFile) {
+            if seen_default {
+                self.error_at(
+                    self.current,
+                    String::from("`default` must be the last case of a switch"),
+                );
+            }
+            let jp_next_case_asm_index = match self.tokens[self.current].token_type() {
+                Case => {
+                    self.advance();
+                    let byte = match self.tokens[self.current].token_type() {
+                        Number(num) => self.byte_immediate(num as i32),
+                        _ => {
+                            self.error_at(
+                                self.current,
+                                String::from("case values must be number literals"),
+                            );
+                            0
+                        }
+                    };
+                    self.advance();
+                    self.emit(SERegByte(value, byte));
+                    self.emit(JP(Addr::new(0)));
+                    Some(self.asm.len() - 1)
+                }
+                Default => {
+                    self.advance();
+                    seen_default = true;
+                    None
+                }
+                _ => {
+                    self.syntax_error_at(
+                        self.current,
+                        String::from("expected `case` or `default`"),
+                    );
+                    break;
+                }
+            };
+            self.consume(Colon);
+
+            self.scope_depth += 1;
+            while !self.check(Case)
+                && !self.check(Default)
+                && !self.check(RightBrace)
+                && !self.check(EndOfFile)
+            {
+                self.declaration();
+            }
+            self.clear_current_scope();
+            self.scope_depth -= 1;
+
+            jp_end_asm_indices.push(self.asm.len());
+            self.emit(JP(Addr::new(0)));
+            if let Some(asm_index) = jp_next_case_asm_index {
+                self.patch_jump_here(asm_index);
+            }
         }
+        self.consume(RightBrace);
+
+        for asm_index in jp_end_asm_indices {
+            self.patch_jump_here(asm_index);
+        }
+        self.dec_reg_stack_top();
     }
 
     fn while_statement(&mut self) {
+        if self.unroll_loops {
+            if let Some((name, count, body_start, decrement_start)) = self.unrollable_countdown() {
+                self.unroll_countdown(name, count, body_start, decrement_start);
+                return;
+            }
+        }
+
         let while_start = asm_bytes_len(self.asm.len());
+        //the body jumps back here with whatever it left in the registers
+        self.known_bytes.clear();
 
         self.consume(LeftParen);
+        self.warn_if_constant_condition();
         self.expression();
         self.consume(RightParen);
 
         //jump to after loop if condition not met
         let jp_condition_not_met_asm_index = self.asm.len();
-        self.emit(JP(0));
+        self.emit(JP(Addr::new(0)));
         self.statement();
 
         //jump back to start of while loop to retest condition
         let jp_loop_asm = self.asm.len();
-        self.emit(JP(0));
-        self.asm[jp_loop_asm] = JP(while_start as u16);
+        self.emit(JP(Addr::new(0)));
+        self.asm[jp_loop_asm] = JP(Addr::new(while_start));
+
+        self.patch_jump_here(jp_condition_not_met_asm_index);
+    }
+
+    //matches `var i = N; while (i != 0) { body i = i - 1; }` with 1 <= N <= UNROLL_LIMIT, where
+    //body never mentions i or declares a function. The current token is the `(` after `while`.
+    //Returns the counter name, N and the token indices of the body and of the final decrement.
+    fn unrollable_countdown(&self) -> Option<(String, u16, usize, usize)> {
+        let while_index = self.previous;
+        let name = match self.tokens.get(self.current + 1)?.token_type() {
+            Identifier(name) => name,
+            _ => return None,
+        };
+        let counter = Identifier(name.clone());
+
+        //None if the source ends first
+        let types = |start: usize, len: usize| -> Option<Vec<TokenType>> {
+            let tokens = self.tokens.get(start..start + len)?;
+            Some(tokens.iter().map(|token| token.token_type()).collect())
+        };
+
+        let count = match while_index.checked_sub(5).and_then(|start| types(start, 5)) {
+            Some(prelude) => match prelude.as_slice() {
+                [Var, ident, Equals, Number(count), Semicolon] if *ident == counter => *count,
+                _ => return None,
+            },
+            None => return None,
+        };
+        if count == 0 || count > UNROLL_LIMIT {
+            return None;
+        }
+
+        let condition = [
+            LeftParen,
+            counter.clone(),
+            NotEquals,
+            Number(0),
+            RightParen,
+            LeftBrace,
+        ];
+        if types(self.current, condition.len())? != condition {
+            return None;
+        }
+
+        let body_start = self.current + condition.len();
+        let mut depth = 1;
+        let mut body_end = body_start;
+        while depth > 0 {
+            match self.tokens[body_end].token_type() {
+                LeftBrace => depth += 1,
+                RightBrace => depth -= 1,
+                EndOfFile => return None,
+                _ => (),
+            }
+            body_end += 1;
+        }
+
+        let decrement = [
+            counter.clone(),
+            Equals,
+            counter.clone(),
+            Minus,
+            Number(1),
+            Semicolon,
+            RightBrace,
+        ];
+        let decrement_start = body_end.checked_sub(decrement.len())?;
+        if decrement_start < body_start || types(decrement_start, decrement.len())? != decrement {
+            return None;
+        }
+
+        let body_is_independent = self.tokens[body_start..decrement_start]
+            .iter()
+            .all(|token| token.token_type != counter && token.token_type != Fn);
+        match body_is_independent {
+            true => Some((name, count, body_start, decrement_start)),
+            false => None,
+        }
+    }
+
+    //compiles the body count times in place of the loop. The counter's initial load is
+    //dropped when nothing after the loop reads it, otherwise it's left at 0 as the loop would
+    //have. Diagnostics from the body are kept from the first copy only.
+    fn unroll_countdown(
+        &mut self,
+        name: String,
+        count: u16,
+        body_start: usize,
+        decrement_start: usize,
+    ) {
+        let reg = self.lookup_variable_register(name.clone()).map(Reg::new);
+        let loop_end = decrement_start + 7;
+        //`var i = N;` has just been compiled, so its load is the last instruction
+        let counter_dropped = match reg {
+            Some(reg)
+                if !self.read_before_scope_end(&name, loop_end)
+                    && self.asm.last() == Some(&LDRegByte(reg, count as u8)) =>
+            {
+                self.discard_asm_from(self.asm.len() - 1);
+                true
+            }
+            _ => false,
+        };
+
+        let mut diagnostics = None;
+        for _ in 0..count {
+            self.previous = body_start - 1;
+            self.current = body_start;
+            self.scope_depth += 1;
+            while self.current < decrement_start {
+                self.declaration();
+            }
+            self.clear_current_scope();
+            self.scope_depth -= 1;
+
+            match diagnostics {
+                None => {
+                    diagnostics = Some((
+                        self.errors.len(),
+                        self.warnings.len(),
+                        self.parse_events.len(),
+                    ))
+                }
+                Some((errors, warnings, parse_events)) => {
+                    self.errors.truncate(errors);
+                    self.warnings.truncate(warnings);
+                    self.parse_events.truncate(parse_events);
+                }
+            }
+        }
+
+        //skip `i = i - 1; }`
+        self.previous = loop_end - 1;
+        self.current = loop_end;
+
+        match reg {
+            Some(reg) if !counter_dropped => self.emit(LDRegByte(reg, 0)),
+            _ => (),
+        }
+        self.mark_variable_read(&name);
+    }
 
-        self.asm[jp_condition_not_met_asm_index] = JP(asm_bytes_len(self.asm.len()));
+    //whether name appears from token start to the end of the enclosing block
+    fn read_before_scope_end(&self, name: &str, start: usize) -> bool {
+        let mut depth = 0;
+        for token in self.tokens[start..].iter() {
+            match token.token_type() {
+                Identifier(ident) if ident == name => return true,
+                LeftBrace => depth += 1,
+                RightBrace if depth == 0 => return false,
+                RightBrace => depth -= 1,
+                _ => (),
+            }
+        }
+        false
     }
 
+    //DRW encodes the sprite height in the opcode's low nibble, so it can't come from a register
     fn draw_statement(&mut self) {
         self.consume(LeftParen);
-        self.expression();
+        let x = self.value_expression();
         self.consume(Comma);
-        self.expression();
+        let y = self.value_expression();
         self.consume(Comma);
-        match self.tokens[self.current].token_type() {
-            Number(num) => {
+
+        let height_index = self.current;
+        let height = match self.tokens[height_index].token_type() {
+            Number(num) if self.tokens[height_index + 1].token_type() == RightParen => {
                 self.advance();
-                self.consume(RightParen);
-                self.emit(DRWRegRegNibble(self.peek_reg_stack(1), self.peek_reg_stack(0), num.clone()));
-                self.dec_reg_stack_top();
+                Some(num)
+            }
+            _ => {
+                //still compile the expression so parsing carries on after the error
+                self.value_expression();
                 self.dec_reg_stack_top();
+                None
+            }
+        };
+        self.consume(RightParen);
+
+        match height {
+            Some(num) if num <= 0xF => {
+                self.emit(DRWRegRegNibble(x, y, Nibble::new(num)));
             }
-            _ => panic!("number literal param must be passed to rand() to AND result with (variable/expression cannot be used)")
+            Some(num) => self.error_at(
+                height_index,
+                format!("sprite height {} is out of range (0 to 15)", num),
+            ),
+            None => self.error_at(
+                height_index,
+                String::from("sprite height must be a number literal"),
+            ),
         }
+        self.dec_reg_stack_top();
+        self.dec_reg_stack_top();
         self.consume(Semicolon);
     }
 
-    fn expression_statement(&mut self) {
-        self.expression();
+    //hline(x, y, len) / vline(x, y, len): draws len single pixels with a 1x1 sprite, stepping x
+    //or y each time. The arguments' registers are used as the loop counters.
+    fn line_statement(&mut self, horizontal: bool) {
+        self.consume(LeftParen);
+        let x = self.value_expression();
+        self.consume(Comma);
+        let y = self.value_expression();
+        self.consume(Comma);
+        let len = self.value_expression();
+        self.consume(RightParen);
+        self.consume(Semicolon);
+
+        self.emit_load_data(&[0x8000]);
+
+        //exit the loop once len reaches 0
+        let loop_start = asm_bytes_len(self.asm.len());
+        self.emit(SNERegByte(len, 0));
+        let jp_end_asm_index = self.asm.len();
+        self.emit(JP(Addr::new(0)));
+        self.emit(DRWRegRegNibble(x, y, Nibble::new(1)));
+        self.emit(LDRegByte(SCRATCH, 1));
+        self.emit(AddRegReg(if horizontal { x } else { y }, SCRATCH));
+        self.emit(SubRegReg(len, SCRATCH));
+        self.emit(JP(Addr::new(loop_start)));
+        self.patch_jump_here(jp_end_asm_index);
+
+        self.dec_reg_stack_top();
+        self.dec_reg_stack_top();
+        self.dec_reg_stack_top();
+    }
+
+    //`wait(frames);` is `DT = frames; while (DT != 0) {}`, polling DT through SCRATCH
+    fn wait_statement(&mut self) {
+        self.consume(LeftParen);
+        let frames = self.value_expression();
+        self.consume(RightParen);
         self.consume(Semicolon);
+
+        self.emit(LDDTReg(frames));
+        let loop_start = asm_bytes_len(self.asm.len());
+        self.emit(LDRegDT(SCRATCH));
+        self.emit(SERegByte(SCRATCH, 0));
+        self.emit(JP(Addr::new(loop_start)));
+
         self.dec_reg_stack_top();
     }
 
-    fn expression(&mut self) {
-        self.compile_precedence(Precedence::Assignment);
+    //values and assignments leave a register on the stack but comparisons leave none, so pop
+    //back to the height before the expression rather than assuming one result. A comparison
+    //also ends in a skip, which would otherwise skip the next statement's first instruction.
+    fn expression_statement(&mut self) {
+        let reg_stack_top = self.reg_stack_top;
+        let value = self.expression();
+        self.consume(Semicolon);
+
+        while self.reg_stack_top > reg_stack_top {
+            self.dec_reg_stack_top();
+        }
+        if value == ExpressionValue::Skip {
+            self.emit(Opcode::Nop);
+        }
+    }
+
+    fn expression(&mut self) -> ExpressionValue {
+        let start = self.enter_node("expression");
+        let value = self.compile_precedence(Precedence::Assignment);
+        self.exit_node("expression", start);
+        value
+    }
+
+    //an expression whose result must end up in a register. A comparison is turned into 1 or 0
+    //on the stack.
+    fn value_expression(&mut self) -> Reg {
+        let value = self.expression();
+        self.value_reg(value)
+    }
+
+    fn value_reg(&mut self, value: ExpressionValue) -> Reg {
+        match value {
+            ExpressionValue::Reg(reg) => reg,
+            ExpressionValue::Skip => self.skip_to_value(),
+        }
+    }
+
+    //pushes 1 if the comparison just compiled skips, else 0
+    fn skip_to_value(&mut self) -> Reg {
+        let result = self.top_reg();
+        let jp_false_asm_index = self.asm.len();
+        self.emit(JP(Addr::new(0)));
+        self.emit(LDRegByte(result, 1));
+        let jp_end_asm_index = self.asm.len();
+        self.emit(JP(Addr::new(0)));
+        self.patch_jump_here(jp_false_asm_index);
+        self.emit(LDRegByte(result, 0));
+        self.patch_jump_here(jp_end_asm_index);
+        self.inc_reg_stack_top();
+        result
+    }
+
+    //`(expr)`. A comparison stays a skip, so `if ((a == 1))` and `(a == 3) or (a == 4)` still
+    //branch on it; operators and value contexts that need it turn it into 1 or 0.
+    fn grouping(&mut self, assign_allowed: bool) {
+        self.expression();
+        self.consume(RightParen);
     }
 
     fn number(&mut self, assign_allowed: bool) {
         //self.inc_reg_stack_top();
         let prev = self.tokens[self.previous].clone().token_type();
         match prev {
-            Number(num) => self.emit(LDRegByte(self.reg_stack_top, num.clone())),
+            Number(num) => {
+                if self.variable_comparison(Number(num)) {
+                    return;
+                }
+                let byte = self.byte_immediate(num as i32);
+                self.emit(LDRegByte(self.top_reg(), byte));
+            }
+            //the lexer keeps the digits of a literal too big for 16 bits
+            NumberOutOfRange(digits) => {
+                self.error(format!("number {} is out of range (0 to 65535)", digits));
+            }
             _ => panic!("non number matched in number()"),
         }
         self.inc_reg_stack_top();
     }
 
-    fn variable(&mut self, assign_allowed: bool) {
-        let prev = self.tokens[self.previous].clone().token_type();
+    fn unary(&mut self, _assign_allowed: bool) {
+        match self.tokens[self.current].token_type() {
+            Number(num) => {
+                self.advance();
+                let byte = self.byte_immediate(-(num as i32));
+                self.emit(LDRegByte(self.top_reg(), byte));
+                self.inc_reg_stack_top();
+            }
+            _ => {
+                //negate a non-literal operand by subtracting it from 0
+                self.emit(LDRegByte(self.top_reg(), 0));
+                self.inc_reg_stack_top();
+                let operand = self.compile_precedence(Precedence::Primary);
+                let operand = self.value_reg(operand);
+                self.emit(SubRegReg(self.peek_reg_stack(1), operand));
+                self.dec_reg_stack_top();
+            }
+        }
+    }
+
+    fn variable(&mut self, assign_allowed: bool) {
+        let name_index = self.previous;
+        let prev = self.tokens[self.previous].clone().token_type();
         let cur = self.tokens[self.current].clone().token_type();
 
         match prev {
             Identifier(name) => match cur {
+                LeftBracket => self.array_access(&name, assign_allowed),
+                //`a + b = 5` would otherwise assign to b
+                Equals if !assign_allowed => {
+                    self.syntax_error_at(self.current, String::from("invalid assignment target"));
+                }
+                //leaves the assigned value on the stack, so `a = b = 5` chains
                 Equals => {
                     self.advance();
-                    self.expression();
-                    self.emit(LDRegReg(
-                        self.lookup_variable_register(name.clone())
-                            .expect(format!("variable {} not found", &name.clone()).as_str()),
-                        self.peek_reg_stack(0),
-                    ));
+                    let value = self.value_expression();
+                    if let Some(reg) = self.variable_reg(&name, name_index) {
+                        self.emit(LDRegReg(reg, value));
+                    }
                     self.dec_reg_stack_top();
                 }
                 LeftParen => {
                     //maybe instead call parse precedence here and go thru that way??
                     self.advance();
 
-                    self.push_frame();
+                    let (num_args, leaf_base) = match self.functions.get(&name.clone()) {
+                        Some(function) => (function.args.len() as u16, function.leaf_base),
+                        None => {
+                            self.error_at(name_index, format!("`{}` is not declared", name));
+                            let mut supplied = 0;
+                            while !self.check(RightParen) && !self.check(EndOfFile) {
+                                self.value_expression();
+                                supplied += 1;
+                                if !self.check(Comma) {
+                                    break;
+                                }
+                                self.advance();
+                            }
+                            self.reg_stack_top -= supplied;
+                            self.consume(RightParen);
+                            self.inc_reg_stack_top();
+                            return;
+                        }
+                    };
+                    //a leaf function only touches registers from leaf_base up, so the frame can
+                    //be skipped when everything live here, and the arguments, sit below that
+                    let frameless = match leaf_base {
+                        Some(base) => self.reg_stack_top + num_args <= base,
+                        None => false,
+                    };
+                    if !frameless {
+                        self.push_frame();
+                    }
 
+                    let mut supplied = 0;
                     if !self.check(RightParen) {
-                        self.expression();
+                        self.value_expression();
+                        supplied += 1;
                         while self.check(Comma) {
                             self.advance();
-                            self.expression();
+                            self.value_expression();
+                            supplied += 1;
                         }
                     }
+                    if supplied != num_args {
+                        self.error_at(
+                            name_index,
+                            format!(
+                                "`{}` expects {} argument{}, found {}",
+                                name,
+                                num_args,
+                                if num_args == 1 { "" } else { "s" },
+                                supplied
+                            ),
+                        );
+                        self.reg_stack_top -= supplied;
+                        self.consume(RightParen);
+                        self.inc_reg_stack_top();
+                        return;
+                    }
 
-                    let num_args = self
-                        .functions
-                        .get(&name.clone())
-                        .expect(format!("function {} not found", &name.clone()).as_str())
-                        .args
-                        .len();
-                    for i in 0..num_args {
-                        self.emit(LDRegReg(
-                            i as u16,
-                            (self.reg_stack_top - num_args as u16) + i as u16,
-                        ))
+                    let arg_base = leaf_base.unwrap_or(0);
+                    let first_arg = self.reg_stack_top - num_args;
+                    //copy upwards in reverse so no argument is overwritten before it's read
+                    let mut copies: Vec<u16> = (0..num_args).collect();
+                    if arg_base > first_arg {
+                        copies.reverse();
+                    }
+                    for i in copies {
+                        self.emit(LDRegReg(Reg::new(arg_base + i), Reg::new(first_arg + i)))
                     }
 
-                    self.reg_stack_top -= num_args as u16;
+                    self.reg_stack_top -= num_args;
 
                     self.consume(RightParen);
 
                     self.emit(CALL(self.functions.get(&name.clone()).unwrap().start_addr));
+
+                    //leaf functions return without restoring the frame, so do it here instead
+                    if leaf_base.is_some() && !frameless {
+                        self.restore_frame();
+                    }
                 }
                 _ => {
-                    self.emit(LDRegReg(
-                        self.reg_stack_top,
-                        self.lookup_variable_register(name.clone())
-                            .expect(format!("variable {} not found", &name.clone()).as_str()),
-                    ));
+                    if self.variable_comparison(Identifier(name.clone())) {
+                        return;
+                    }
+
+                    let global = match self.lookup_variable_register(name.clone()) {
+                        Some(_) => None,
+                        None => self.global_register(&name),
+                    };
+                    match global {
+                        Some(reg) => self.load_global(reg),
+                        None => {
+                            if let Some(reg) = self.variable_reg(&name, name_index) {
+                                self.emit(LDRegReg(self.top_reg(), reg));
+                            }
+                        }
+                    }
+                    self.mark_variable_read(&name);
                 }
             },
             _ => {
@@ -656,6 +2479,129 @@ impl Compiler {
         self.inc_reg_stack_top();
     }
 
+    //`name[index]` reads or, followed by `=`, writes one byte. I is pointed at the element with
+    //LD I / ADD I, and Fx65/Fx55 with x = 0 move the byte through V0, which is saved in SCRATCH
+    //if it holds something else. Leaves one register on the stack like a variable read.
+    fn array_access(&mut self, name: &str, assign_allowed: bool) {
+        let name_index = self.previous;
+        self.advance();
+        let index = self.value_expression();
+        self.consume(RightBracket);
+
+        let array = match self.arrays.get(name) {
+            Some(array) => Some((array.data_index, array.len)),
+            None => {
+                self.error_at(name_index, format!("`{}` is not an array", name));
+                None
+            }
+        };
+        if let Some((_, len)) = array {
+            if self.bounds_checks {
+                self.emit_bounds_check(index, len);
+            }
+        }
+
+        let write = assign_allowed && self.check(Equals);
+        let value = match write {
+            true => {
+                self.advance();
+                Some(self.value_expression())
+            }
+            false => None,
+        };
+        if let Some((data_index, _)) = array {
+            self.emit_load_data_at(data_index);
+            self.emit(AddIReg(index));
+            let v0 = Reg::new(0);
+            match value {
+                Some(value) => {
+                    self.emit(LDRegReg(SCRATCH, v0));
+                    self.emit(LDRegReg(v0, value));
+                    self.emit(LDIReg(v0));
+                    self.emit(LDRegReg(v0, SCRATCH));
+                }
+                None if index == v0 => self.emit(LDRegI(v0)),
+                None => {
+                    self.emit(LDRegReg(SCRATCH, v0));
+                    self.emit(LDRegI(v0));
+                    self.emit(LDRegReg(index, v0));
+                    self.emit(LDRegReg(v0, SCRATCH));
+                }
+            }
+        }
+
+        if write {
+            self.dec_reg_stack_top();
+        }
+        //variable() pushes the result
+        self.dec_reg_stack_top();
+    }
+
+    //jumps to the trap unless index < len. SUB leaves VF = 1 when index - len doesn't borrow.
+    fn emit_bounds_check(&mut self, index: Reg, len: u8) {
+        self.emit(LDRegReg(SCRATCH, index));
+        self.emit(LDRegByte(FLAG, len));
+        self.emit(SubRegReg(SCRATCH, FLAG));
+        self.emit(SERegByte(FLAG, 0));
+        self.trap_refs.push(self.asm.len());
+        self.emit(JP(Addr::new(0)));
+    }
+
+    //`a == b`, `a == 5` and `5 == a` skip on the variable's own register (SE Vx, Vy or
+    //SE Vx, byte), rather than copying both operands onto the register stack first.
+    //Returns false if the fast path doesn't apply.
+    fn variable_comparison(&mut self, lhs: TokenType) -> bool {
+        if self.prefix_precedence > Precedence::Equality {
+            return false;
+        }
+
+        let op = self.tokens[self.current].token_type();
+        if op != EqualsEquals && op != NotEquals {
+            return false;
+        }
+
+        let rhs = self.tokens[self.current + 1].token_type();
+
+        match self.tokens.get(self.current + 2).map(Token::token_type) {
+            Some(RightParen | Semicolon | Comma | And | Or) => (),
+            _ => return false,
+        }
+
+        let skip = match (&lhs, &rhs) {
+            (Identifier(lhs_name), Identifier(rhs_name)) => match (
+                self.lookup_variable_register(lhs_name.clone())
+                    .map(Reg::new),
+                self.lookup_variable_register(rhs_name.clone())
+                    .map(Reg::new),
+            ) {
+                (Some(lhs_reg), Some(rhs_reg)) if op == EqualsEquals => SERegReg(lhs_reg, rhs_reg),
+                (Some(lhs_reg), Some(rhs_reg)) => SNERegReg(lhs_reg, rhs_reg),
+                _ => return false,
+            },
+            (Identifier(name), Number(byte)) | (Number(byte), Identifier(name))
+                if *byte <= 0xFF =>
+            {
+                match self.lookup_variable_register(name.clone()).map(Reg::new) {
+                    Some(reg) if op == EqualsEquals => SERegByte(reg, *byte as u8),
+                    Some(reg) => SNERegByte(reg, *byte as u8),
+                    None => return false,
+                }
+            }
+            _ => return false,
+        };
+
+        self.advance();
+        self.advance();
+        self.emit(skip);
+
+        for operand in [lhs, rhs].iter() {
+            if let Identifier(name) = operand {
+                self.mark_variable_read(name);
+            }
+        }
+        true
+    }
+
     fn DT(&mut self, assign_allowed: bool) {
         let prev = self.tokens[self.previous].clone().token_type();
         let cur = self.tokens[self.current].clone().token_type();
@@ -664,11 +2610,11 @@ impl Compiler {
             DT => match cur {
                 Equals => {
                     self.advance();
-                    self.expression();
-                    self.emit(LDDTReg(self.peek_reg_stack(0)));
+                    let value = self.value_expression();
+                    self.emit(LDDTReg(value));
                 }
                 _ => {
-                    self.emit(LDRegDT(self.reg_stack_top));
+                    self.emit(LDRegDT(self.top_reg()));
                     self.inc_reg_stack_top();
                 }
             },
@@ -686,10 +2632,13 @@ impl Compiler {
             ST => match cur {
                 Equals => {
                     self.advance();
-                    self.expression();
-                    self.emit(LDSTReg(self.peek_reg_stack(0)));
+                    let value = self.value_expression();
+                    self.emit(LDSTReg(value));
                 }
-                _ => panic!("equals must follow ST as it can only be assigned to, not read"),
+                _ => self.syntax_error_at(
+                    self.previous,
+                    String::from("`ST` can only be assigned to, not read"),
+                ),
             },
             _ => {
                 panic!("non ST matched in ST()");
@@ -708,13 +2657,29 @@ impl Compiler {
                     match self.tokens[self.current].token_type() {
                         Number(num) => {
                             self.advance();
-                            self.emit(LDIAddr(num.clone()));
+                            if num <= 0xFFF {
+                                self.emit(LDIAddr(Addr::new(num)));
+                            } else {
+                                self.error(format!("address {} is out of range (0 to 4095)", num));
+                            }
+                            self.inc_reg_stack_top();
+                        }
+                        Identifier(name) if self.arrays.contains_key(&name) => {
+                            self.advance();
+                            let data_index = self.arrays[&name].data_index;
+                            self.emit_load_data_at(data_index);
                             self.inc_reg_stack_top();
                         }
-                        _ => panic!("I must be assigned to number literal (variable/expression cannot be used)")
+                        _ => self.syntax_error_at(
+                            self.current,
+                            String::from("`I` must be assigned a number literal or data label"),
+                        ),
                     }
                 }
-                _ => panic!("equals must follow I as it can only be assigned to, not read"),
+                _ => self.syntax_error_at(
+                    self.previous,
+                    String::from("`I` can only be assigned to, not read"),
+                ),
             },
             _ => {
                 panic!("non I matched in I()");
@@ -722,6 +2687,8 @@ impl Compiler {
         }
     }
 
+    //`RAND(mask)`. Cxkk only takes an immediate mask, so any other mask expression is applied
+    //with an AND after rolling a full byte into SCRATCH.
     fn rand(&mut self, assign_allowed: bool) {
         let prev = self.tokens[self.previous].clone().token_type();
         let cur = self.tokens[self.current].clone().token_type();
@@ -730,17 +2697,31 @@ impl Compiler {
             Rand => match cur {
                 LeftParen => {
                     self.consume(LeftParen);
-                    match self.tokens[self.current].token_type() {
-                        Number(num) => {
+                    let mask = self.tokens[self.current].token_type();
+                    let next = self.tokens.get(self.current + 1).map(Token::token_type);
+                    match (mask, next) {
+                        (Number(num), Some(RightParen)) => {
                             self.advance();
                             self.consume(RightParen);
-                            self.emit(RNDRegByte(self.reg_stack_top, num.clone()));
+                            let byte = self.byte_immediate(num as i32);
+                            self.emit(RNDRegByte(self.top_reg(), byte));
                             self.inc_reg_stack_top();
                         }
-                        _ => panic!("number literal param must be passed to rand() to AND result with (variable/expression cannot be used)")
+                        _ => {
+                            let mask = self.value_expression();
+                            self.consume(RightParen);
+                            self.emit(RNDRegByte(SCRATCH, 0xFF));
+                            self.emit(AndRegReg(mask, SCRATCH));
+                        }
                     }
                 }
-                _ => panic!("number literal param must be passed to rand() to AND result with (variable/expression cannot be used)")
+                _ => {
+                    self.syntax_error_at(
+                        self.current,
+                        String::from("expected a mask in () after `RAND`"),
+                    );
+                    self.inc_reg_stack_top();
+                }
             },
             _ => {
                 panic!("non rand matched in rand()");
@@ -748,6 +2729,36 @@ impl Compiler {
         }
     }
 
+    //`is_pressed(key)` is 1 while the key is held and 0 otherwise, without waiting like KEY()
+    fn is_pressed(&mut self, assign_allowed: bool) {
+        self.consume(LeftParen);
+        let key = self.value_expression();
+        self.consume(RightParen);
+
+        self.emit(LDRegByte(SCRATCH, 0));
+        self.emit(SKNPReg(key));
+        self.emit(LDRegByte(SCRATCH, 1));
+        self.emit(LDRegReg(key, SCRATCH));
+    }
+
+    //`slt(a, b)` is the signed form of `a < b`, treating 0x80..0xFF as -128..-1. Flipping the
+    //sign bit of both operands maps signed order onto unsigned order, so the usual borrow test
+    //applies afterwards. The operands are temporaries, so flipping them in place is safe.
+    fn slt(&mut self, assign_allowed: bool) {
+        self.consume(LeftParen);
+        let a = self.value_expression();
+        self.consume(Comma);
+        let b = self.value_expression();
+        self.consume(RightParen);
+
+        self.emit(LDRegByte(SCRATCH, 0x80));
+        self.emit(XorRegReg(a, SCRATCH));
+        self.emit(XorRegReg(b, SCRATCH));
+        self.comparison(Less);
+        self.dec_reg_stack_top();
+        self.dec_reg_stack_top();
+    }
+
     fn key(&mut self, assign_allowed: bool) {
         let prev = self.tokens[self.previous].clone().token_type();
         let cur = self.tokens[self.current].clone().token_type();
@@ -757,10 +2768,13 @@ impl Compiler {
                 LeftParen => {
                     self.consume(LeftParen);
                     self.consume(RightParen);
-                    self.emit(LDRegKey(self.reg_stack_top));
+                    self.emit(LDRegKey(self.top_reg()));
+                    self.inc_reg_stack_top();
+                }
+                _ => {
+                    self.syntax_error_at(self.current, String::from("expected () after `KEY`"));
                     self.inc_reg_stack_top();
                 }
-                _ => panic!("expect () after key"),
             },
             _ => {
                 panic!("non rand matched in rand()");
@@ -770,27 +2784,41 @@ impl Compiler {
 
     fn binary(&mut self, assign_allowed: bool) {
         let binop_type = self.tokens[self.previous].clone().token_type;
+        let line = self.tokens[self.previous].line;
         let next_prec =
             Precedence::try_from(self.get_rule(&self.tokens[self.previous]).precedence as u8 + 1)
                 .unwrap();
-        self.compile_precedence(next_prec);
+        let rhs = self.compile_precedence(next_prec);
+        let rhs = self.value_reg(rhs);
 
         match binop_type {
             Plus => {
-                self.emit(AddRegReg(self.peek_reg_stack(1), self.peek_reg_stack(0)));
+                self.emit(AddRegReg(self.peek_reg_stack(1), rhs));
                 self.dec_reg_stack_top();
             }
             Minus => {
-                self.emit(SubRegReg(self.peek_reg_stack(1), self.peek_reg_stack(0)));
+                self.warn_if_underflow(line);
+                self.emit(SubRegReg(self.peek_reg_stack(1), rhs));
+                if self.underflow_checks {
+                    //VF is 1 when the subtraction didn't borrow
+                    self.emit(SERegByte(FLAG, 1));
+                    self.trap_refs.push(self.asm.len());
+                    self.emit(JP(Addr::new(0)));
+                }
                 self.dec_reg_stack_top();
             }
             EqualsEquals => {
-                self.emit(SERegReg(self.peek_reg_stack(1), self.peek_reg_stack(0)));
+                self.emit(SERegReg(self.peek_reg_stack(1), rhs));
                 self.dec_reg_stack_top();
                 self.dec_reg_stack_top();
             }
             NotEquals => {
-                self.emit(SNERegReg(self.peek_reg_stack(1), self.peek_reg_stack(0)));
+                self.emit(SNERegReg(self.peek_reg_stack(1), rhs));
+                self.dec_reg_stack_top();
+                self.dec_reg_stack_top();
+            }
+            Less | Greater | LessEquals | GreaterEquals => {
+                self.comparison(binop_type);
                 self.dec_reg_stack_top();
                 self.dec_reg_stack_top();
             }
@@ -801,229 +2829,1690 @@ impl Compiler {
         }
     }
 
+    //registers only hold 0 to 255, so `3 - 5` is 254 rather than -2
+    fn warn_if_underflow(&mut self, line: u32) {
+        let lhs = self
+            .known_bytes
+            .get(&self.peek_reg_stack(1).value())
+            .copied();
+        let rhs = self
+            .known_bytes
+            .get(&self.peek_reg_stack(0).value())
+            .copied();
+        if let (Some(lhs), Some(rhs)) = (lhs, rhs) {
+            if lhs < rhs {
+                self.warnings.push(CompileWarning::new(
+                    line,
+                    format!(
+                        "subtraction underflows: {} - {} wraps to {}",
+                        lhs,
+                        rhs,
+                        lhs.wrapping_sub(rhs)
+                    ),
+                ));
+            }
+        }
+    }
+
+    //a < b skips the next instruction when a - b borrows (VF == 0). > and <= swap the operands,
+    //>= and <= skip on no borrow instead. SCRATCH holds the difference so neither operand changes.
+    //These compare unsigned bytes; `slt(a, b)` is the signed alternative.
+    fn comparison(&mut self, op: TokenType) {
+        let (lhs, rhs) = match op {
+            Less | GreaterEquals => (self.peek_reg_stack(1), self.peek_reg_stack(0)),
+            _ => (self.peek_reg_stack(0), self.peek_reg_stack(1)),
+        };
+        self.emit(LDRegReg(SCRATCH, lhs));
+        self.emit(SubRegReg(SCRATCH, rhs));
+        match op {
+            Less | Greater => self.emit(SERegByte(FLAG, 0)),
+            _ => self.emit(SNERegByte(FLAG, 0)),
+        }
+    }
+
     fn or(&mut self, assign_allowed: bool) {
         let jp_condition_not_met_asm_index = self.asm.len();
-        self.emit(JP(0));
+        self.emit(JP(Addr::new(0)));
         let jp_condition_met_asm_index = self.asm.len();
-        self.emit(JP(0));
+        self.emit(JP(Addr::new(0)));
 
-        self.asm[jp_condition_not_met_asm_index] = JP(asm_bytes_len(self.asm.len()));
+        self.patch_jump_here(jp_condition_not_met_asm_index);
         self.compile_precedence(Precedence::Or);
-        self.asm[jp_condition_met_asm_index] = JP(asm_bytes_len(self.asm.len()) + 2);
+        self.asm[jp_condition_met_asm_index] = JP(Addr::new(asm_bytes_len(self.asm.len()) + 2));
     }
 
     fn and(&mut self, assign_allowed: bool) {
         let jp_asm_index = self.asm.len();
-        self.emit(JP(0));
+        self.emit(JP(Addr::new(0)));
 
         self.compile_precedence(Precedence::And);
 
-        self.asm[jp_asm_index] = JP(asm_bytes_len(self.asm.len()));
+        self.patch_jump_here(jp_asm_index);
+    }
+}
+
+impl Compiler {
+    pub fn asm(&self) -> &Vec<Opcode> {
+        &self.asm
+    }
+
+    pub fn ram_line_map(&self) -> &HashMap<u16, u32> {
+        &self.ram_line_map
+    }
+
+    pub fn warnings(&self) -> Vec<CompileWarning> {
+        self.warnings.clone()
+    }
+
+    pub fn errors(&self) -> Vec<CompileError> {
+        self.errors.clone()
     }
 }
 
-impl Compiler {
-    pub fn asm(&self) -> &Vec<Opcode> {
-        &self.asm
+#[cfg(test)]
+mod tests {
+    use super::Compiler;
+    use super::*;
+
+    #[test]
+    pub fn test_check() {
+        let mut l = Lexer::new("var test 123 55");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        assert!(c.check(Var));
+    }
+
+    #[test]
+    pub fn test_operand_ranges() {
+        assert_eq!(Reg::new(0xF).value(), 0xF);
+        assert_eq!(Nibble::new(0xF).value(), 0xF);
+        assert_eq!(Addr::new(0xFFF).value(), 0xFFF);
+    }
+
+    #[test]
+    #[should_panic(expected = "Register V10 out of range")]
+    pub fn test_reg_out_of_range() {
+        Reg::new(0x10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Nibble 10 out of range")]
+    pub fn test_nibble_out_of_range() {
+        Nibble::new(0x10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Address 1000 out of range")]
+    pub fn test_addr_out_of_range() {
+        Addr::new(0x1000);
+    }
+
+    #[test]
+    pub fn test_number() {
+        let mut l = Lexer::new("10; 5;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        let mut l = Lexer::new("12 + 3 + 7 + 2;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        assert_eq!(c.reg_stack_top, 0);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 12),
+                LDRegByte(Reg::new(1), 3),
+                AddRegReg(Reg::new(0), Reg::new(1)),
+                LDRegByte(Reg::new(1), 7),
+                AddRegReg(Reg::new(0), Reg::new(1)),
+                LDRegByte(Reg::new(1), 2),
+                AddRegReg(Reg::new(0), Reg::new(1)),
+            ]
+        ));
+
+        assert_eq!(c.reg_stack_top, 0);
+    }
+
+    #[test]
+    pub fn test_sub() {
+        let mut l = Lexer::new("9 - 7;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 9),
+                LDRegByte(Reg::new(1), 7),
+                SubRegReg(Reg::new(0), Reg::new(1))
+            ]
+        ));
+        assert_eq!(c.reg_stack_top, 0);
+    }
+
+    #[test]
+    pub fn test_negative_literals() {
+        let mut l = Lexer::new("var a = -1; var b = -128; var c = 5 - -2; -a;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(!c.had_error());
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 0xFF),
+                LDRegByte(Reg::new(1), 0x80),
+                LDRegByte(Reg::new(2), 5),
+                LDRegByte(Reg::new(3), 0xFE),
+                SubRegReg(Reg::new(2), Reg::new(3)),
+                LDRegByte(Reg::new(3), 0),
+                LDRegReg(Reg::new(4), Reg::new(0)),
+                SubRegReg(Reg::new(3), Reg::new(4)),
+            ]
+        ));
+        assert_eq!(c.reg_stack_top, 3);
+    }
+
+    #[test]
+    pub fn test_byte_out_of_range() {
+        let mut l = Lexer::new("var a = -129;\nvar b = 256;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert_eq!(
+            c.errors(),
+            vec![
+                CompileError::new(
+                    0,
+                    9,
+                    String::from("number -129 is out of range for a byte (-128 to 255)")
+                ),
+                CompileError::new(
+                    1,
+                    8,
+                    String::from("number 256 is out of range for a byte (-128 to 255)")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_number_out_of_range() {
+        let mut l = Lexer::new("var a = 70000;\nvar b = 1 + 65536;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert_eq!(
+            c.errors(),
+            vec![
+                CompileError::new(
+                    0,
+                    8,
+                    String::from("number 70000 is out of range (0 to 65535)")
+                ),
+                CompileError::new(
+                    1,
+                    12,
+                    String::from("number 65536 is out of range (0 to 65535)")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_render_error() {
+        let src = "var a = 1;\nvar b = 256;";
+        let mut l = Lexer::new(src);
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert_eq!(
+            c.errors()[0].render(src),
+            String::from(
+                "error: number 256 is out of range for a byte (-128 to 255)
+ --> 2:9
+  |
+2 | var b = 256;
+  |         ^"
+            )
+        );
+    }
+
+    #[test]
+    pub fn test_draw_height() {
+        let mut l = Lexer::new("var h = 3; DRAW(1, 2, 5);");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(!c.had_error());
+        assert_eq!(
+            c.asm().last(),
+            Some(&DRWRegRegNibble(Reg::new(1), Reg::new(2), Nibble::new(5)))
+        );
+        assert_eq!(c.reg_stack_top, 1);
+
+        let mut l = Lexer::new("var h = 3; DRAW(1, 2, h);\nDRAW(1, 2, 16); h;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert_eq!(
+            c.errors(),
+            vec![
+                CompileError::new(
+                    0,
+                    22,
+                    String::from("sprite height must be a number literal")
+                ),
+                CompileError::new(
+                    1,
+                    11,
+                    String::from("sprite height 16 is out of range (0 to 15)")
+                ),
+            ]
+        );
+        assert_eq!(c.reg_stack_top, 1);
+    }
+
+    #[test]
+    pub fn test_i_address_out_of_range() {
+        let mut l = Lexer::new("I = 4095;\nI = 5000;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert_eq!(
+            c.errors(),
+            vec![CompileError::new(
+                1,
+                4,
+                String::from("address 5000 is out of range (0 to 4095)")
+            )]
+        );
+        assert_eq!(c.asm()[0], LDIAddr(Addr::new(0xFFF)));
+    }
+
+    #[test]
+    pub fn test_reserved_names() {
+        let mut l = Lexer::new("var I = 1;\nfn DT() {}\nfn f(a, KEY) {}");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert_eq!(
+            c.errors(),
+            vec![
+                CompileError::new(0, 4, String::from("`I` is a reserved CHIP-8 register name")),
+                CompileError::new(
+                    1,
+                    3,
+                    String::from("`DT` is a reserved CHIP-8 register name")
+                ),
+                CompileError::new(
+                    2,
+                    8,
+                    String::from("`KEY` is a reserved built-in function name")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_variable() {
+        let mut l = Lexer::new("var a = 3; a;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 3),
+                LDRegReg(Reg::new(1), Reg::new(0))
+            ]
+        ));
+        assert_eq!(c.reg_stack_top, 1);
+    }
+
+    #[test]
+    pub fn test_variable_assignment() {
+        let mut l = Lexer::new("var a = 1; a + 4; var b = 2; var c = b + a; c = a;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            //vec![LDRegByte(Reg::new(0), 3), LDRegByte(Reg::new(1), 10), LDRegReg(Reg::new(0), Reg::new(1))]
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDRegByte(Reg::new(2), 4),
+                AddRegReg(Reg::new(1), Reg::new(2)),
+                LDRegByte(Reg::new(1), 2),
+                LDRegReg(Reg::new(2), Reg::new(1)),
+                LDRegReg(Reg::new(3), Reg::new(0)),
+                AddRegReg(Reg::new(2), Reg::new(3)),
+                LDRegReg(Reg::new(3), Reg::new(0)),
+                LDRegReg(Reg::new(2), Reg::new(3))
+            ]
+        ));
+        assert_eq!(c.reg_stack_top, 3);
+    }
+
+    #[test]
+    pub fn test_expression_statement_stack() {
+        let mut l = Lexer::new("var a = 1; a = 5;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert_eq!(c.reg_stack_top, 1);
+
+        let mut l = Lexer::new("var a = 1; a == 5; 1 != 2; var b = 2;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert_eq!(c.reg_stack_top, 2);
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                SERegByte(Reg::new(0), 5),
+                Opcode::Nop,
+                LDRegByte(Reg::new(1), 1),
+                LDRegByte(Reg::new(2), 2),
+                SNERegReg(Reg::new(1), Reg::new(2)),
+                Opcode::Nop,
+                LDRegByte(Reg::new(1), 2),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_lexical_scope() {
+        let mut l = Lexer::new("var a = 1; { var b = 4; } var c = 7;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            //vec![LDRegByte(Reg::new(0), 3), LDRegByte(Reg::new(1), 10), LDRegReg(Reg::new(0), Reg::new(1))]
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 4),
+                LDRegByte(Reg::new(1), 7),
+            ]
+        ));
+        assert_eq!(c.reg_stack_top, 2);
+    }
+
+    #[test]
+    pub fn test_unused_variable_warning() {
+        let mut l = Lexer::new("var a = 1;\n{ var b = 2; a; }");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert_eq!(
+            c.warnings(),
+            vec![CompileWarning::new(
+                1,
+                String::from("variable b is declared but never read")
+            )]
+        );
+    }
+
+    #[test]
+    pub fn test_symbols() {
+        let src = "data ship = [24, 60];\nvar grid[4];\nfn step(x, y) {\n    var d = x + y;\n    grid[0] = d;\n}\nvar a = 1;\nstep(a, 2);";
+        let mut l = Lexer::new(src);
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(!c.had_error());
+
+        assert_eq!(
+            c.symbols,
+            vec![
+                Symbol::new("ship", SymbolKind::Data, 0),
+                Symbol::new("grid", SymbolKind::Array, 1),
+                Symbol::new("step", SymbolKind::Function, 2),
+                Symbol::new("x", SymbolKind::Argument, 2),
+                Symbol::new("y", SymbolKind::Argument, 2),
+                Symbol::new("d", SymbolKind::Variable, 3),
+                Symbol::new("a", SymbolKind::Variable, 6),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_leaf_function_symbols() {
+        let mut l = Lexer::new("fn f(a) { var t = a + 1; } f(2);");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_leaf_functions(true);
+        c.compile();
+        assert!(!c.had_error());
+        assert!(c.functions["f"].leaf_base.is_some());
+
+        //the body is compiled twice but its declarations are only recorded once
+        assert_eq!(
+            c.symbols,
+            vec![
+                Symbol::new("f", SymbolKind::Function, 0),
+                Symbol::new("a", SymbolKind::Argument, 0),
+                Symbol::new("t", SymbolKind::Variable, 0),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_constant_condition_warning() {
+        let mut l = Lexer::new("var a = 1;\nif (1 == 2) a = 3;\nwhile (a != 0) { a = a - 1; }");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert_eq!(
+            c.warnings(),
+            vec![CompileWarning::new(
+                1,
+                String::from("condition is constant")
+            )]
+        );
+    }
+
+    #[test]
+    pub fn test_fold_constant_conditions() {
+        let compile = |src: &str| {
+            let mut l = Lexer::new(src);
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.set_fold_constant_conditions(true);
+            c.compile();
+            assert!(!c.had_error());
+            c.asm
+        };
+
+        assert!(utils::vectors_equivalent(
+            compile("if (1 == 1) 5;"),
+            vec![LDRegByte(Reg::new(0), 5)]
+        ));
+        assert!(compile("if (0 == 1) 5;").is_empty());
+        assert!(utils::vectors_equivalent(
+            compile("if ((200 + 100) < 45 and -1 == 255) 5; else 6;"),
+            vec![LDRegByte(Reg::new(0), 5)]
+        ));
+        assert!(utils::vectors_equivalent(
+            compile("if (2 - 3 < 1 or 0 != 0) { 5; } else { 6; } 7;"),
+            vec![LDRegByte(Reg::new(0), 6), LDRegByte(Reg::new(0), 7)]
+        ));
+
+        //anything that reads state is compiled as usual
+        let asm = compile("if (RAND(1) == 1) 5;");
+        assert!(asm.iter().any(|op| matches!(op, RNDRegByte(..))));
+        assert!(asm.iter().any(|op| matches!(op, JP(_))));
+    }
+
+    #[test]
+    pub fn test_fold_constant_conditions_discards_declarations() {
+        let compile = |src: &str| {
+            let mut l = Lexer::new(src);
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.set_fold_constant_conditions(true);
+            c.compile();
+            c
+        };
+
+        let c = compile("var r = 0; if (0 == 1) { fn g(a) { a = 1; } } g(r); r = 2;");
+        assert_eq!(c.errors()[0].message, "`g` is not declared");
+        assert!(!c.functions.contains_key("g"));
+        assert!(c.symbols.iter().all(|symbol| symbol.name != "g"));
+
+        let c = compile("var r = 0; if (1 == 1) 5; else { fn g(a) { a = 1; } } g(r);");
+        assert!(c.had_error());
+        let c = compile("var r = 0; if (1 == 1) { fn g(a) { a = 1; } } g(r);");
+        assert!(!c.had_error());
+
+        //the discarded hline's pixel sprite and array aren't placed
+        let c = compile("if (0 == 1) { hline(0, 0, 3); } else { 5; }");
+        assert!(c.data.is_empty());
+        let c = compile("if (1 == 1) 5; else { var a[4]; }");
+        assert!(c.data.is_empty() && c.arrays.is_empty());
+    }
+
+    #[test]
+    pub fn test_parse_events() {
+        let mut l = Lexer::new("var a = 1 + 2; a;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_record_parse_events(true);
+        c.compile();
+
+        assert_eq!(
+            c.parse_events,
+            vec![
+                ParseEvent::new(ParseEventKind::Enter, "declaration", 0, 0),
+                ParseEvent::new(ParseEventKind::Enter, "expression", 3, 3),
+                ParseEvent::new(ParseEventKind::Exit, "expression", 3, 5),
+                ParseEvent::new(ParseEventKind::Exit, "declaration", 0, 6),
+                ParseEvent::new(ParseEventKind::Enter, "declaration", 7, 7),
+                ParseEvent::new(ParseEventKind::Enter, "statement", 7, 7),
+                ParseEvent::new(ParseEventKind::Enter, "expression", 7, 7),
+                ParseEvent::new(ParseEventKind::Exit, "expression", 7, 7),
+                ParseEvent::new(ParseEventKind::Exit, "statement", 7, 8),
+                ParseEvent::new(ParseEventKind::Exit, "declaration", 7, 8),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_empty_program() {
+        let mut l = Lexer::new("");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(c.asm.is_empty());
+        assert_eq!(c.reg_stack_top, 0);
+    }
+
+    #[test]
+    pub fn test_empty_blocks() {
+        let mut l = Lexer::new("{} { {} }");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(c.asm.is_empty());
+        assert_eq!((c.reg_stack_top, c.scope_depth), (0, 0));
+
+        let mut l = Lexer::new("if (1==1) {}");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 1),
+                SERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x208)),
+            ]
+        ));
+        assert_eq!(c.reg_stack_top, 0);
+
+        let mut l = Lexer::new("while (0 != 0) {}");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 0),
+                LDRegByte(Reg::new(1), 0),
+                SNERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x20A)),
+                JP(Addr::new(0x200)),
+            ]
+        ));
+        assert_eq!(c.reg_stack_top, 0);
+    }
+
+    #[test]
+    pub fn test_if() {
+        let mut l = Lexer::new("if (1+3 == 4) { 10; } 5;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            //vec![LDRegByte(Reg::new(0), 3), LDRegByte(Reg::new(1), 10), LDRegReg(Reg::new(0), Reg::new(1))]
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 3),
+                AddRegReg(Reg::new(0), Reg::new(1)),
+                LDRegByte(Reg::new(1), 4),
+                SERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x20E)),
+                LDRegByte(Reg::new(0), 10),
+                LDRegByte(Reg::new(0), 5)
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_if_else() {
+        let mut l = Lexer::new("var a = 0; if (1 == 2) a = 5; else a = 9;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 0),
+                LDRegByte(Reg::new(1), 1),
+                LDRegByte(Reg::new(2), 2),
+                SERegReg(Reg::new(1), Reg::new(2)),
+                JP(Addr::new(0x210)),
+                LDRegByte(Reg::new(1), 5),
+                LDRegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x214)),
+                LDRegByte(Reg::new(1), 9),
+                LDRegReg(Reg::new(0), Reg::new(1))
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_variable_comparison() {
+        let mut l =
+            Lexer::new("var a = 1; var b = 2; if (a == b) a = 3; if (a != b and b == a) b = 4;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 2),
+                SERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x20C)),
+                LDRegByte(Reg::new(2), 3),
+                LDRegReg(Reg::new(0), Reg::new(2)),
+                SNERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x212)),
+                SERegReg(Reg::new(1), Reg::new(0)),
+                JP(Addr::new(0x218)),
+                LDRegByte(Reg::new(2), 4),
+                LDRegReg(Reg::new(1), Reg::new(2)),
+            ]
+        ));
+        assert_eq!(c.reg_stack_top, 2);
+    }
+
+    #[test]
+    pub fn test_constant_comparison() {
+        let mut l = Lexer::new("var a = 5; if (a == 5) a = 1; if (7 != a) a = 2;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 5),
+                SERegByte(Reg::new(0), 5),
+                JP(Addr::new(0x20A)),
+                LDRegByte(Reg::new(1), 1),
+                LDRegReg(Reg::new(0), Reg::new(1)),
+                SNERegByte(Reg::new(0), 7),
+                JP(Addr::new(0x212)),
+                LDRegByte(Reg::new(1), 2),
+                LDRegReg(Reg::new(0), Reg::new(1)),
+            ]
+        ));
+        assert_eq!(c.reg_stack_top, 1);
+    }
+
+    #[test]
+    pub fn test_comparison_precedence() {
+        assert!(
+            Precedence::try_from(Precedence::Equality as u8 + 1).unwrap() == Precedence::Comparison
+        );
+        assert!(
+            Precedence::try_from(Precedence::Comparison as u8 + 1).unwrap() == Precedence::Term
+        );
+
+        //(1 + 2) < 4
+        let mut l = Lexer::new("if (1 + 2 < 4) 5;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 2),
+                AddRegReg(Reg::new(0), Reg::new(1)),
+                LDRegByte(Reg::new(1), 4),
+                LDRegReg(SCRATCH, Reg::new(0)),
+                SubRegReg(SCRATCH, Reg::new(1)),
+                SERegByte(FLAG, 0),
+                JP(Addr::new(0x212)),
+                LDRegByte(Reg::new(0), 5),
+            ]
+        ));
+
+        //4 > (1 + 2), compiled as (1 + 2) < 4
+        let mut l = Lexer::new("if (4 > 1 + 2) 5;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 4),
+                LDRegByte(Reg::new(1), 1),
+                LDRegByte(Reg::new(2), 2),
+                AddRegReg(Reg::new(1), Reg::new(2)),
+                LDRegReg(SCRATCH, Reg::new(1)),
+                SubRegReg(SCRATCH, Reg::new(0)),
+                SERegByte(FLAG, 0),
+                JP(Addr::new(0x212)),
+                LDRegByte(Reg::new(0), 5),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_and() {
+        let mut l = Lexer::new("if (2 == 2 and 4 == 4) 5; else 9;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 2),
+                LDRegByte(Reg::new(1), 2),
+                SERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x20E)),
+                LDRegByte(Reg::new(0), 4),
+                LDRegByte(Reg::new(1), 4),
+                SERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x214)),
+                LDRegByte(Reg::new(0), 5),
+                JP(Addr::new(0x216)),
+                LDRegByte(Reg::new(0), 9)
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_coalesce_jumps() {
+        let mut l =
+            Lexer::new("var a = 1; var b = 2; if (a == b and b == a) a = 3; while (1 == 1) {}");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_coalesce_jumps(true);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 2),
+                SERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x210)),
+                SERegReg(Reg::new(1), Reg::new(0)),
+                JP(Addr::new(0x210)),
+                LDRegByte(Reg::new(2), 3),
+                LDRegReg(Reg::new(0), Reg::new(2)),
+                LDRegByte(Reg::new(2), 1),
+                LDRegByte(Reg::new(3), 1),
+                SERegReg(Reg::new(2), Reg::new(3)),
+                JP(Addr::new(0x21A)),
+                JP(Addr::new(0x210)),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_unroll_loops() {
+        let src = "var x = 0; var i = 3; while (i != 0) { x = x + 2; i = i - 1; }";
+        let mut l = Lexer::new(src);
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_unroll_loops(true);
+        c.compile();
+
+        let body = vec![
+            LDRegReg(Reg::new(2), Reg::new(0)),
+            LDRegByte(Reg::new(3), 2),
+            AddRegReg(Reg::new(2), Reg::new(3)),
+            LDRegReg(Reg::new(0), Reg::new(2)),
+        ];
+        //the counter is never read again, so neither its load nor the final 0 is emitted
+        let mut expected = vec![LDRegByte(Reg::new(0), 0)];
+        for _ in 0..3 {
+            expected.extend(body.clone());
+        }
+        assert!(utils::vectors_equivalent(c.asm.clone(), expected));
+        assert!(!c.asm.iter().any(|op| matches!(op, JP(_))));
+        assert_eq!(c.reg_stack_top, 2);
+        assert!(c.warnings().is_empty());
+
+        //off by default
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(c.asm.iter().any(|op| matches!(op, JP(_))));
+    }
+
+    #[test]
+    pub fn test_unroll_loops_counter_read_after() {
+        let src = "var x = 0; var i = 2; while (i != 0) { x = x + 2; i = i - 1; } x = i;";
+        let mut l = Lexer::new(src);
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_unroll_loops(true);
+        c.compile();
+
+        assert_eq!(c.asm[1], LDRegByte(Reg::new(1), 2));
+        assert_eq!(c.asm[10], LDRegByte(Reg::new(1), 0));
+        assert!(!c.asm.iter().any(|op| matches!(op, JP(_))));
+    }
+
+    #[test]
+    pub fn test_unroll_loops_reports_once() {
+        let src = "var i = 3; while (i != 0) { y = 1; var z = 2; i = i - 1; }";
+        let mut l = Lexer::new(src);
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_unroll_loops(true);
+        c.compile();
+
+        assert_eq!(
+            c.errors(),
+            vec![CompileError::new(
+                0,
+                28,
+                String::from("`y` is not declared")
+            )]
+        );
+        assert_eq!(c.warnings().len(), 1);
+    }
+
+    #[test]
+    pub fn test_unroll_loops_conservative() {
+        for src in [
+            //body reads the counter
+            "var x = 0; var i = 3; while (i != 0) { x = i; i = i - 1; }",
+            //bound above UNROLL_LIMIT
+            "var x = 0; var i = 9; while (i != 0) { x = x + 1; i = i - 1; }",
+            //counter not initialised right before the loop
+            "var i = 3; var x = 0; while (i != 0) { x = x + 1; i = i - 1; }",
+            //decrement isn't the last statement
+            "var x = 0; var i = 3; while (i != 0) { i = i - 1; x = x + 1; }",
+        ]
+        .iter()
+        {
+            let mut l = Lexer::new(src);
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.set_unroll_loops(true);
+            c.compile();
+            assert!(c.asm.iter().any(|op| matches!(op, JP(_))), "{}", src);
+        }
+    }
+
+    #[test]
+    pub fn test_unroll_truncated_loop() {
+        for src in [
+            "while",
+            "var i = 3; while (i",
+            "var i = 3; while (i != 0) {",
+        ]
+        .iter()
+        {
+            let mut l = Lexer::new(src);
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            //positioned as while_statement() sees it, just past `while`
+            c.previous = c
+                .tokens
+                .iter()
+                .position(|token| token.token_type == While)
+                .unwrap();
+            c.current = c.previous + 1;
+            assert_eq!(c.unrollable_countdown(), None, "{}", src);
+        }
+    }
+
+    #[test]
+    pub fn test_not_equal() {
+        let mut l = Lexer::new("if (1 != 5) 3;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 5),
+                SNERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x20A)),
+                LDRegByte(Reg::new(0), 3),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_or() {
+        let mut l = Lexer::new("if (1 != 1 or 3 == 3) 8; else 5;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 1),
+                SNERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x20A)),
+                JP(Addr::new(0x212)),
+                LDRegByte(Reg::new(0), 3),
+                LDRegByte(Reg::new(1), 3),
+                SERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x216)),
+                LDRegByte(Reg::new(0), 8),
+                JP(Addr::new(0x218)),
+                LDRegByte(Reg::new(0), 5),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_while() {
+        let mut l = Lexer::new("var a = 255; while (a != 0) { a = a - 1; }");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 255),
+                SNERegByte(Reg::new(0), 0),
+                JP(Addr::new(0x210)),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDRegByte(Reg::new(2), 1),
+                SubRegReg(Reg::new(1), Reg::new(2)),
+                LDRegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x202)),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_nop() {
+        let mut l = Lexer::new("nop; var a = 1; nop;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![Opcode::Nop, LDRegByte(Reg::new(0), 1), Opcode::Nop]
+        ));
+    }
+
+    #[test]
+    pub fn test_hline() {
+        let mut l = Lexer::new("hline(1, 2, 3);");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 2),
+                LDRegByte(Reg::new(2), 3),
+                LDIAddr(Addr::new(0x218)),
+                SNERegByte(Reg::new(2), 0),
+                JP(Addr::new(0x216)),
+                DRWRegRegNibble(Reg::new(0), Reg::new(1), Nibble::new(1)),
+                LDRegByte(SCRATCH, 1),
+                AddRegReg(Reg::new(0), SCRATCH),
+                SubRegReg(Reg::new(2), SCRATCH),
+                JP(Addr::new(0x208)),
+                JP(Addr::new(0x216)),
+                DW(0x8000),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_array_indexing() {
+        let mut l = Lexer::new("var a[4]; var i = 1; var x = a[i]; a[i + 1] = x;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        //the data region starts after the 18 instructions and the halt
+        let a = Addr::new(0x226);
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDIAddr(a),
+                AddIReg(Reg::new(1)),
+                LDRegReg(SCRATCH, Reg::new(0)),
+                LDRegI(Reg::new(0)),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDRegReg(Reg::new(0), SCRATCH),
+                LDRegReg(Reg::new(2), Reg::new(0)),
+                LDRegByte(Reg::new(3), 1),
+                AddRegReg(Reg::new(2), Reg::new(3)),
+                LDRegReg(Reg::new(3), Reg::new(1)),
+                LDIAddr(a),
+                AddIReg(Reg::new(2)),
+                LDRegReg(SCRATCH, Reg::new(0)),
+                LDRegReg(Reg::new(0), Reg::new(3)),
+                LDIReg(Reg::new(0)),
+                LDRegReg(Reg::new(0), SCRATCH),
+                JP(Addr::new(0x224)),
+                DW(0),
+                DW(0),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_bounds_checks() {
+        let compile = |checks: bool| {
+            let mut l = Lexer::new("var a[3]; var i = 1; a[i] = 4;");
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.set_bounds_checks(checks);
+            c.compile();
+            assert!(!c.had_error());
+            c.asm
+        };
+        let check = |asm: &Vec<Opcode>| {
+            asm.windows(3).any(|ops| {
+                ops[0] == LDRegByte(FLAG, 3)
+                    && ops[1] == SubRegReg(SCRATCH, FLAG)
+                    && ops[2] == SERegByte(FLAG, 0)
+            })
+        };
+
+        let unchecked = compile(false);
+        assert!(!check(&unchecked));
+        assert!(unchecked.contains(&AddIReg(Reg::new(1))));
+
+        let checked = compile(true);
+        assert!(check(&checked));
+        //the halt is followed by a trap that every failed check jumps to
+        let trap = checked.len() - 3;
+        assert_eq!(checked[trap], JP(Addr::new(0x200 + 2 * trap as u16)));
+        assert!(checked[..trap].contains(&checked[trap]));
+    }
+
+    #[test]
+    pub fn test_underflow_warning() {
+        let mut l = Lexer::new("var a = 3;\nvar b = 1;\na = a - 5;\nb = a - b;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        //a is no longer known after the first subtraction, so the second isn't flagged
+        assert_eq!(
+            c.warnings(),
+            vec![CompileWarning::new(
+                2,
+                String::from("subtraction underflows: 3 - 5 wraps to 254")
+            )]
+        );
+
+        //a may or may not have been reassigned where the branches join
+        for src in [
+            "var a = 10; var k = KEY(); if (k == 1) a = 3; a = a - 5;",
+            "var a = 10; var k = KEY(); if (k == 1) a = 3; else a = 4; a = a - 5;",
+            "var a = 10; var k = KEY(); switch (k) { case 1: a = 3; } a = a - 5;",
+            "var a = 10; var k = KEY(); var b = k == 1 and (a = 3) == 3; a = a - 5;",
+        ] {
+            let mut l = Lexer::new(src);
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.compile();
+            assert!(
+                c.warnings()
+                    .iter()
+                    .all(|warning| !warning.message.starts_with("subtraction underflows")),
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_underflow_checks() {
+        let compile = |checks: bool| {
+            let mut l = Lexer::new("var a = 3; a = a - 5;");
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.set_underflow_checks(checks);
+            c.compile();
+            assert!(!c.had_error());
+            c.asm
+        };
+
+        assert!(!compile(false).contains(&SERegByte(FLAG, 1)));
+
+        let checked = compile(true);
+        let sub = checked
+            .iter()
+            .position(|op| *op == SubRegReg(Reg::new(1), Reg::new(2)))
+            .unwrap();
+        assert_eq!(checked[sub + 1], SERegByte(FLAG, 1));
+        //with no data the trap is the last instruction, right after the halt
+        let trap = checked.len() - 1;
+        assert_eq!(checked[trap], JP(Addr::new(0x200 + 2 * trap as u16)));
+        assert_eq!(checked[sub + 2], checked[trap]);
+    }
+
+    #[test]
+    pub fn test_include_errors() {
+        let src = "include \"lib\";\ninclude \"nope\";";
+        let mut l = Lexer::new(src);
+        l.add_source("lib", "var a = 1;\nb = 2;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        let mut in_lib = CompileError::new(1, 0, String::from("`b` is not declared"));
+        in_lib.file = Some(String::from("lib"));
+        assert_eq!(
+            c.errors(),
+            vec![
+                in_lib,
+                CompileError::new(1, 0, String::from("cannot include `nope`: no such source")),
+            ]
+        );
+
+        let errors = |src: &str| {
+            let mut l = Lexer::new(src);
+            l.add_source("lib", "var a = 1;");
+            l.add_source("self", "include \"self\";");
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.compile();
+            c.errors()
+                .iter()
+                .map(|error| error.message.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            errors("include \"lib\""),
+            vec![String::from("expected `;` after include")]
+        );
+        //recovery carries on at the next statement
+        assert_eq!(
+            errors("include \"lib\"\nvar a = 1;\nb = 2;"),
+            vec![
+                String::from("expected `;` after include"),
+                String::from("`b` is not declared")
+            ]
+        );
+        assert_eq!(
+            errors("include \"self\";"),
+            vec![String::from("cannot include `self`: it includes itself")]
+        );
+        assert!(c
+            .render_errors(src)
+            .starts_with("error: `b` is not declared\n --> lib:2:1\n  |\n2 | b = 2;\n  | ^"));
+    }
+
+    #[test]
+    pub fn test_vline_shares_sprite() {
+        let mut l = Lexer::new("var a = 4; hline(a, 0, 2); vline(0, a, 2);");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        let asm = c.asm();
+        assert_eq!(asm[asm.len() - 1], DW(0x8000));
+        assert_eq!(
+            asm[asm.len() - 2],
+            JP(Addr::new(asm_bytes_len(asm.len() - 2)))
+        );
+        let sprite = Addr::new(asm_bytes_len(asm.len() - 1));
+        assert_eq!(asm.iter().filter(|op| **op == LDIAddr(sprite)).count(), 2);
+        //vline steps y, which is its second argument
+        assert!(asm.contains(&AddRegReg(Reg::new(2), SCRATCH)));
+        assert_eq!(c.reg_stack_top, 1);
+    }
+
+    #[test]
+    pub fn test_octo() {
+        let mut l =
+            Lexer::new("var a = 3; fn inc(n) { n = n + 1; } while (a != 0) { inc(a); a = a - 1; }");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert_eq!(
+            c.stringify_octo(),
+            [
+                ": main",
+                "\tv0 := 3",
+                "\tjump L_216",
+                ": L_204",
+                "\tv1 := v0",
+                "\tv2 := 1",
+                "\tv1 += v2",
+                "\tv0 := v1",
+                "\tve := 3",
+                "\tvd -= ve",
+                "\ti := hex vd",
+                "\tload vd",
+                "\treturn",
+                ": L_216",
+                "\tif v0 == 0 then",
+                "\tjump L_232",
+                "\ti := hex vd",
+                "\tsave vd",
+                "\tve := 3",
+                "\tvd += ve",
+                "\tv1 := v0",
+                "\tv0 := v1",
+                "\tL_204",
+                "\tv1 := v0",
+                "\tv2 := 1",
+                "\tv1 -= v2",
+                "\tv0 := v1",
+                "\tjump L_216",
+                ": L_232",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    pub fn test_fn_without_args() {
+        let mut l = Lexer::new("var variable = 6; fn test() {5;} test(); variable;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 6),
+                JP(Addr::new(528)),
+                LDRegByte(Reg::new(0), 5),
+                LDRegByte(Reg::new(14), 3),
+                SubRegReg(Reg::new(13), Reg::new(14)),
+                LDFReg(Reg::new(13)),
+                LDRegI(Reg::new(13)),
+                RET,
+                LDFReg(Reg::new(13)),
+                LDIReg(Reg::new(13)),
+                LDRegByte(Reg::new(14), 3),
+                AddRegReg(Reg::new(13), Reg::new(14)),
+                CALL(Addr::new(516)),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_fn_nested_call_with_args() {
+        let mut l =
+            Lexer::new("var variable = 9; fn test(num) {var a = 5; num;} test(1); variable;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 9),
+                JP(Addr::new(530)),
+                LDRegByte(Reg::new(1), 5),
+                LDRegReg(Reg::new(2), Reg::new(0)),
+                LDRegByte(Reg::new(14), 3),
+                SubRegReg(Reg::new(13), Reg::new(14)),
+                LDFReg(Reg::new(13)),
+                LDRegI(Reg::new(13)),
+                RET,
+                LDFReg(Reg::new(13)),
+                LDIReg(Reg::new(13)),
+                LDRegByte(Reg::new(14), 3),
+                AddRegReg(Reg::new(13), Reg::new(14)),
+                LDRegByte(Reg::new(1), 1),
+                LDRegReg(Reg::new(0), Reg::new(1)),
+                CALL(Addr::new(516)),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+            ]
+        ));
+    }
+
+    #[test]
+    pub fn test_fn_with_args() {
+        let mut l = Lexer::new(
+            "var glob1 = 7;
+            var glob2 = 3;
+            
+            fn doubleloop(num1, num2) {
+              var num2backup = num2;
+              while(num1 != 0) {
+                 while(num2 != 0) {
+                     num2 = num2 - 1;
+                 }
+               num2 = num2backup;
+               num1 = num1 - 1;
+              }
+            }
+            
+            var glob3 = 255;
+            
+            doubleloop(glob2, glob1);
+            
+            var glob4 = 128;
+            
+            glob3;",
+        );
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 7),
+                LDRegByte(Reg::new(1), 3),
+                JP(Addr::new(562)),
+                LDRegReg(Reg::new(2), Reg::new(1)),
+                SNERegByte(Reg::new(0), 0),
+                JP(Addr::new(552)),
+                SNERegByte(Reg::new(1), 0),
+                JP(Addr::new(538)),
+                LDRegReg(Reg::new(3), Reg::new(1)),
+                LDRegByte(Reg::new(4), 1),
+                SubRegReg(Reg::new(3), Reg::new(4)),
+                LDRegReg(Reg::new(1), Reg::new(3)),
+                JP(Addr::new(524)),
+                LDRegReg(Reg::new(3), Reg::new(2)),
+                LDRegReg(Reg::new(1), Reg::new(3)),
+                LDRegReg(Reg::new(3), Reg::new(0)),
+                LDRegByte(Reg::new(4), 1),
+                SubRegReg(Reg::new(3), Reg::new(4)),
+                LDRegReg(Reg::new(0), Reg::new(3)),
+                JP(Addr::new(520)),
+                LDRegByte(Reg::new(14), 3),
+                SubRegReg(Reg::new(13), Reg::new(14)),
+                LDFReg(Reg::new(13)),
+                LDRegI(Reg::new(13)),
+                RET,
+                LDRegByte(Reg::new(2), 255),
+                LDFReg(Reg::new(13)),
+                LDIReg(Reg::new(13)),
+                LDRegByte(Reg::new(14), 3),
+                AddRegReg(Reg::new(13), Reg::new(14)),
+                LDRegReg(Reg::new(3), Reg::new(1)),
+                LDRegReg(Reg::new(4), Reg::new(0)),
+                LDRegReg(Reg::new(0), Reg::new(3)),
+                LDRegReg(Reg::new(1), Reg::new(4)),
+                CALL(Addr::new(518)),
+                LDRegByte(Reg::new(3), 128),
+                LDRegReg(Reg::new(4), Reg::new(2)),
+            ]
+        ));
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::Compiler;
-    use super::*;
 
     #[test]
-    pub fn test_check() {
-        let mut l = Lexer::new("var test 123 55");
+    pub fn test_rand_literal_mask() {
+        let mut l = Lexer::new("var r = RAND(15);");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
-        assert!(c.check(Var));
+        c.compile();
+
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![RNDRegByte(Reg::new(0), 15)]
+        ));
     }
 
     #[test]
-    pub fn test_number() {
-        let mut l = Lexer::new("10; 5;");
+    pub fn test_rand_variable_mask() {
+        let mut l = Lexer::new("var m = 7; var r = RAND(m); var s = RAND(m + 8);");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
 
-        let mut l = Lexer::new("12 + 3 + 7 + 2;");
-        l.lex();
-        let mut c = Compiler::new_from_lexer(&l);
-        assert_eq!(c.reg_stack_top, 0);
-        c.compile();
         assert!(utils::vectors_equivalent(
             c.asm,
             vec![
-                LDRegByte(0, 12),
-                LDRegByte(1, 3),
-                AddRegReg(0, 1),
-                LDRegByte(1, 7),
-                AddRegReg(0, 1),
-                LDRegByte(1, 2),
-                AddRegReg(0, 1),
+                LDRegByte(Reg::new(0), 7),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                RNDRegByte(SCRATCH, 0xFF),
+                AndRegReg(Reg::new(1), SCRATCH),
+                LDRegReg(Reg::new(2), Reg::new(0)),
+                LDRegByte(Reg::new(3), 8),
+                AddRegReg(Reg::new(2), Reg::new(3)),
+                RNDRegByte(SCRATCH, 0xFF),
+                AndRegReg(Reg::new(2), SCRATCH),
             ]
         ));
-
-        assert_eq!(c.reg_stack_top, 0);
     }
 
     #[test]
-    pub fn test_sub() {
-        let mut l = Lexer::new("9 - 7;");
+    pub fn test_is_pressed() {
+        let mut l = Lexer::new("var down = is_pressed(5);");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
+
         assert!(utils::vectors_equivalent(
             c.asm,
-            vec![LDRegByte(0, 9), LDRegByte(1, 7), SubRegReg(0, 1)]
+            vec![
+                LDRegByte(Reg::new(0), 5),
+                LDRegByte(SCRATCH, 0),
+                SKNPReg(Reg::new(0)),
+                LDRegByte(SCRATCH, 1),
+                LDRegReg(Reg::new(0), SCRATCH),
+            ]
         ));
-        assert_eq!(c.reg_stack_top, 0);
     }
 
     #[test]
-    pub fn test_variable() {
-        let mut l = Lexer::new("var a = 3; a;");
+    pub fn test_data_declaration() {
+        let mut l = Lexer::new("data table = [1, 2, 3];\nI = table;");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
+
+        assert!(!c.had_error());
         assert!(utils::vectors_equivalent(
             c.asm,
-            vec![LDRegByte(0, 3), LDRegReg(1, 0)]
+            vec![
+                LDIAddr(Addr::new(0x204)),
+                JP(Addr::new(0x202)),
+                DW(0x0102),
+                DW(0x0300),
+            ]
         ));
-        assert_eq!(c.reg_stack_top, 1);
     }
 
     #[test]
-    pub fn test_variable_assignment() {
-        let mut l = Lexer::new("var a = 1; a + 4; var b = 2; var c = b + a; c = a;");
+    pub fn test_data_declaration_errors() {
+        for src in [
+            "data t = [];",
+            "data t = [1, 256];",
+            "data t = [x];",
+            "data = [1];",
+        ] {
+            let mut l = Lexer::new(src);
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.compile();
+            assert!(c.had_error(), "{}", src);
+        }
+    }
+
+    #[test]
+    pub fn test_switch() {
+        let mut l = Lexer::new(
+            "var x = 2; var r = 0;\n\
+            switch (x) { case 1: r = 10; case 2: r = 20; case 3: r = 30; }",
+        );
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
+
         assert!(utils::vectors_equivalent(
             c.asm,
-            //vec![LDRegByte(0, 3), LDRegByte(1, 10), LDRegReg(0, 1)]
             vec![
-                LDRegByte(0, 1),
-                LDRegReg(1, 0),
-                LDRegByte(2, 4),
-                AddRegReg(1, 2),
-                LDRegByte(1, 2),
-                LDRegReg(2, 1),
-                LDRegReg(3, 0),
-                AddRegReg(2, 3),
-                LDRegReg(3, 0),
-                LDRegReg(2, 3)
+                LDRegByte(Reg::new(0), 2),
+                LDRegByte(Reg::new(1), 0),
+                LDRegReg(Reg::new(2), Reg::new(0)),
+                SERegByte(Reg::new(2), 1),
+                JP(Addr::new(0x210)),
+                LDRegByte(Reg::new(3), 10),
+                LDRegReg(Reg::new(1), Reg::new(3)),
+                JP(Addr::new(0x224)),
+                SERegByte(Reg::new(2), 2),
+                JP(Addr::new(0x21A)),
+                LDRegByte(Reg::new(3), 20),
+                LDRegReg(Reg::new(1), Reg::new(3)),
+                JP(Addr::new(0x224)),
+                SERegByte(Reg::new(2), 3),
+                JP(Addr::new(0x224)),
+                LDRegByte(Reg::new(3), 30),
+                LDRegReg(Reg::new(1), Reg::new(3)),
+                JP(Addr::new(0x224)),
             ]
         ));
-        assert_eq!(c.reg_stack_top, 3);
     }
 
     #[test]
-    pub fn test_lexical_scope() {
-        let mut l = Lexer::new("var a = 1; { var b = 4; } var c = 7;");
+    pub fn test_switch_default_must_be_last() {
+        let mut l = Lexer::new("var x = 2; switch (x) { default: x = 0; case 1: x = 1; }");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
-        assert!(utils::vectors_equivalent(
-            c.asm,
-            //vec![LDRegByte(0, 3), LDRegByte(1, 10), LDRegReg(0, 1)]
-            vec![LDRegByte(0, 1), LDRegByte(1, 4), LDRegByte(1, 7),]
-        ));
-        assert_eq!(c.reg_stack_top, 2);
+
+        assert_eq!(c.errors().len(), 1);
+        assert_eq!(
+            c.errors()[0].message,
+            "`default` must be the last case of a switch"
+        );
     }
 
     #[test]
-    pub fn test_if() {
-        let mut l = Lexer::new("if (1+3 == 4) { 10; } 5;");
+    pub fn test_call_too_few_arguments() {
+        let mut l = Lexer::new("fn add(a, b) { a = a + b; }\nadd(1);");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
-        assert!(utils::vectors_equivalent(
-            c.asm,
-            //vec![LDRegByte(0, 3), LDRegByte(1, 10), LDRegReg(0, 1)]
-            vec![
-                LDRegByte(0, 1),
-                LDRegByte(1, 3),
-                AddRegReg(0, 1),
-                LDRegByte(1, 4),
-                SERegReg(0, 1),
-                JP(0x20E),
-                LDRegByte(0, 10),
-                LDRegByte(0, 5)
-            ]
-        ));
+
+        assert_eq!(
+            c.errors(),
+            vec![CompileError::new(
+                1,
+                0,
+                String::from("`add` expects 2 arguments, found 1")
+            )]
+        );
     }
 
     #[test]
-    pub fn test_if_else() {
-        let mut l = Lexer::new("var a = 0; if (1 == 2) a = 5; else a = 9;");
+    pub fn test_call_too_many_arguments() {
+        let mut l = Lexer::new("fn inc(a) { a = a + 1; }\ninc(1, 2, 3);\ninc();");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
-        assert!(utils::vectors_equivalent(
-            c.asm,
+
+        assert_eq!(
+            c.errors(),
             vec![
-                LDRegByte(0, 0),
-                LDRegByte(1, 1),
-                LDRegByte(2, 2),
-                SERegReg(1, 2),
-                JP(0x210),
-                LDRegByte(1, 5),
-                LDRegReg(0, 1),
-                JP(0x214),
-                LDRegByte(1, 9),
-                LDRegReg(0, 1)
+                CompileError::new(1, 0, String::from("`inc` expects 1 argument, found 3")),
+                CompileError::new(2, 0, String::from("`inc` expects 1 argument, found 0")),
             ]
-        ));
+        );
     }
 
     #[test]
-    pub fn test_and() {
-        let mut l = Lexer::new("if (2 == 2 and 4 == 4) 5; else 9;");
+    pub fn test_register_exhaustion() {
+        let mut src = String::new();
+        for i in 0..7 {
+            src.push_str(&format!("{{ var a{} = {}; var b{} = {};\n", i, i, i, i));
+        }
+        src.push_str(&"}".repeat(7));
+        let mut l = Lexer::new(&src);
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
-        assert!(utils::vectors_equivalent(
-            c.asm,
-            vec![
-                LDRegByte(0, 2),
-                LDRegByte(1, 2),
-                SERegReg(0, 1),
-                JP(0x20E),
-                LDRegByte(0, 4),
-                LDRegByte(1, 4),
-                SERegReg(0, 1),
-                JP(0x214),
-                LDRegByte(0, 5),
-                JP(0x216),
-                LDRegByte(0, 9)
-            ]
-        ));
+
+        assert_eq!(
+            c.errors(),
+            vec![CompileError::new(
+                6,
+                18,
+                String::from(
+                    "register exhaustion at line 7: too many simultaneous locals (max 13)"
+                )
+            )]
+        );
     }
 
     #[test]
-    pub fn test_not_equal() {
-        let mut l = Lexer::new("if (1 != 5) 3;");
+    pub fn test_register_exhaustion_in_expression() {
+        let src = format!(
+            "var a = 1;\nvar b = {}a{};",
+            "1 + (".repeat(13),
+            ")".repeat(13)
+        );
+        let mut l = Lexer::new(&src);
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert_eq!(c.errors().len(), 1);
+        assert_eq!(
+            c.errors()[0].message,
+            "register exhaustion at line 2: expression needs more than 13 registers"
+        );
+    }
+
+    #[test]
+    pub fn test_max_locals() {
+        let src = "var a = 1; { var b = 2; } var c = 3; var d = 4;";
+        let mut l = Lexer::new(src);
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_max_locals(2);
+        c.compile();
+
+        assert_eq!(c.errors().len(), 1);
+        assert_eq!(
+            c.errors()[0].message,
+            "register exhaustion at line 1: too many simultaneous locals (max 2)"
+        );
+    }
+
+    #[test]
+    pub fn test_wait() {
+        let mut l = Lexer::new("wait(30); var frames = 2; wait(frames);");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
+
         assert!(utils::vectors_equivalent(
             c.asm,
             vec![
-                LDRegByte(0, 1),
-                LDRegByte(1, 5),
-                SNERegReg(0, 1),
-                JP(0x20A),
-                LDRegByte(0, 3),
+                LDRegByte(Reg::new(0), 30),
+                LDDTReg(Reg::new(0)),
+                LDRegDT(SCRATCH),
+                SERegByte(SCRATCH, 0),
+                JP(Addr::new(0x204)),
+                LDRegByte(Reg::new(0), 2),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDDTReg(Reg::new(1)),
+                LDRegDT(SCRATCH),
+                SERegByte(SCRATCH, 0),
+                JP(Addr::new(0x210)),
             ]
         ));
     }
 
     #[test]
-    pub fn test_or() {
-        let mut l = Lexer::new("if (1 != 1 or 3 == 3) 8; else 5;");
+    pub fn test_boolean_value() {
+        let mut l = Lexer::new("var f = (3 == 3);");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
@@ -1031,25 +4520,20 @@ mod tests {
         assert!(utils::vectors_equivalent(
             c.asm,
             vec![
-                LDRegByte(0, 1),
-                LDRegByte(1, 1),
-                SNERegReg(0, 1),
-                JP(0x20A),
-                JP(0x212),
-                LDRegByte(0, 3),
-                LDRegByte(1, 3),
-                SERegReg(0, 1),
-                JP(0x216),
-                LDRegByte(0, 8),
-                JP(0x218),
-                LDRegByte(0, 5),
+                LDRegByte(Reg::new(0), 3),
+                LDRegByte(Reg::new(1), 3),
+                SERegReg(Reg::new(0), Reg::new(1)),
+                JP(Addr::new(0x20C)),
+                LDRegByte(Reg::new(0), 1),
+                JP(Addr::new(0x20E)),
+                LDRegByte(Reg::new(0), 0),
             ]
         ));
     }
 
     #[test]
-    pub fn test_while() {
-        let mut l = Lexer::new("var a = 255; while (a != 0) { a = a - 1; }");
+    pub fn test_slt() {
+        let mut l = Lexer::new("var a = 200; if (slt(a, 10)) a = 0;");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
@@ -1057,86 +4541,130 @@ mod tests {
         assert!(utils::vectors_equivalent(
             c.asm,
             vec![
-                LDRegByte(0, 255),
-                LDRegReg(1, 0),
-                LDRegByte(2, 0),
-                SNERegReg(1, 2),
-                JP(0x214),
-                LDRegReg(1, 0),
-                LDRegByte(2, 1),
-                SubRegReg(1, 2),
-                LDRegReg(0, 1),
-                JP(0x202),
+                LDRegByte(Reg::new(0), 200),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDRegByte(Reg::new(2), 10),
+                LDRegByte(SCRATCH, 0x80),
+                XorRegReg(Reg::new(1), SCRATCH),
+                XorRegReg(Reg::new(2), SCRATCH),
+                LDRegReg(SCRATCH, Reg::new(1)),
+                SubRegReg(SCRATCH, Reg::new(2)),
+                SERegByte(FLAG, 0),
+                JP(Addr::new(0x218)),
+                LDRegByte(Reg::new(1), 0),
+                LDRegReg(Reg::new(0), Reg::new(1)),
             ]
         ));
     }
 
     #[test]
-    pub fn test_fn_without_args() {
-        let mut l = Lexer::new("var variable = 6; fn test() {5;} test(); variable;");
+    pub fn test_slt_reserved() {
+        let mut l = Lexer::new("var slt = 1;");
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
+        assert!(c.had_error());
+    }
 
-        assert!(utils::vectors_equivalent(
-            c.asm,
+    #[test]
+    pub fn test_error_recovery() {
+        let src = "var a = ;\nvar b = 2;\nDRAW(b, 3;\nvar c = 4;";
+        let mut l = Lexer::new(src);
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert_eq!(
+            c.errors(),
             vec![
-                LDRegByte(0, 6),
-                JP(528),
-                LDRegByte(0, 5),
-                LDRegByte(14, 3),
-                SubRegReg(13, 14),
-                LDFReg(13),
-                LDRegI(13),
-                RET,
-                LDFReg(13),
-                LDIReg(13),
-                LDRegByte(14, 3),
-                AddRegReg(13, 14),
-                CALL(516),
-                LDRegReg(1, 0),
+                CompileError::new(
+                    0,
+                    8,
+                    String::from("expected an expression, found Semicolon")
+                ),
+                CompileError::new(2, 9, String::from("expected Comma, found Semicolon")),
             ]
-        ));
+        );
+        //the statements after each error still compile
+        assert!(c.asm.contains(&LDRegByte(Reg::new(1), 2)));
+        assert!(c.asm.contains(&LDRegByte(Reg::new(2), 4)));
     }
 
     #[test]
-    pub fn test_fn_nested_call_with_args() {
-        let mut l =
-            Lexer::new("var variable = 9; fn test(num) {var a = 5; num;} test(1); variable;");
+    pub fn test_error_recovery_never_panics() {
+        let sources = [
+            "var = 3; var x = 1;",
+            "var a 5;",
+            "fn (a) { } var z = 1;",
+            "fn f(a { a = 1; }",
+            "if (1 == ) { var q = 2; }",
+            "while (1 != 0 { }",
+            "}",
+            "var a = 1; { a = a + ; } a = 2;",
+            "var a = 1 + + 2; var b = a;",
+            "DRAW(1, 2;",
+            "var a = 1; a = a a;",
+            //truncated source ends on EndOfFile where a token is still expected
+            "DRAW(1, 2",
+            "var a[4]; a[",
+            "hline(0, 0,",
+            "if (1 == 1",
+            "var a = 1; while (a",
+            "fn f(",
+            "fn f(a,",
+            "fn main {}",
+            "f();",
+            "var r = rand(300);",
+            "var a = 1; var b = not (a == 3);",
+            "var r = RAND",
+            "var k = KEY",
+            "ST",
+            "I =",
+        ];
+        for src in sources.iter() {
+            let mut l = Lexer::new(src);
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.compile();
+            assert!(c.had_error(), "{}", src);
+        }
+    }
+
+    #[test]
+    pub fn test_block_scopes_reuse_registers() {
+        let mut l = Lexer::new(
+            "var g = 1; { var a = 2; } { var b = 3; var c = 4; } { var d = 5; } var h = 6;",
+        );
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
 
         assert!(utils::vectors_equivalent(
-            c.asm,
+            c.asm.clone(),
             vec![
-                LDRegByte(0, 9),
-                JP(530),
-                LDRegByte(1, 5),
-                LDRegReg(2, 0),
-                LDRegByte(14, 3),
-                SubRegReg(13, 14),
-                LDFReg(13),
-                LDRegI(13),
-                RET,
-                LDFReg(13),
-                LDIReg(13),
-                LDRegByte(14, 3),
-                AddRegReg(13, 14),
-                LDRegByte(1, 1),
-                LDRegReg(0, 1),
-                CALL(516),
-                LDRegReg(1, 0),
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 2),
+                LDRegByte(Reg::new(1), 3),
+                LDRegByte(Reg::new(2), 4),
+                LDRegByte(Reg::new(1), 5),
+                LDRegByte(Reg::new(1), 6),
             ]
         ));
+        assert_eq!(c.max_register_pressure(), 3);
     }
 
     #[test]
-    pub fn test_fn_with_args() {
+    pub fn test_max_register_pressure() {
+        let mut l = Lexer::new("var a = 1; var b = 2;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert_eq!(c.max_register_pressure(), 2);
+
+        //same program as test_fn_with_args: V0-V4 are all live at once, e.g. glob1-glob4 and the
+        //copy of glob3 at the end, or num1, num2, num2backup, num2 - 1's lhs and the literal 1
         let mut l = Lexer::new(
-            "var glob1 = 7;
-            var glob2 = 3;
-            
+            "var glob1 = 7; var glob2 = 3;
             fn doubleloop(num1, num2) {
               var num2backup = num2;
               while(num1 != 0) {
@@ -1147,65 +4675,254 @@ mod tests {
                num1 = num1 - 1;
               }
             }
-            
             var glob3 = 255;
-            
             doubleloop(glob2, glob1);
-            
             var glob4 = 128;
-            
             glob3;",
         );
         l.lex();
         let mut c = Compiler::new_from_lexer(&l);
         c.compile();
+        assert_eq!(c.max_register_pressure(), 5);
+    }
+
+    #[test]
+    pub fn test_fn_scope() {
+        let mut l = Lexer::new(
+            "var g = 1;\n{ var local = 2;\nfn f(a) { a = local; g; } }\nfn outer(b) { fn inner() { b; g = 3; } }",
+        );
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        let outside = |name: &str| {
+            format!(
+                "`{}` is declared outside this function, pass it as an argument instead",
+                name
+            )
+        };
+        assert_eq!(
+            c.errors(),
+            vec![
+                CompileError::new(2, 14, outside("local")),
+                CompileError::new(3, 27, outside("b")),
+                CompileError::new(
+                    3,
+                    30,
+                    String::from("`g` is a top-level variable, functions can only read it")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_fn_args_dont_leak() {
+        let mut l = Lexer::new("fn f(a) { a; } a;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert_eq!(
+            c.errors(),
+            vec![CompileError::new(
+                0,
+                15,
+                String::from("`a` is not declared")
+            )]
+        );
+        assert_eq!(c.lookup_variable_register(String::from("a")), None);
+    }
+
+    #[test]
+    pub fn test_leaf_functions() {
+        let mut l = Lexer::new("var g = 1; fn inc(n) { n = n + 1; } inc(g);");
+        l.lex();
 
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
         assert!(utils::vectors_equivalent(
             c.asm,
             vec![
-                LDRegByte(0, 7),
-                LDRegByte(1, 3),
-                JP(570),
-                LDRegReg(2, 1),
-                LDRegReg(3, 0),
-                LDRegByte(4, 0),
-                SNERegReg(3, 4),
-                JP(560),
-                LDRegReg(3, 1),
-                LDRegByte(4, 0),
-                SNERegReg(3, 4),
-                JP(546),
-                LDRegReg(3, 1),
-                LDRegByte(4, 1),
-                SubRegReg(3, 4),
-                LDRegReg(1, 3),
-                JP(528),
-                LDRegReg(3, 2),
-                LDRegReg(1, 3),
-                LDRegReg(3, 0),
-                LDRegByte(4, 1),
-                SubRegReg(3, 4),
-                LDRegReg(0, 3),
-                JP(520),
-                LDRegByte(14, 3),
-                SubRegReg(13, 14),
-                LDFReg(13),
-                LDRegI(13),
+                LDRegByte(Reg::new(0), 1),
+                JP(Addr::new(0x216)),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDRegByte(Reg::new(2), 1),
+                AddRegReg(Reg::new(1), Reg::new(2)),
+                LDRegReg(Reg::new(0), Reg::new(1)),
+                LDRegByte(SCRATCH, 3),
+                SubRegReg(FRAME_POINTER, SCRATCH),
+                LDFReg(FRAME_POINTER),
+                LDRegI(FRAME_POINTER),
+                RET,
+                LDFReg(FRAME_POINTER),
+                LDIReg(FRAME_POINTER),
+                LDRegByte(SCRATCH, 3),
+                AddRegReg(FRAME_POINTER, SCRATCH),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDRegReg(Reg::new(0), Reg::new(1)),
+                CALL(Addr::new(0x204)),
+            ]
+        ));
+
+        //inc needs 3 registers, so it runs in VA-VC and the call needs no frame
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_leaf_functions(true);
+        c.compile();
+        assert!(utils::vectors_equivalent(
+            c.asm.clone(),
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                JP(Addr::new(0x20E)),
+                LDRegReg(Reg::new(0xB), Reg::new(0xA)),
+                LDRegByte(Reg::new(0xC), 1),
+                AddRegReg(Reg::new(0xB), Reg::new(0xC)),
+                LDRegReg(Reg::new(0xA), Reg::new(0xB)),
                 RET,
-                LDRegByte(2, 255),
-                LDFReg(13),
-                LDIReg(13),
-                LDRegByte(14, 3),
-                AddRegReg(13, 14),
-                LDRegReg(3, 1),
-                LDRegReg(4, 0),
-                LDRegReg(0, 3),
-                LDRegReg(1, 4),
-                CALL(518),
-                LDRegByte(3, 128),
-                LDRegReg(4, 2),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDRegReg(Reg::new(0xA), Reg::new(1)),
+                CALL(Addr::new(0x204)),
+            ]
+        ));
+        assert_eq!(c.max_register_pressure(), 3);
+        assert!(c.errors().is_empty());
+        assert!(c.warnings().is_empty());
+    }
+
+    #[test]
+    pub fn test_leaf_functions_not_applied() {
+        //calls another function
+        let mut l = Lexer::new("fn a() { 1; } fn b() { a(); } b();");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_leaf_functions(true);
+        c.compile();
+        assert!(c.functions["a"].leaf_base.is_some());
+        assert!(c.functions["b"].leaf_base.is_none());
+
+        //too many live registers at the call site: framed call, restored by the caller
+        let mut l = Lexer::new(
+            "var a = 1; var b = 2; var c = 3; var d = 4; var e = 5; var f = 6; var g = 7; \
+            var h = 8; var i = 9; var j = 10; var k = 11; fn add(x, y) { x = x + y; } add(a, b);",
+        );
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_leaf_functions(true);
+        c.compile();
+        assert_eq!(c.functions["add"].leaf_base, Some(PROGRAM_REGS - 4));
+        let asm = c.asm();
+        assert_eq!(asm[asm.len() - 5], CALL(c.functions["add"].start_addr));
+        assert_eq!(asm[asm.len() - 1], LDRegI(FRAME_POINTER));
+    }
+
+    #[test]
+    pub fn test_chained_assignment() {
+        let mut l = Lexer::new("var a = 1; var b = 2; a = b = 5; var c = 7;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert!(!c.had_error());
+        assert!(utils::vectors_equivalent(
+            c.asm,
+            vec![
+                LDRegByte(Reg::new(0), 1),
+                LDRegByte(Reg::new(1), 2),
+                LDRegByte(Reg::new(2), 5),
+                LDRegReg(Reg::new(1), Reg::new(2)),
+                LDRegReg(Reg::new(0), Reg::new(2)),
+                LDRegByte(Reg::new(2), 7),
             ]
         ));
+        assert_eq!(c.reg_stack_top, 3);
+    }
+
+    #[test]
+    pub fn test_invalid_assignment_target() {
+        let mut l = Lexer::new("var a = 1; var b = 2; a + b = 5; b = a;");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        assert_eq!(
+            c.errors(),
+            vec![CompileError::new(
+                0,
+                28,
+                String::from("invalid assignment target")
+            )]
+        );
+        assert_eq!(c.reg_stack_top, 2);
+    }
+
+    #[test]
+    pub fn test_main_entry() {
+        let src = "fn helper(a) { a; } fn main() { var x = 3; helper(x); }";
+        let mut l = Lexer::new(src);
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.set_main_entry(true);
+        c.compile();
+
+        assert!(!c.had_error());
+        assert_eq!(c.entry_point(), 0x200);
+        assert_eq!(c.asm()[0], CALL(c.functions["main"].start_addr));
+        assert_eq!(c.asm()[1], JP(Addr::new(0x202)));
+
+        //without the option the program starts after the function bodies as before
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert!(!matches!(c.asm()[0], CALL(_)));
+    }
+
+    #[test]
+    pub fn test_main_entry_errors() {
+        let errors = |src: &str| {
+            let mut l = Lexer::new(src);
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.set_main_entry(true);
+            c.compile();
+            c.errors()
+                .iter()
+                .map(|e| (e.line, e.column, e.message.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            errors("fn main() {}\nvar a = 1;"),
+            vec![(
+                1,
+                0,
+                String::from(
+                    "only definitions are allowed outside functions when `main` is the entry point"
+                )
+            )]
+        );
+        assert_eq!(
+            errors("fn main(a) { a; }"),
+            vec![(0, 9, String::from("`main` can't take arguments"))]
+        );
+        //no main, no convention
+        assert!(errors("var a = 1; fn helper() {}").is_empty());
+    }
+
+    #[test]
+    pub fn test_entry_point() {
+        let mut l = Lexer::new("fn one() { 1; } fn two(a) { a; } var b = 2; two(b);");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+
+        //one: JP over, LD V0, 1 and a 5 opcode frame pop. two: JP over, LD V1, V0 and the pop
+        assert_eq!(c.entry_point(), 0x200 + 2 * 14);
+        assert_eq!(c.asm()[13], RET);
+        assert_eq!(c.asm()[14], LDRegByte(Reg::new(0), 2));
+
+        let mut l = Lexer::new("var b = 2; fn one() { 1; }");
+        l.lex();
+        let mut c = Compiler::new_from_lexer(&l);
+        c.compile();
+        assert_eq!(c.entry_point(), 0x200);
     }
 
     #[test]
@@ -1239,51 +4956,108 @@ mod tests {
         assert!(utils::vectors_equivalent(
             c.asm,
             vec![
-                LDRegByte(0, 10),
-                JP(568),
-                LDIAddr(20),
-                LDRegReg(2, 0),
-                LDRegByte(3, 0),
-                SNERegReg(2, 3),
-                JP(558),
-                LDRegReg(2, 0),
-                LDRegByte(3, 1),
-                SubRegReg(2, 3),
-                LDRegReg(0, 2),
-                LDRegKey(2),
-                LDRegReg(2, 1),
-                LDDTReg(2),
-                LDRegDT(2),
-                LDRegByte(3, 0),
-                SNERegReg(2, 3),
-                JP(550),
-                JP(540),
-                RNDRegByte(2, 255),
-                RNDRegByte(3, 255),
-                DRWRegRegNibble(2, 3, 5),
-                JP(518),
-                LDRegByte(14, 3),
-                SubRegReg(13, 14),
-                LDFReg(13),
-                LDRegI(13),
+                LDRegByte(Reg::new(0), 10),
+                JP(Addr::new(564)),
+                LDIAddr(Addr::new(20)),
+                SNERegByte(Reg::new(0), 0),
+                JP(Addr::new(554)),
+                LDRegReg(Reg::new(2), Reg::new(0)),
+                LDRegByte(Reg::new(3), 1),
+                SubRegReg(Reg::new(2), Reg::new(3)),
+                LDRegReg(Reg::new(0), Reg::new(2)),
+                LDRegKey(Reg::new(2)),
+                LDRegReg(Reg::new(2), Reg::new(1)),
+                LDDTReg(Reg::new(2)),
+                LDRegDT(Reg::new(2)),
+                LDRegByte(Reg::new(3), 0),
+                SNERegReg(Reg::new(2), Reg::new(3)),
+                JP(Addr::new(546)),
+                JP(Addr::new(536)),
+                RNDRegByte(Reg::new(2), 255),
+                RNDRegByte(Reg::new(3), 255),
+                DRWRegRegNibble(Reg::new(2), Reg::new(3), Nibble::new(5)),
+                JP(Addr::new(518)),
+                LDRegByte(Reg::new(14), 3),
+                SubRegReg(Reg::new(13), Reg::new(14)),
+                LDFReg(Reg::new(13)),
+                LDRegI(Reg::new(13)),
                 RET,
-                LDFReg(13),
-                LDIReg(13),
-                LDRegByte(14, 3),
-                AddRegReg(13, 14),
-                LDRegReg(1, 0),
-                LDRegByte(2, 50),
-                LDRegReg(0, 1),
-                LDRegReg(1, 2),
-                CALL(516),
-                LDRegByte(1, 1),
-                LDRegByte(2, 1),
-                SERegReg(1, 2),
-                JP(598),
-                LDRegByte(1, 7),
-                //JP(588),
-                JP(586),
+                LDFReg(Reg::new(13)),
+                LDIReg(Reg::new(13)),
+                LDRegByte(Reg::new(14), 3),
+                AddRegReg(Reg::new(13), Reg::new(14)),
+                LDRegReg(Reg::new(1), Reg::new(0)),
+                LDRegByte(Reg::new(2), 50),
+                LDRegReg(Reg::new(0), Reg::new(1)),
+                LDRegReg(Reg::new(1), Reg::new(2)),
+                CALL(Addr::new(516)),
+                LDRegByte(Reg::new(1), 1),
+                LDRegByte(Reg::new(2), 1),
+                SERegReg(Reg::new(1), Reg::new(2)),
+                JP(Addr::new(594)),
+                LDRegByte(Reg::new(1), 7),
+                JP(Addr::new(582)),
             ]
         ));
     }
+
+    #[test]
+    pub fn test_ram_line_map_with_comments() {
+        let plain = [
+            "var a = 3;",
+            "var b = a + 1;",
+            "while (a != 0) {",
+            "    a = a - 1;",
+            "}",
+            "b = b + a;",
+        ];
+        //(line of plain it goes before, comment)
+        let comments = [
+            (0, "// header"),
+            (2, "// loop"),
+            (2, "//"),
+            (3, "   // body"),
+            (6, "// tail"),
+        ];
+
+        let mut commented = Vec::new();
+        let mut shifted = Vec::new();
+        for (line, text) in plain.iter().enumerate() {
+            for (_, comment) in comments.iter().filter(|(before, _)| *before == line) {
+                commented.push(comment.to_string());
+            }
+            shifted.push(commented.len() as u32);
+            commented.push(format!("{} // trailing", text));
+        }
+        commented.extend(
+            comments
+                .iter()
+                .filter(|(before, _)| *before == plain.len())
+                .map(|(_, c)| c.to_string()),
+        );
+
+        let compile = |src: &str| {
+            let mut l = Lexer::new(src);
+            l.lex();
+            let mut c = Compiler::new_from_lexer(&l);
+            c.compile();
+            assert!(!c.had_error());
+            c
+        };
+        let without = compile(&plain.join("\n"));
+        let with = compile(&commented.join("\n"));
+
+        assert!(utils::vectors_equivalent(
+            with.asm.clone(),
+            without.asm.clone()
+        ));
+        assert_eq!(with.ram_line_map.len(), without.ram_line_map.len());
+        for (addr, line) in without.ram_line_map.iter() {
+            assert_eq!(
+                with.ram_line_map[addr], shifted[*line as usize],
+                "opcode at {:X}",
+                addr
+            );
+        }
+    }
 }
@@ -13,6 +13,8 @@ pub enum TokenType {
     //literals:
     Identifier(String),
     Number(u16),
+    //only used to name an included source
+    Str(String),
 
     //keywords:
     True,
@@ -23,8 +25,13 @@ pub enum TokenType {
     Or,
     Var,
     While,
+    Switch,
+    Case,
+    Default,
     Not,
     Fn,
+    Include,
+    Data,
 
     //in-built global CHIP-8 variables
     DT,
@@ -35,6 +42,12 @@ pub enum TokenType {
     Rand,
     Draw,
     Key,
+    Nop,
+    HLine,
+    VLine,
+    IsPressed,
+    Slt,
+    Wait,
 
     //single-char tokens:
     LeftParen,
@@ -45,26 +58,57 @@ pub enum TokenType {
     Minus,
     ForwardSlash,
     Semicolon,
+    Colon,
     Equals,
     Comma,
+    Less,
+    Greater,
+    LeftBracket,
+    RightBracket,
 
     //two-char tokens:
     EqualsEquals,
     NotEquals,
+    LessEquals,
+    GreaterEquals,
+
+    //trivia, only emitted by lex_with_trivia():
+    Whitespace(String),
+    Newline,
+    //`//` to the end of the line, the newline itself stays a separate token
+    Comment(String),
 
     EndOfFile,
-    ErrorToken,
+    //a character that starts no token, or '"' for an unterminated string
+    ErrorToken(char),
+    //the digits of a number literal too big for 16 bits
+    NumberOutOfRange(String),
+}
+
+impl TokenType {
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Whitespace(_) | Newline | Comment(_))
+    }
 }
 
 #[derive(Clone)]
 pub struct Token {
     pub token_type: TokenType,
     pub line: u32,
+    //0-based character offset of the token's first character within its line
+    pub column: u32,
+    //name of the included source the token came from, None for the main source
+    pub file: Option<String>,
 }
 
 impl Token {
-    pub fn new(token_type: TokenType, line: u32) -> Token {
-        Token { token_type, line }
+    pub fn new(token_type: TokenType, line: u32, column: u32) -> Token {
+        Token {
+            token_type,
+            line,
+            column,
+            file: None,
+        }
     }
 
     pub fn token_type(&self) -> TokenType {
@@ -74,6 +118,10 @@ impl Token {
     pub fn line(&self) -> u32 {
         self.line
     }
+
+    pub fn column(&self) -> u32 {
+        self.column
+    }
 }
 
 impl fmt::Display for TokenType {
@@ -88,8 +136,14 @@ pub struct Lexer {
     start: usize,
     current: usize,
     line: u32,
+    //index into src of the first character of the current line
+    line_start: usize,
     tokens: Vec<Token>,
     keywords: HashMap<String, TokenType>,
+    //emit Whitespace, Newline and Comment tokens so formatters can rebuild the layout
+    keep_trivia: bool,
+    //name -> source available to `include "name";`
+    sources: HashMap<String, String>,
 }
 
 #[wasm_bindgen]
@@ -100,6 +154,7 @@ impl Lexer {
             start: 0,
             current: 0,
             line: 0,
+            line_start: 0,
             tokens: Vec::new(),
             keywords: HashMap::<_, _>::from_iter(IntoIter::new([
                 (String::from("true"), True),
@@ -110,14 +165,27 @@ impl Lexer {
                 (String::from("or"), Or),
                 (String::from("var"), Var),
                 (String::from("while"), While),
+                (String::from("switch"), Switch),
+                (String::from("case"), Case),
+                (String::from("default"), Default),
                 (String::from("fn"), Fn),
+                (String::from("include"), Include),
+                (String::from("data"), Data),
                 (String::from("DT"), DT),
                 (String::from("ST"), ST),
                 (String::from("I"), I),
                 (String::from("RAND"), Rand),
                 (String::from("DRAW"), Draw),
                 (String::from("KEY"), Key),
+                (String::from("nop"), Nop),
+                (String::from("hline"), HLine),
+                (String::from("vline"), VLine),
+                (String::from("is_pressed"), IsPressed),
+                (String::from("slt"), Slt),
+                (String::from("wait"), Wait),
             ])),
+            keep_trivia: false,
+            sources: HashMap::new(),
         }
     }
 
@@ -145,51 +213,153 @@ impl Lexer {
         ret
     }
 
+    fn column(&self) -> u32 {
+        (self.start - self.line_start) as u32
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.src.len()
     }
 
+    //makes src available to `include "name";`. There is no filesystem under wasm, so the host
+    //supplies every source up front.
+    pub fn add_source(&mut self, name: &str, src: &str) {
+        self.sources.insert(String::from(name), String::from(src));
+    }
+
     pub fn lex(&mut self) {
+        self.scan_tokens();
+        self.splice_includes(&[]);
+    }
+
+    fn scan_tokens(&mut self) {
+        //typical source averages well over two characters per token, so this avoids regrowing
+        self.tokens.reserve(self.src.len() / 2 + 1);
         while !self.is_at_end() {
             self.start = self.current;
 
             let character = self.advance();
             match character {
-                '+' => self.tokens.push(Token::new(Plus, self.line)),
-                '-' => self.tokens.push(Token::new(Minus, self.line)),
-                '/' => self.tokens.push(Token::new(ForwardSlash, self.line)),
-                '{' => self.tokens.push(Token::new(LeftBrace, self.line)),
-                '}' => self.tokens.push(Token::new(RightBrace, self.line)),
-                '(' => self.tokens.push(Token::new(LeftParen, self.line)),
-                ')' => self.tokens.push(Token::new(RightParen, self.line)),
-                ';' => self.tokens.push(Token::new(Semicolon, self.line)),
-                ',' => self.tokens.push(Token::new(Comma, self.line)),
+                '+' => self.tokens.push(Token::new(Plus, self.line, self.column())),
+                '-' => self
+                    .tokens
+                    .push(Token::new(Minus, self.line, self.column())),
+                '/' => match self.match_char('/') {
+                    //the '\n' is left for the main loop so line counting stays in one place
+                    true => {
+                        while self.peek() != '\n' && !self.is_at_end() {
+                            self.advance();
+                        }
+                        if self.keep_trivia {
+                            self.tokens.push(Token::new(
+                                Comment(self.src[self.start..self.current].iter().collect()),
+                                self.line,
+                                self.column(),
+                            ));
+                        }
+                    }
+                    false => self
+                        .tokens
+                        .push(Token::new(ForwardSlash, self.line, self.column())),
+                },
+                '{' => self
+                    .tokens
+                    .push(Token::new(LeftBrace, self.line, self.column())),
+                '}' => self
+                    .tokens
+                    .push(Token::new(RightBrace, self.line, self.column())),
+                '(' => self
+                    .tokens
+                    .push(Token::new(LeftParen, self.line, self.column())),
+                ')' => self
+                    .tokens
+                    .push(Token::new(RightParen, self.line, self.column())),
+                '[' => self
+                    .tokens
+                    .push(Token::new(LeftBracket, self.line, self.column())),
+                ']' => self
+                    .tokens
+                    .push(Token::new(RightBracket, self.line, self.column())),
+                ';' => self
+                    .tokens
+                    .push(Token::new(Semicolon, self.line, self.column())),
+                ',' => self
+                    .tokens
+                    .push(Token::new(Comma, self.line, self.column())),
+                ':' => self
+                    .tokens
+                    .push(Token::new(Colon, self.line, self.column())),
                 '=' => match self.match_char('=') {
-                    true => self.tokens.push(Token::new(EqualsEquals, self.line)),
-                    false => self.tokens.push(Token::new(Equals, self.line)),
+                    true => self
+                        .tokens
+                        .push(Token::new(EqualsEquals, self.line, self.column())),
+                    false => self
+                        .tokens
+                        .push(Token::new(Equals, self.line, self.column())),
                 },
                 '!' => match self.match_char('=') {
-                    true => self.tokens.push(Token::new(NotEquals, self.line)),
-                    false => self.tokens.push(Token::new(Not, self.line)),
+                    true => self
+                        .tokens
+                        .push(Token::new(NotEquals, self.line, self.column())),
+                    false => self.tokens.push(Token::new(Not, self.line, self.column())),
+                },
+                '<' => match self.match_char('=') {
+                    true => self
+                        .tokens
+                        .push(Token::new(LessEquals, self.line, self.column())),
+                    false => self.tokens.push(Token::new(Less, self.line, self.column())),
                 },
-                '\n' => self.line += 1,
+                '>' => match self.match_char('=') {
+                    true => self
+                        .tokens
+                        .push(Token::new(GreaterEquals, self.line, self.column())),
+                    false => self
+                        .tokens
+                        .push(Token::new(Greater, self.line, self.column())),
+                },
+                '"' => {
+                    while self.peek() != '"' && self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
+                    }
+                    let token_type = match self.match_char('"') {
+                        true => Str(self.src[self.start + 1..self.current - 1].iter().collect()),
+                        false => ErrorToken('"'),
+                    };
+                    self.tokens
+                        .push(Token::new(token_type, self.line, self.column()));
+                }
+                '\n' => {
+                    if self.keep_trivia {
+                        self.tokens
+                            .push(Token::new(Newline, self.line, self.column()));
+                    }
+                    self.line += 1;
+                    self.line_start = self.current;
+                }
                 _ => {
                     if character.is_digit(10) {
                         while self.peek().is_digit(10) {
                             self.advance();
                         }
-                        self.tokens.push(Token::new(
-                            Number(
-                                self.src[self.start..self.current]
-                                    .iter()
-                                    .collect::<String>()
-                                    .parse()
-                                    .unwrap(),
+                        //digits straight from the source, without building a String to parse
+                        let value = self.src[self.start..self.current].iter().try_fold(
+                            0u16,
+                            |value, digit| {
+                                value
+                                    .checked_mul(10)?
+                                    .checked_add(digit.to_digit(10)? as u16)
+                            },
+                        );
+                        let token_type = match value {
+                            Some(value) => Number(value),
+                            None => NumberOutOfRange(
+                                self.src[self.start..self.current].iter().collect(),
                             ),
-                            self.line,
-                        ));
-                    } else if character.is_alphabetic() {
-                        while self.peek().is_alphanumeric() {
+                        };
+                        self.tokens
+                            .push(Token::new(token_type, self.line, self.column()));
+                    } else if character.is_alphabetic() || character == '_' {
+                        while self.peek().is_alphanumeric() || self.peek() == '_' {
                             self.advance();
                         }
 
@@ -198,18 +368,97 @@ impl Lexer {
                             .collect::<String>();
 
                         match self.keywords.get(&ident) {
-                            None => self.tokens.push(Token::new(Identifier(ident), self.line)),
-                            Some(x) => self.tokens.push(Token::new(x.clone(), self.line)),
+                            None => self.tokens.push(Token::new(
+                                Identifier(ident),
+                                self.line,
+                                self.column(),
+                            )),
+                            Some(x) => {
+                                self.tokens
+                                    .push(Token::new(x.clone(), self.line, self.column()))
+                            }
                         }
                     } else if character.is_whitespace() {
-                        ()
+                        if self.keep_trivia {
+                            while self.peek() != '\n' && self.peek().is_whitespace() {
+                                self.advance();
+                            }
+                            self.tokens.push(Token::new(
+                                Whitespace(self.src[self.start..self.current].iter().collect()),
+                                self.line,
+                                self.column(),
+                            ));
+                        }
                     } else {
-                        self.tokens.push(Token::new(ErrorToken, self.line));
+                        self.tokens.push(Token::new(
+                            ErrorToken(character),
+                            self.line,
+                            self.column(),
+                        ));
+                    }
+                }
+            }
+        }
+        self.start = self.current;
+        self.tokens
+            .push(Token::new(EndOfFile, self.line, self.column()));
+    }
+
+    //lex() that also keeps spacing and line breaks as trivia tokens
+    pub fn lex_with_trivia(&mut self) {
+        self.keep_trivia = true;
+        self.lex();
+    }
+
+    //replaces each `include "name";` with the tokens of that source. Includes missing their `;`,
+    //naming an unknown source or one already being included are left for the compiler to report.
+    fn splice_includes(&mut self, including: &[String]) {
+        if !self.tokens.iter().any(|token| token.token_type == Include) {
+            return;
+        }
+        let tokens = std::mem::take(&mut self.tokens);
+        self.tokens.reserve(tokens.len());
+        let mut index = 0;
+        while index < tokens.len() {
+            let name = match (tokens.get(index + 1), tokens.get(index + 2)) {
+                (Some(name), Some(semicolon))
+                    if tokens[index].token_type == Include && semicolon.token_type == Semicolon =>
+                {
+                    match &name.token_type {
+                        Str(name) => Some(name.clone()),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            let src = name
+                .as_ref()
+                .filter(|name| !including.contains(name))
+                .and_then(|name| self.sources.get(name));
+
+            match (name.clone(), src) {
+                (Some(name), Some(src)) => {
+                    let mut included = Lexer::new(src);
+                    included.sources = self.sources.clone();
+                    included.keep_trivia = self.keep_trivia;
+                    included.scan_tokens();
+                    let mut stack = including.to_vec();
+                    stack.push(name.clone());
+                    included.splice_includes(&stack);
+
+                    included.tokens.pop();
+                    for mut token in included.tokens {
+                        token.file.get_or_insert_with(|| name.clone());
+                        self.tokens.push(token);
                     }
+                    index += 3;
+                }
+                _ => {
+                    self.tokens.push(tokens[index].clone());
+                    index += 1;
                 }
             }
         }
-        self.tokens.push(Token::new(EndOfFile, self.line));
     }
 
     pub fn stringify_tokens(&self) -> String {
@@ -221,10 +470,21 @@ impl Lexer {
     }
 }
 
+#[wasm_bindgen]
+pub fn lex_source(src: &str) -> String {
+    let mut lexer = Lexer::new(src);
+    lexer.lex();
+    lexer.stringify_tokens()
+}
+
 impl Lexer {
     pub fn tokens(&self) -> &Vec<Token> {
         &self.tokens
     }
+
+    pub fn sources(&self) -> &HashMap<String, String> {
+        &self.sources
+    }
 }
 
 #[cfg(test)]
@@ -314,6 +574,14 @@ mod tests {
         );
     }
 
+    #[test]
+    pub fn test_lex_source() {
+        assert_eq!(
+            lex_source("var a = 5;"),
+            String::from("Var Identifier(\"a\") Equals Number(5) Semicolon EndOfFile")
+        );
+    }
+
     #[test]
     pub fn test_globals() {
         let mut l = Lexer::new("ST test test DT 123 I 55 RAND");
@@ -326,6 +594,229 @@ mod tests {
         );
     }
 
+    #[test]
+    pub fn test_comparison_operators() {
+        let mut l = Lexer::new("< <= > >= == !=");
+        l.lex();
+        assert_eq!(
+            l.stringify_tokens(),
+            String::from("Less LessEquals Greater GreaterEquals EqualsEquals NotEquals EndOfFile")
+        );
+    }
+
+    #[test]
+    pub fn test_columns() {
+        let mut l = Lexer::new("var a = 5;\n  a == 10;");
+        l.lex();
+        let positions: Vec<(u32, u32)> =
+            l.tokens().iter().map(|t| (t.line(), t.column())).collect();
+        assert_eq!(
+            positions,
+            vec![
+                (0, 0),
+                (0, 4),
+                (0, 6),
+                (0, 8),
+                (0, 9),
+                (1, 2),
+                (1, 4),
+                (1, 7),
+                (1, 9),
+                (1, 10)
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_comments() {
+        let mut l = Lexer::new("a / b; // half\n// whole line\nc;//");
+        l.lex();
+        let types: Vec<TokenType> = l.tokens().iter().map(|t| t.token_type.clone()).collect();
+        assert_eq!(
+            types,
+            vec![
+                Identifier(String::from("a")),
+                ForwardSlash,
+                Identifier(String::from("b")),
+                Semicolon,
+                Identifier(String::from("c")),
+                Semicolon,
+                EndOfFile,
+            ]
+        );
+        assert_eq!(l.tokens()[4].line, 2);
+
+        let mut trivia = Lexer::new("a; // half\n");
+        trivia.lex_with_trivia();
+        assert!(trivia
+            .tokens()
+            .iter()
+            .any(|t| t.token_type == Comment(String::from("// half"))));
+    }
+
+    #[test]
+    pub fn test_trivia_round_trip() {
+        let src = "var a = 5;\n\nwhile (a != 0) {\n    a = a - 1;\n\t}\n";
+        let mut l = Lexer::new(src);
+        l.lex_with_trivia();
+
+        let text = |token_type: &TokenType| match token_type {
+            Identifier(name) => name.clone(),
+            Number(n) => n.to_string(),
+            Whitespace(spacing) => spacing.clone(),
+            Newline => String::from("\n"),
+            Var => String::from("var"),
+            While => String::from("while"),
+            LeftParen => String::from("("),
+            RightParen => String::from(")"),
+            LeftBrace => String::from("{"),
+            RightBrace => String::from("}"),
+            Equals => String::from("="),
+            NotEquals => String::from("!="),
+            Minus => String::from("-"),
+            Semicolon => String::from(";"),
+            EndOfFile => String::new(),
+            other => panic!("unexpected token {}", other),
+        };
+        let rebuilt: String = l.tokens().iter().map(|t| text(&t.token_type)).collect();
+        assert_eq!(rebuilt, src);
+
+        let mut plain = Lexer::new(src);
+        plain.lex();
+        assert!(plain.tokens().iter().all(|t| !t.token_type.is_trivia()));
+        let significant: Vec<TokenType> = l
+            .tokens()
+            .iter()
+            .map(|t| t.token_type())
+            .filter(|t| !t.is_trivia())
+            .collect();
+        assert!(utils::vectors_equivalent(
+            significant,
+            plain.tokens().iter().map(|t| t.token_type()).collect()
+        ));
+    }
+
+    #[test]
+    pub fn test_include() {
+        let mut l = Lexer::new("include \"lib\";\nf(1);");
+        l.add_source("lib", "fn f(a) {\n  a = a + 1;\n}");
+        l.lex();
+        assert_eq!(
+            l.stringify_tokens(),
+            String::from(
+                "Fn Identifier(\"f\") LeftParen Identifier(\"a\") RightParen LeftBrace \
+                Identifier(\"a\") Equals Identifier(\"a\") Plus Number(1) Semicolon RightBrace \
+                Identifier(\"f\") LeftParen Number(1) RightParen Semicolon EndOfFile"
+            )
+        );
+
+        let located: Vec<(Option<&str>, u32, u32)> = l
+            .tokens()
+            .iter()
+            .map(|t| (t.file.as_deref(), t.line(), t.column()))
+            .collect();
+        assert_eq!(located[6], (Some("lib"), 1, 2));
+        assert_eq!(located[13], (None, 1, 0));
+    }
+
+    #[test]
+    pub fn test_include_unresolved() {
+        let mut l = Lexer::new("include \"missing\"; include \"self\";");
+        l.add_source("self", "include \"self\";");
+        l.lex();
+        assert_eq!(
+            l.stringify_tokens(),
+            String::from(
+                "Include Str(\"missing\") Semicolon Include Str(\"self\") Semicolon EndOfFile"
+            )
+        );
+    }
+
+    #[test]
+    pub fn test_error_token() {
+        let mut l = Lexer::new("var a = 1;\n  a = @;\n\"open");
+        l.lex();
+        let errors: Vec<(TokenType, u32, u32)> = l
+            .tokens()
+            .iter()
+            .filter(|t| matches!(t.token_type, ErrorToken(_)))
+            .map(|t| (t.token_type(), t.line(), t.column()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![(ErrorToken('@'), 1, 6), (ErrorToken('"'), 2, 0)]
+        );
+        assert_eq!(
+            lex_source("a @ b"),
+            String::from("Identifier(\"a\") ErrorToken('@') Identifier(\"b\") EndOfFile")
+        );
+    }
+
+    #[test]
+    pub fn test_underscore_identifiers() {
+        assert_eq!(
+            lex_source("var _tmp = is_pressed(key_1);"),
+            String::from(
+                "Var Identifier(\"_tmp\") Equals IsPressed LeftParen Identifier(\"key_1\") \
+                RightParen Semicolon EndOfFile"
+            )
+        );
+    }
+
+    #[test]
+    pub fn test_lex_reserves_tokens() {
+        let src = "var a = 65535; a = a - 1;\n".repeat(20);
+        let mut l = Lexer::new(&src);
+        l.lex();
+        //at least the src.len() / 2 + 1 tokens scan_tokens() reserves
+        assert!(l.tokens.capacity() > src.len() / 2);
+        assert_eq!(l.tokens.len(), 20 * 11 + 1);
+        assert_eq!(l.tokens[3].token_type, Number(65535));
+    }
+
+    //cargo test --release bench_lex -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    pub fn bench_lex() {
+        let src = "var a = 0; while (a != 200) { a = a + 1; DRAW(a, 2, 5); }\n".repeat(5000);
+
+        let runs = 20;
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            let mut l = Lexer::new(&src);
+            l.lex();
+            assert_eq!(l.tokens.last().unwrap().token_type, EndOfFile);
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "5000 lines lexed {} times in {:?}, {:.2} ms/run",
+            runs,
+            elapsed,
+            elapsed.as_secs_f64() * 1000.0 / runs as f64
+        );
+    }
+
+    #[test]
+    pub fn test_number_out_of_range() {
+        assert_eq!(
+            lex_source("65536 007"),
+            String::from("NumberOutOfRange(\"65536\") Number(7) EndOfFile")
+        );
+    }
+
+    #[test]
+    pub fn test_switch_tokens() {
+        let mut l = Lexer::new("switch (x) { case 1: default: }");
+        l.lex();
+        assert_eq!(
+            l.stringify_tokens(),
+            String::from(
+                "Switch LeftParen Identifier(\"x\") RightParen LeftBrace Case Number(1) Colon \
+                Default Colon RightBrace EndOfFile"
+            )
+        );
+    }
+
     #[test]
     pub fn test_keywords() {
         let mut l = Lexer::new("ST test test DT var while 55 RAND");
@@ -2,6 +2,7 @@ mod assembler;
 mod chip8;
 mod compiler;
 mod lexer;
+mod pipeline;
 mod utils;
 
 extern crate web_sys;
@@ -0,0 +1,259 @@
+use crate::assembler::*;
+use crate::chip8::Chip8;
+use crate::compiler::*;
+use crate::lexer::TokenType::*;
+use crate::lexer::*;
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+//an error from any stage of compile_source/assemble_source, with typed fields for the editor
+//instead of a message to pick apart. kind is "lex", "compile" or "assemble"; lines and columns
+//are 0-based like the lexer, col is 0 for assembler errors which only know their line.
+#[wasm_bindgen]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct PipelineError {
+    line: u32,
+    col: u32,
+    kind: String,
+    message: String,
+}
+
+#[wasm_bindgen]
+impl PipelineError {
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn col(&self) -> u32 {
+        self.col
+    }
+
+    pub fn kind(&self) -> String {
+        self.kind.clone()
+    }
+
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+}
+
+impl PipelineError {
+    pub fn new(kind: &str, line: u32, col: u32, message: String) -> PipelineError {
+        PipelineError {
+            line,
+            col,
+            kind: String::from(kind),
+            message,
+        }
+    }
+}
+
+impl From<&CompileError> for PipelineError {
+    fn from(error: &CompileError) -> PipelineError {
+        PipelineError::new("compile", error.line, error.column, error.message.clone())
+    }
+}
+
+impl From<&AsmError> for PipelineError {
+    fn from(error: &AsmError) -> PipelineError {
+        PipelineError::new("assemble", error.line, 0, error.message.clone())
+    }
+}
+
+//characters the lexer couldn't turn into a token. The compiler would only see them as a confusing
+//parse error further on, so they're reported and compilation stops here.
+pub fn lex_errors(lexer: &Lexer) -> Vec<PipelineError> {
+    lexer
+        .tokens()
+        .iter()
+        .filter_map(|token| {
+            let message = match token.token_type {
+                ErrorToken('"') => String::from("unterminated string"),
+                ErrorToken(character) => format!("unexpected character '{}'", character),
+                NumberOutOfRange(ref digits) => {
+                    format!("number {} is out of range (0 to 65535)", digits)
+                }
+                _ => return None,
+            };
+            Some(PipelineError::new("lex", token.line, token.column, message))
+        })
+        .collect()
+}
+
+//lexes, compiles and assembles src into a ROM image, or every error from the first stage to fail
+pub fn build_rom(src: &str) -> Result<Vec<u8>, Vec<PipelineError>> {
+    let mut lexer = Lexer::new(src);
+    lexer.lex();
+    let errors = lex_errors(&lexer);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut compiler = Compiler::new_from_lexer(&lexer);
+    compiler.compile();
+    if compiler.had_error() {
+        return Err(compiler.errors().iter().map(PipelineError::from).collect());
+    }
+
+    Ok(Assembler::assemble_compiler(&compiler).binary().clone())
+}
+
+//Assembler::from_source followed by assemble(), with the errors in the same shape as build_rom
+pub fn build_rom_from_asm(src: &str) -> Result<Vec<u8>, Vec<PipelineError>> {
+    match Assembler::from_source(src) {
+        Ok(mut assembler) => {
+            assembler.assemble();
+            Ok(assembler.binary().clone())
+        }
+        Err(errors) => Err(errors.iter().map(PipelineError::from).collect()),
+    }
+}
+
+//throws a JS array of PipelineError
+#[wasm_bindgen]
+pub fn compile_source(src: &str) -> Result<Vec<u8>, JsValue> {
+    build_rom(src).map_err(JsValue::from)
+}
+
+//throws a JS array of PipelineError
+#[wasm_bindgen]
+pub fn assemble_source(src: &str) -> Result<Vec<u8>, JsValue> {
+    build_rom_from_asm(src).map_err(JsValue::from)
+}
+
+//one instruction a source line assembled to, for the editor's tooltip
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct LineOpcode {
+    pub addr: u16,
+    pub word: u16,
+    pub text: String,
+}
+
+//joins the compiler's ram_line_map with the assembled words and the emulator's disassembly of
+//them, in address order. Empty for lines that emitted no code.
+pub fn opcodes_for_line(compiler: &Compiler, line: u32) -> Vec<LineOpcode> {
+    let mut addrs: Vec<u16> = compiler
+        .ram_line_map()
+        .iter()
+        .filter(|(_, l)| **l == line)
+        .map(|(addr, _)| *addr)
+        .collect();
+    if addrs.is_empty() {
+        return Vec::new();
+    }
+    addrs.sort_unstable();
+
+    let assembler = Assembler::assemble_compiler(compiler);
+    let binary = assembler.binary();
+    let mut c8 = Chip8::new();
+    c8.load_rom_from_bytes(binary);
+    c8.disassemble();
+    let disasm = c8.disasm_lines();
+
+    addrs
+        .into_iter()
+        .map(|addr| {
+            let offset = (addr - 0x200) as usize;
+            LineOpcode {
+                addr,
+                word: u16::from_be_bytes([binary[offset], binary[offset + 1]]),
+                text: disasm[offset / 2].text.clone(),
+            }
+        })
+        .collect()
+}
+
+//JSON serialised LineOpcodes
+#[wasm_bindgen]
+pub fn line_to_opcodes(compiler: &Compiler, line: u32) -> JsValue {
+    JsValue::from_serde(&opcodes_for_line(compiler, line)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_lex_error() {
+        let errors = build_rom("var a = 1;\nvar b = a $ 2;").err().unwrap();
+        assert_eq!(
+            errors,
+            vec![PipelineError::new(
+                "lex",
+                1,
+                10,
+                String::from("unexpected character '$'")
+            )]
+        );
+        assert_eq!(errors[0].kind(), "lex");
+        assert_eq!(errors[0].col(), 10);
+
+        let errors = build_rom("var a = 65536;").err().unwrap();
+        assert_eq!(
+            errors[0].message(),
+            "number 65536 is out of range (0 to 65535)"
+        );
+    }
+
+    #[test]
+    pub fn test_compile_error() {
+        let errors = build_rom("var a = 1;\n\nb = a;").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind(), "compile");
+        assert_eq!(errors[0].line(), 2);
+        assert_eq!(errors[0].col(), 0);
+        assert!(errors[0].message().contains('b'), "{}", errors[0].message());
+    }
+
+    #[test]
+    pub fn test_assemble_error() {
+        let errors = build_rom_from_asm("LD V0, 5\nMOV V0, V1").err().unwrap();
+        assert_eq!(
+            errors,
+            vec![PipelineError::new(
+                "assemble",
+                1,
+                0,
+                String::from("unknown mnemonic MOV")
+            )]
+        );
+    }
+
+    #[test]
+    pub fn test_opcodes_for_line() {
+        let mut lexer = Lexer::new("var a = 5;\nvar b = a + 1;\n\nb = 7;");
+        lexer.lex();
+        let mut compiler = Compiler::new_from_lexer(&lexer);
+        compiler.compile();
+
+        assert_eq!(
+            opcodes_for_line(&compiler, 0),
+            vec![LineOpcode {
+                addr: 0x200,
+                word: 0x6005,
+                text: String::from("LD V0, 5")
+            }]
+        );
+        let line = opcodes_for_line(&compiler, 1);
+        let words: Vec<(u16, u16, &str)> = line
+            .iter()
+            .map(|op| (op.addr, op.word, op.text.as_str()))
+            .collect();
+        assert_eq!(
+            words,
+            vec![
+                (0x202, 0x8100, "LD V1, V0"),
+                (0x204, 0x6201, "LD V2, 1"),
+                (0x206, 0x8124, "ADD V1, V2"),
+            ]
+        );
+        assert!(opcodes_for_line(&compiler, 2).is_empty());
+    }
+
+    #[test]
+    pub fn test_build_rom() {
+        assert_eq!(build_rom("var a = 5;"), Ok(vec![0x60, 0x05]));
+        assert_eq!(build_rom_from_asm("LD V0, 5"), Ok(vec![0x60, 0x05]));
+    }
+}
@@ -2,10 +2,21 @@ import { memory } from "../pkg/c8_web_toolchain_bg.wasm";
 import { Lexer, Compiler, Assembler, echo_string } from "../pkg/c8_web_toolchain";
 
 var num_disasm_rows = 21;
+var max_cycles_per_frame = 10000;
+//instructions a program compiled in the playground gets before it's paused as probably stuck.
+//ROMs such as games never halt, so they run without a budget.
+var playground_cycle_budget = 100000;
+//V0-VC, VD-VF are reserved by the compiler
+var max_program_registers = 13;
+//registers that should stay free for the temporaries of deeper expressions and calls, the
+//pressure warning fires once fewer than this are left
+var register_pressure_headroom = 2;
 
 export class emulator_view {
     constructor(chip8, scale, editor, framebuffer_parent, register_parent, disasm_parent) {
         this.chip8 = chip8;
+        //so the disassembly can mark instructions the program has overwritten
+        this.chip8.set_track_writes(true);
         this.editor = editor;
         this.scale = scale;
         this.ram_line_map = new Object();
@@ -15,6 +26,12 @@ export class emulator_view {
         this.ctx = this.canvas.getContext('2d');
         this.set_scale(scale);
         this.paused = false;
+        this.blocked_on_key = false;
+        this.profile = "";
+        this.rom_filename = null;
+        this.entry_point = 0x200;
+        this.cycle_budget = undefined;
+        this.timed_out = false;
 
         this.key_mappings = new Map([
             [0x1, "Digit1"], [0x2, "Digit2"], [0x3, "Digit3"], [0xC, "Digit4"],
@@ -29,6 +46,9 @@ export class emulator_view {
         let step_button = document.createElement("button"); step_button.innerHTML = "Step Forward";
         step_button.onclick = this.onClickStepButton.bind(this); step_button.setAttribute("class", "btn btn-secondary")
         disasm_parent.appendChild(step_button);
+        let frame_button = document.createElement("button"); frame_button.innerHTML = "Next Frame";
+        frame_button.onclick = this.onClickFrameButton.bind(this); frame_button.setAttribute("class", "btn btn-secondary")
+        disasm_parent.appendChild(frame_button);
 
         this.register_list = register_parent.appendChild(document.createElement("ul"));
         this.register_list.setAttribute("class", "list-group list-group-mine");
@@ -44,10 +64,13 @@ export class emulator_view {
 
         this.disasm_list = disasm_parent.appendChild(document.createElement("ul"));
         this.disasm_list.setAttribute("class", "list-group list-group-mine");
+        //clicking a row toggles a breakpoint on its address
+        this.breakpoints = new Set();
         for (let i = 0; i < num_disasm_rows; i++) {
             let li = this.disasm_list.appendChild(document.createElement("li"));
             li.setAttribute("class", "list-group-item list-group-item-secondary py-0");
             li.setAttribute("id", `${i}`);
+            li.onclick = () => this.toggle_breakpoint(this.chip8.pc() + i * 2);
         }
 
         document.addEventListener("keydown", this.onKeyDown.bind(this));
@@ -65,6 +88,14 @@ export class emulator_view {
     onClickPauseButton() {
         this.paused = !this.paused;
         console.log(this.paused);
+        if (!this.paused) this.rearm_cycle_budget();
+    }
+
+    //a program paused by a timeout gets a fresh budget when the user carries on with it
+    rearm_cycle_budget() {
+        if (!this.timed_out) return;
+        this.timed_out = false;
+        this.chip8.set_cycle_budget(this.cycle_budget);
     }
 
     onClickStepButton() {
@@ -72,11 +103,27 @@ export class emulator_view {
         this.step();
     }
 
+    onClickFrameButton() {
+        if (!this.paused) this.paused = true;
+        this.rearm_cycle_budget();
+        switch (this.chip8.run_until_draw(max_cycles_per_frame).stop_reason) {
+            case "key_wait":
+                this.blocked_on_key = true;
+                break;
+            case "timeout":
+                this.timed_out = true;
+                console.warn("program may be stuck: it ran out of cycles without halting");
+                break;
+        }
+        this.redraw();
+    }
+
 
     onKeyDown(e) {
         for (const [key, value] of this.key_mappings.entries()) {
             if (e.code == value) {
                 this.chip8.set_key(key, 1);
+                this.blocked_on_key = false;
             }
         }
     }
@@ -95,12 +142,39 @@ export class emulator_view {
         lexer.lex();
 
         let compiler = Compiler.new_from_lexer(lexer);
+        compiler.set_coalesce_jumps(true);
+        compiler.set_unroll_loops(true);
+        compiler.set_leaf_functions(true);
+        compiler.set_fold_constant_conditions(true);
         compiler.compile();
         this.ram_line_map = compiler.ram_line_map_serialised();
+        this.entry_point = compiler.entry_point();
+        let warnings = compiler.warnings_serialised();
+        //register pressure isn't tied to a line, so it's shown at the top of the program
+        if (compiler.max_register_pressure() > max_program_registers - register_pressure_headroom) {
+            warnings.push({
+                line: 0,
+                message: `program uses ${compiler.max_register_pressure()} of ${max_program_registers} registers`
+            });
+        }
+        editor.session.setAnnotations(warnings.map(warning => ({
+            row: warning.line, column: 0, text: warning.message, type: "warning"
+        })).concat(compiler.errors_serialised().map(error => ({
+            row: error.line, column: error.column, text: error.message, type: "error"
+        }))));
+
+        if (compiler.had_error()) {
+            document.getElementById("compilerOutputTextarea").value = compiler.render_errors(editor.getValue());
+            return;
+        }
 
         let assembler = Assembler.new_from_compiler(compiler);
         assembler.assemble();
+        this.cycle_budget = playground_cycle_budget;
+        this.timed_out = false;
+        this.chip8.set_cycle_budget(this.cycle_budget);
         this.chip8.load_rom_from_assembler(assembler);
+        this.blocked_on_key = false;
 
         this.chip8.disassemble();
         this.disasm_map = this.chip8.disasm_map_serialised();
@@ -114,11 +188,29 @@ export class emulator_view {
         document.getElementById("assemblerOutputTextarea").value = assembler.stringify_binary();
     }
 
+    set_profile(profile) {
+        this.profile = profile;
+        if (profile) this.chip8.set_profile(profile);
+        if (this.rom_filename) this.load_rom_from_file(this.rom_filename);
+    }
+
     async load_rom_from_file(filename) {
         const response = await fetch(`roms/${filename}`);
         const buffer = await response.arrayBuffer();
         const array = new Uint8Array(buffer);
-        this.chip8.load_rom_from_bytes(array);
+        this.rom_filename = filename;
+        this.entry_point = 0x200;
+        this.cycle_budget = undefined;
+        this.timed_out = false;
+        this.chip8.set_cycle_budget(this.cycle_budget);
+        //an explicit profile wins over detection
+        if (this.profile) {
+            this.chip8.load_rom_from_bytes(array);
+        } else {
+            this.chip8.load_rom_auto(array);
+        }
+        this.blocked_on_key = false;
+        this.set_scale(this.scale);
 
         this.chip8.disassemble();
         this.disasm_map = this.chip8.disasm_map_serialised();
@@ -169,6 +261,9 @@ export class emulator_view {
             list[i].style.backgroundColor = "#272822"
             let pc = this.chip8.pc() + i * 2;
             list[i].innerHTML = "0x" + pc.toString(16).toUpperCase() + ": " + this.disasm_map[pc];
+            if (pc == this.entry_point) list[i].innerHTML += " (entry)";
+            if (this.chip8.self_modified(pc)) list[i].innerHTML += " &#9888; self-modified";
+            if (this.breakpoints.has(pc)) list[i].innerHTML = "&#9679; " + list[i].innerHTML;
             if (pc.toString() in this.ram_line_map) {
                 let end = this.editor.getSelectionRange().end.row, start = this.editor.getSelectionRange().start.row;
                 for (var line = start; line <= end; line++) {
@@ -181,21 +276,57 @@ export class emulator_view {
 
     }
 
+    toggle_breakpoint(addr) {
+        if (this.breakpoints.has(addr)) {
+            this.breakpoints.delete(addr);
+            this.chip8.remove_breakpoint(addr);
+        } else {
+            this.breakpoints.add(addr);
+            this.chip8.add_breakpoint(addr);
+        }
+        this.draw_disasm();
+    }
+
     step() {
-        this.chip8.clock();
+        this.chip8.step();
+        this.redraw();
+    }
 
+    redraw() {
         this.draw_framebuffer();
         this.draw_registers();
         this.draw_disasm();
     }
 
     emulation_loop() {
-        if (!this.paused) {
-            this.step();
+        if (!this.paused && !this.blocked_on_key) {
+            switch (this.chip8.run(1).stop_reason) {
+                case "key_wait":
+                    this.blocked_on_key = true;
+                    break;
+                case "breakpoint":
+                case "illegal":
+                    this.paused = true;
+                    break;
+                case "timeout":
+                    this.paused = true;
+                    this.timed_out = true;
+                    console.warn("program may be stuck: it ran out of cycles without halting");
+                    break;
+            }
+            this.redraw();
         }
         setTimeout(this.emulation_loop.bind(this), (1 / 240) * 1000);
     }
 
+    timer_loop() {
+        //timers keep running while Fx0A waits for a key
+        if (!this.paused) {
+            this.chip8.tick_timers();
+        }
+        window.requestAnimationFrame(this.timer_loop.bind(this));
+    }
+
     set_scale(scale) {
         //this.scale = scale;
         this.canvas.width = this.chip8.video_width() * scale;
@@ -47,6 +47,15 @@ let view = new emulator_view(c8, 11, editor,
     document.getElementById("editor-container"),
 );
 
+var profile_dropdown = document.getElementById("select-profile");
+var profile_select = profile_dropdown.getElementsByTagName("select")[0];
+for (const name of Chip8.profile_names()) {
+    profile_select.add(new Option(name, name));
+}
+profile_dropdown.addEventListener("change", function(e) {
+    view.set_profile(e.target.value);
+});
+
 editor.addEventListener("changeSelection", function() {
     view.draw_disasm();
 });
@@ -65,4 +74,5 @@ function onDragHorizontal() {
 (async () => {
     await view.load_rom_from_file('pong.rom');
     view.emulation_loop();
+    view.timer_loop();
 })();
\ No newline at end of file